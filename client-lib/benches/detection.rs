@@ -0,0 +1,254 @@
+//! Benchmarks of the TLSH detection hot path
+//!
+//! The database is built from pseudo-random data, so no signature file is needed. The scanned
+//! input never matches, every comparison runs like for a clean file.
+//!
+//! The `check_file` group compares hashing files through a memory mapping with buffered reads.
+//! It uses synthetic files, or every regular file directly in `SIMBIOTA_BENCH_DIR` if set,
+//! e.g. a directory of large binaries.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use simbiota_clientlib::api::detector::Detector;
+use simbiota_clientlib::api::hash::{
+    AbstractHashBasedDetector, ComparableHash, CompareAgainstAllDetector, HashAlg,
+    HashBasedDetector, HashDatabase, DEFAULT_READ_BUFFER_SIZE,
+};
+use simbiota_clientlib::detector::tlsh_detector::{ComparableTLSHHash, TLSHHashAlg};
+use std::fs::File;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+const DATABASE_SIZES: [usize; 3] = [100, 1_000, 10_000];
+const INPUT_SIZES: [usize; 2] = [4 * 1024, 1024 * 1024];
+const READ_BUFFER_SIZES: [usize; 3] = [1024, 16 * 1024, DEFAULT_READ_BUFFER_SIZE];
+const FILE_SIZES: [usize; 2] = [16 * 1024 * 1024, 64 * 1024 * 1024];
+
+/// Deterministic pseudo-random bytes, so every run hashes the same inputs
+fn synthetic_data(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn hash(data: &[u8]) -> ComparableTLSHHash {
+    let mut alg = TLSHHashAlg::new();
+    alg.update(data);
+    alg.finalize();
+    alg.get_hash().expect("synthetic data can be hashed")
+}
+
+struct SyntheticDatabase {
+    hashes: Vec<ComparableTLSHHash>,
+}
+
+impl SyntheticDatabase {
+    fn new(entries: usize) -> Self {
+        Self {
+            hashes: (1..=entries as u64)
+                .map(|seed| hash(&synthetic_data(seed, 4096)))
+                .collect(),
+        }
+    }
+}
+
+impl HashDatabase<ComparableTLSHHash> for SyntheticDatabase {
+    fn get_hashes(&mut self) -> &[ComparableTLSHHash] {
+        &self.hashes
+    }
+}
+
+fn detector(entries: usize) -> CompareAgainstAllDetector<ComparableTLSHHash> {
+    CompareAgainstAllDetector::new(
+        Box::new(SyntheticDatabase::new(entries)),
+        Box::new(|hash: &ComparableTLSHHash, stored: &ComparableTLSHHash| stored.diff(hash) < 40),
+    )
+}
+
+fn bench_hashing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tlsh_hash");
+    for size in INPUT_SIZES {
+        let data = synthetic_data(u64::MAX, size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| hash(black_box(data)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_compare(c: &mut Criterion) {
+    let input = hash(&synthetic_data(u64::MAX, 4096));
+    let mut group = c.benchmark_group("compare_against_all");
+    for entries in DATABASE_SIZES {
+        let mut detector = detector(entries);
+        group.throughput(Throughput::Elements(entries as u64));
+        group.bench_function(BenchmarkId::from_parameter(entries), |b| {
+            b.iter(|| detector.do_detect(black_box(&input)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_header_filter(c: &mut Criterion) {
+    let entries = 10_000;
+    let input = hash(&synthetic_data(u64::MAX, 4096));
+    let mut group = c.benchmark_group("header_filter");
+    group.throughput(Throughput::Elements(entries as u64));
+    for filter in [false, true] {
+        let mut detector = CompareAgainstAllDetector::new(
+            Box::new(SyntheticDatabase::new(entries)),
+            Box::new(
+                move |hash: &ComparableTLSHHash, stored: &ComparableTLSHHash| {
+                    if filter && stored.header_diff(hash) >= 40 {
+                        return false;
+                    }
+                    stored.diff(hash) < 40
+                },
+            ),
+        );
+        group.bench_function(BenchmarkId::from_parameter(filter), |b| {
+            b.iter(|| detector.do_detect(black_box(&input)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_check_reader(c: &mut Criterion) {
+    let entries = 10_000;
+    let mut detector = AbstractHashBasedDetector::<TLSHHashAlg, ComparableTLSHHash>::new(Box::new(
+        detector(entries),
+    ));
+    let mut group = c.benchmark_group("check_reader");
+    for size in INPUT_SIZES {
+        let data = synthetic_data(u64::MAX, size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| {
+                detector
+                    .check_reader(&mut Cursor::new(black_box(data)))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_read_buffer_size(c: &mut Criterion) {
+    let size = 16 * 1024 * 1024;
+    let data = synthetic_data(u64::MAX, size);
+    let mut group = c.benchmark_group("read_buffer_size");
+    group.throughput(Throughput::Bytes(size as u64));
+    group.sample_size(20);
+    for buffer_size in READ_BUFFER_SIZES {
+        let mut detector = AbstractHashBasedDetector::<TLSHHashAlg, ComparableTLSHHash>::new(
+            Box::new(detector(1)),
+        )
+        .with_read_buffer_size(buffer_size);
+        group.bench_function(BenchmarkId::from_parameter(buffer_size), |b| {
+            b.iter(|| {
+                detector
+                    .check_reader(&mut Cursor::new(black_box(&data)))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Files scanned by the `check_file` group, the synthetic ones are removed on drop
+struct BenchFiles {
+    paths: Vec<PathBuf>,
+    synthetic: bool,
+}
+
+impl BenchFiles {
+    fn new() -> Self {
+        if let Some(dir) = std::env::var_os("SIMBIOTA_BENCH_DIR") {
+            let paths = std::fs::read_dir(dir)
+                .expect("SIMBIOTA_BENCH_DIR is not readable")
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().map_or(false, |t| t.is_file()))
+                .map(|entry| entry.path())
+                .collect();
+            return Self {
+                paths,
+                synthetic: false,
+            };
+        }
+        let paths = FILE_SIZES
+            .iter()
+            .map(|size| {
+                let path = std::env::temp_dir().join(format!(
+                    "simbiota-bench-{}-{}",
+                    std::process::id(),
+                    size
+                ));
+                std::fs::write(&path, synthetic_data(u64::MAX, *size)).unwrap();
+                path
+            })
+            .collect();
+        Self {
+            paths,
+            synthetic: true,
+        }
+    }
+
+    fn total_size(&self) -> u64 {
+        self.paths
+            .iter()
+            .map(|path| std::fs::metadata(path).map_or(0, |m| m.len()))
+            .sum()
+    }
+}
+
+impl Drop for BenchFiles {
+    fn drop(&mut self) {
+        if self.synthetic {
+            for path in &self.paths {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn bench_check_file(c: &mut Criterion) {
+    let files = BenchFiles::new();
+    let mut detector =
+        AbstractHashBasedDetector::<TLSHHashAlg, ComparableTLSHHash>::new(Box::new(detector(1)));
+    let mut group = c.benchmark_group("check_file");
+    group.throughput(Throughput::Bytes(files.total_size()));
+    group.sample_size(10);
+    group.bench_function("mmap", |b| {
+        b.iter(|| {
+            for path in &files.paths {
+                let mut file = File::open(path).unwrap();
+                let _ = detector.check_file(black_box(&mut file));
+            }
+        })
+    });
+    group.bench_function("read", |b| {
+        b.iter(|| {
+            for path in &files.paths {
+                let mut file = File::open(path).unwrap();
+                let _ = detector.check_reader(black_box(&mut file));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_hashing,
+    bench_compare,
+    bench_header_filter,
+    bench_check_reader,
+    bench_read_buffer_size,
+    bench_check_file
+);
+criterion_main!(benches);
@@ -5,10 +5,13 @@ use crate::api::detector::DetectionResult;
 /// It can be used for speeding up the blocking operation by
 /// only scanning files that were not scanned or were modified.
 ///
-/// The planned implementation uses `fanotify_event_metadata` for `T`.
+/// The planned implementation uses `fanotify_event_metadata` for `T`. Implementations derive
+/// the cache key from `data`, so a file is found regardless of the path it was opened with.
 pub trait DetectionCache<T> {
-    fn get_result_for(&self, key: &str, data: &T) -> Option<DetectionResult>;
-    fn set_result_for(&mut self, key: String, data: &T, result: DetectionResult);
+    fn get_result_for(&self, data: &T) -> Option<DetectionResult>;
+    fn set_result_for(&mut self, data: &T, result: DetectionResult);
+    /// Drop all cached results, e.g. because the detection database changed.
+    fn clear(&mut self);
 }
 
 /// Simple cache implementation that does nothing
@@ -16,11 +19,15 @@ pub trait DetectionCache<T> {
 /// Can be used to disable cacheing
 pub struct NoopCache;
 impl<T> DetectionCache<T> for NoopCache {
-    fn get_result_for(&self, _key: &str, _data: &T) -> Option<DetectionResult> {
+    fn get_result_for(&self, _data: &T) -> Option<DetectionResult> {
         None
     }
 
-    fn set_result_for(&mut self, _key: String, _data: &T, _result: DetectionResult) {
+    fn set_result_for(&mut self, _data: &T, _result: DetectionResult) {
+        // noop
+    }
+
+    fn clear(&mut self) {
         // noop
     }
 }
@@ -1,17 +1,30 @@
+use crate::api::mmap::MappedFile;
 use std::error::Error;
+use std::fs::File;
 use std::io::Read;
 
 /// The result of a detector check.
 ///
 /// `Match` means the `Detector`'s comparator function returned true
 /// for a comparison result. If no result was flagged by the comparator, `NoMatch` is returned.
-/// The `Match` varian contains the value of the comparison diff as `value`.
+/// The `Match` variant contains the identifier of the matched database entry as `signature`
+/// and the value of the comparison diff as `value` (for TLSH, the distance), if the detector
+/// knows them.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum DetectionResult {
-    Match,
+    Match {
+        signature: Option<usize>,
+        value: Option<i64>,
+    },
     NoMatch,
 }
 
+impl DetectionResult {
+    pub fn is_match(&self) -> bool {
+        matches!(self, DetectionResult::Match { .. })
+    }
+}
+
 /// Generic detector interface
 ///
 /// All SIMBIoTA code uses this when a detector is needed
@@ -23,6 +36,33 @@ pub trait Detector {
     /// Checks whether the provided reader's content results in a match using the
     /// detector.
     fn check_reader(&mut self, reader: &mut dyn Read) -> Result<DetectionResult, Box<dyn Error>>;
+
+    /// Checks whether the content of the file results in a match using the detector.
+    ///
+    /// Large regular files are memory-mapped and passed to [`Detector::check_bytes`] in one
+    /// piece, which saves the read syscall per buffer of [`Detector::check_reader`]. Other files,
+    /// or files that cannot be mapped, are passed to [`Detector::check_reader`].
+    ///
+    /// Truncating a mapped file during the check raises `SIGBUS` and kills the process. Only use
+    /// this for files other users cannot modify meanwhile, e.g. in a scanner run by the user,
+    /// and [`Detector::check_reader`] for files anyone may write to.
+    fn check_file(&mut self, file: &mut File) -> Result<DetectionResult, Box<dyn Error>> {
+        match MappedFile::map(file) {
+            Some(mapped) => self.check_bytes(mapped.as_slice()),
+            None => self.check_reader(file),
+        }
+    }
+
+    /// Number of hashes the detector compares against, if it is hash based.
+    fn hash_count(&mut self) -> Option<usize> {
+        None
+    }
+
+    /// Distance of the closest database entry from the last checked input, if the detector
+    /// records it. Useful for tuning detection thresholds.
+    fn closest_distance(&self) -> Option<i64> {
+        None
+    }
 }
 
 /// A [`Detector`] implementation that uses a list of other detectors and weights
@@ -56,39 +96,105 @@ impl<'a> Detector for WeightedDetector<'a> {
     fn check_bytes(&mut self, bytes: &[u8]) -> Result<DetectionResult, Box<dyn Error>> {
         let mut match_sum = 0;
         let mut nomatch_sum = 0;
+        // the details of the first match are reported
+        let mut first_match = None;
         for (detector, weight) in self.detectors.iter_mut() {
             let result = detector.check_bytes(bytes)?;
-            if matches!(result, DetectionResult::Match) {
+            if result.is_match() {
                 match_sum += *weight;
+                first_match = first_match.or(Some(result));
             } else {
                 nomatch_sum += *weight;
             }
         }
 
         Ok(if match_sum >= nomatch_sum {
-            DetectionResult::Match
+            first_match.unwrap_or(DetectionResult::Match {
+                signature: None,
+                value: None,
+            })
         } else {
             DetectionResult::NoMatch
         })
     }
 
+    /// The reader can only be consumed once, so the input is buffered and every detector
+    /// checks the buffered bytes.
     fn check_reader(&mut self, reader: &mut dyn Read) -> Result<DetectionResult, Box<dyn Error>> {
-        let mut match_sum = 0;
-        let mut nomatch_sum = 0;
-        for (detector, weight) in self.detectors.iter_mut() {
-            let result = detector.check_reader(reader)?;
-            if matches!(result, DetectionResult::Match) {
-                match_sum += *weight;
-            } else {
-                nomatch_sum += *weight;
-            }
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+        self.check_bytes(&buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads its whole input and never matches
+    struct DrainingDetector;
+
+    impl Detector for DrainingDetector {
+        fn check_bytes(&mut self, _bytes: &[u8]) -> Result<DetectionResult, Box<dyn Error>> {
+            Ok(DetectionResult::NoMatch)
         }
 
-        // Use >= here as a safety measure
-        Ok(if match_sum >= nomatch_sum {
-            DetectionResult::Match
-        } else {
-            DetectionResult::NoMatch
-        })
+        fn check_reader(
+            &mut self,
+            reader: &mut dyn Read,
+        ) -> Result<DetectionResult, Box<dyn Error>> {
+            std::io::copy(reader, &mut std::io::sink())?;
+            Ok(DetectionResult::NoMatch)
+        }
+    }
+
+    /// Matches inputs that contain `pattern`
+    struct PatternDetector {
+        pattern: &'static [u8],
+    }
+
+    impl Detector for PatternDetector {
+        fn check_bytes(&mut self, bytes: &[u8]) -> Result<DetectionResult, Box<dyn Error>> {
+            Ok(
+                if bytes
+                    .windows(self.pattern.len())
+                    .any(|window| window == self.pattern)
+                {
+                    DetectionResult::Match {
+                        signature: Some(1),
+                        value: None,
+                    }
+                } else {
+                    DetectionResult::NoMatch
+                },
+            )
+        }
+
+        fn check_reader(
+            &mut self,
+            reader: &mut dyn Read,
+        ) -> Result<DetectionResult, Box<dyn Error>> {
+            let mut buffer = Vec::new();
+            reader.read_to_end(&mut buffer)?;
+            self.check_bytes(&buffer)
+        }
+    }
+
+    #[test]
+    fn every_detector_checks_the_whole_reader() {
+        let mut draining = DrainingDetector;
+        let mut pattern = PatternDetector { pattern: b"evil" };
+        let mut detector = WeightedDetector::default();
+        detector.add_detector(&mut draining, 1);
+        detector.add_detector(&mut pattern, 1);
+
+        let mut reader = &b"some evil bytes"[..];
+        assert_eq!(
+            detector.check_reader(&mut reader).unwrap(),
+            DetectionResult::Match {
+                signature: Some(1),
+                value: None,
+            }
+        );
     }
 }
@@ -1,7 +1,9 @@
 //! Traits for hash-based detectir implementations
 
 use crate::api::detector::{DetectionResult, Detector};
-use log::debug;
+use log::{debug, warn};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::io::Read;
@@ -22,6 +24,10 @@ pub trait ComparableHash {
     /// Get the hex representation of the digest
     fn get_digest_hex(&self) -> String;
     fn color(&self) -> u8;
+    /// Identifier of the database entry this hash was loaded from, `None` for calculated hashes
+    fn signature_id(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// The `HashAlg` trait allows for hashing algorithms that results in a given
@@ -67,6 +73,13 @@ where
     /// Return a slice of `T` typed ComparableHash that will be used for malware
     /// detection.
     fn get_hashes(&mut self) -> &[H];
+
+    /// Return the hashes that can match a hash of the given color.
+    ///
+    /// Databases that do not group their hashes by color return every hash.
+    fn get_hashes_for_color(&mut self, _color: u8) -> &[H] {
+        self.get_hashes()
+    }
 }
 
 /// The `HashBasedDetector` trait enables the customization of the hash against database comparison of the [`Detector`].
@@ -78,11 +91,21 @@ where
     H: ComparableHash,
 {
     fn do_detect(&mut self, hash: &H) -> Result<DetectionResult, Box<dyn Error>>;
+
+    /// Number of hashes used for the detection, if known.
+    fn hash_count(&mut self) -> Option<usize> {
+        None
+    }
+
+    /// Distance of the closest hash to the last detected hash, if recorded.
+    fn closest_distance(&self) -> Option<i64> {
+        None
+    }
 }
 
 /// Used for buffered reading in [`AbstractHashBasedDetector`], specifies
-/// the read buffer size
-const READ_BUFFER_SIZE: usize = 1024;
+/// the read buffer size when nothing else is configured
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
 
 /// A [`Detector`] implementation to be used with various hashes that can be calculated from the input data
 pub struct AbstractHashBasedDetector<'a, A, H>
@@ -91,6 +114,8 @@ where
     A: HashAlg<H>,
 {
     detector_impl: Box<dyn HashBasedDetector<'a, H>>,
+    /// Reused by [`Detector::check_reader`]
+    read_buffer: Vec<u8>,
     _phantom: PhantomData<A>,
 }
 
@@ -102,10 +127,18 @@ where
     pub fn new(detector_impl: Box<dyn HashBasedDetector<'a, H>>) -> Self {
         Self {
             detector_impl,
+            read_buffer: vec![0; DEFAULT_READ_BUFFER_SIZE],
             _phantom: PhantomData,
         }
     }
 
+    /// Read input in chunks of `size` bytes in [`Detector::check_reader`]. Larger buffers need
+    /// fewer reads for large files.
+    pub fn with_read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer = vec![0; size.max(1)];
+        self
+    }
+
     /// Called by the detector trait implmentations wi
     fn do_detect(&mut self, hash: H) -> Result<DetectionResult, Box<dyn Error>> {
         self.detector_impl.do_detect(&hash)
@@ -131,13 +164,13 @@ where
     }
 
     fn check_reader(&mut self, input: &mut dyn Read) -> Result<DetectionResult, Box<dyn Error>> {
-        let mut buffer = [0; READ_BUFFER_SIZE];
+        let buffer = &mut self.read_buffer;
 
         let mut tlsh = A::new();
-        let mut read = input.read(&mut buffer)?;
+        let mut read = input.read(buffer)?;
         while read > 0 {
             tlsh.update(&buffer[0..read]);
-            read = input.read(&mut buffer)?;
+            read = input.read(buffer)?;
         }
         tlsh.finalize();
         let Some(hash) = tlsh.get_hash() else {
@@ -147,34 +180,100 @@ where
         };
         self.do_detect(hash)
     }
+
+    fn hash_count(&mut self) -> Option<usize> {
+        self.detector_impl.hash_count()
+    }
+
+    fn closest_distance(&self) -> Option<i64> {
+        self.detector_impl.closest_distance()
+    }
 }
 
 /// Implement the SIMBIoTA detection algorithm for [`DetectorImpl`].
 ///
-/// The calculated hash is compared against all hashes in the database, a match is found when the diff
-/// is less than a provided threshold.
+/// The calculated hash is compared against all hashes of the same color in the database, a match is
+/// found when the diff is less than a provided threshold.
+///
+/// The comparisons can be split across a thread pool with [`CompareAgainstAllDetector::with_threads`],
+/// which helps with large databases on multi-core devices.
+///
+/// With [`CompareAgainstAllDetector::with_closest_distance`] every hash is compared instead of
+/// stopping at the first match, and the smallest diff is recorded.
 pub struct CompareAgainstAllDetector<H>
 where
     H: ComparableHash,
 {
-    compare_fn: Box<dyn Fn(&H, &H) -> bool>,
+    compare_fn: Box<dyn Fn(&H, &H) -> bool + Send + Sync>,
     database: Box<dyn HashDatabase<H>>,
+    pool: Option<ThreadPool>,
+    track_closest: bool,
+    closest: Option<i64>,
 }
 impl<'a, H> HashBasedDetector<'a, H> for CompareAgainstAllDetector<H>
 where
-    H: ComparableHash,
+    H: ComparableHash + Sync,
+    H::ResultType: Into<i64>,
 {
     fn do_detect(&mut self, hash: &H) -> Result<DetectionResult, Box<dyn Error>> {
-        let mut compare_counter = 0;
         let start = Instant::now();
-        let mut result = DetectionResult::NoMatch;
-        for stored_hash in self.database.get_hashes() {
-            compare_counter += 1;
-            if (self.compare_fn)(hash, stored_hash) {
-                result = DetectionResult::Match;
-                break;
+        let stored_hashes = self.database.get_hashes_for_color(hash.color());
+        let compare_fn = &self.compare_fn;
+        if self.track_closest {
+            let mut matched: Option<&H> = None;
+            let mut closest: Option<i64> = None;
+            for stored_hash in stored_hashes {
+                let diff = stored_hash.diff(hash).into();
+                closest = Some(closest.map_or(diff, |closest| closest.min(diff)));
+                if matched.is_none() && compare_fn(hash, stored_hash) {
+                    matched = Some(stored_hash);
+                }
             }
+            self.closest = closest;
+            debug!(
+                "compared against {} hashes in {:?}, closest distance: {:?}",
+                stored_hashes.len(),
+                start.elapsed(),
+                closest
+            );
+            return Ok(match matched {
+                Some(stored_hash) => DetectionResult::Match {
+                    signature: stored_hash.signature_id(),
+                    value: Some(stored_hash.diff(hash).into()),
+                },
+                None => DetectionResult::NoMatch,
+            });
         }
+        let (matched, compare_counter) = match &self.pool {
+            // the workers stop at the first match, the counter is an upper bound
+            Some(pool) => (
+                pool.install(|| {
+                    stored_hashes
+                        .par_iter()
+                        .find_any(|stored_hash| compare_fn(hash, stored_hash))
+                }),
+                stored_hashes.len(),
+            ),
+            None => {
+                let mut compare_counter = 0;
+                let mut matched = None;
+                for stored_hash in stored_hashes {
+                    compare_counter += 1;
+                    if compare_fn(hash, stored_hash) {
+                        matched = Some(stored_hash);
+                        break;
+                    }
+                }
+                (matched, compare_counter)
+            }
+        };
+        let result = match matched {
+            Some(stored_hash) => DetectionResult::Match {
+                signature: stored_hash.signature_id(),
+                value: Some(stored_hash.diff(hash).into()),
+            },
+            None => DetectionResult::NoMatch,
+        };
         let end = start.elapsed();
         let msc = end.as_micros() as f64 / compare_counter as f64;
         debug!(
@@ -183,18 +282,59 @@ where
         );
         Ok(result)
     }
+
+    fn hash_count(&mut self) -> Option<usize> {
+        Some(self.database.get_hashes().len())
+    }
+
+    fn closest_distance(&self) -> Option<i64> {
+        self.closest
+    }
 }
 impl<H> CompareAgainstAllDetector<H>
 where
     H: ComparableHash,
 {
+    /// `comparator` is called with the input hash and a stored hash of the same color, and
+    /// decides whether they match. Both hashes are passed so the decision can depend on the
+    /// stored entry, e.g. its color or its own detection distance.
     pub fn new(
         database: Box<dyn HashDatabase<H>>,
-        comparator: Box<dyn Fn(&H, &H) -> bool>,
+        comparator: Box<dyn Fn(&H, &H) -> bool + Send + Sync>,
     ) -> Self {
         Self {
             database,
             compare_fn: comparator,
+            pool: None,
+            track_closest: false,
+            closest: None,
         }
     }
+
+    /// Compare against every hash and record the smallest diff, see
+    /// [`HashBasedDetector::closest_distance`]
+    pub fn with_closest_distance(mut self, enabled: bool) -> Self {
+        self.track_closest = enabled;
+        self
+    }
+
+    /// Compare on `threads` worker threads, 0 or 1 keeps the single-threaded scan
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.pool = if threads > 1 {
+            match ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("tlsh-compare-{i}"))
+                .build()
+            {
+                Ok(pool) => Some(pool),
+                Err(e) => {
+                    warn!("failed to start comparison threads, comparing on one thread: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        self
+    }
 }
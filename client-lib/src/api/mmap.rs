@@ -0,0 +1,63 @@
+//! Read-only memory mapping of files for hashing them in one piece
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+
+/// Files smaller than this are read with a buffer, mapping them is not worth the extra syscalls
+pub(crate) const MMAP_MIN_SIZE: u64 = 1024 * 1024;
+
+/// A read-only, private mapping of a whole file, unmapped on drop
+pub(crate) struct MappedFile {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl MappedFile {
+    /// Map `file` if it is a regular file of at least [`MMAP_MIN_SIZE`] bytes
+    ///
+    /// Returns `None` for pipes, special files, small files and if `mmap` fails, callers
+    /// should read the file instead.
+    pub(crate) fn map(file: &File) -> Option<Self> {
+        let meta = file.metadata().ok()?;
+        if !meta.file_type().is_file() || meta.len() < MMAP_MIN_SIZE {
+            return None;
+        }
+        let len = usize::try_from(meta.len()).ok()?;
+        // SAFETY: Standard LibC call, the result is checked before use
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        // SAFETY: `ptr` is a valid mapping of `len` bytes, the advice is only a hint
+        unsafe {
+            libc::madvise(ptr, len, libc::MADV_SEQUENTIAL);
+        }
+        Some(Self { ptr, len })
+    }
+
+    /// The contents of the file
+    ///
+    /// Truncating the file while it is mapped makes reading the missing pages raise `SIGBUS`.
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        // SAFETY: the mapping is valid for `len` bytes until drop
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        // SAFETY: unmaps the mapping created in `map`
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
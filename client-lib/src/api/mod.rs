@@ -9,3 +9,4 @@
 pub mod cache;
 pub mod detector;
 pub mod hash;
+mod mmap;
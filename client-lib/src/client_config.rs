@@ -1,22 +1,77 @@
 use log::{info, warn};
+use serde::Deserialize;
 use std::any::Any;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use yaml_rust::yaml::Hash;
 use yaml_rust::{Yaml, YamlLoader};
 
 #[derive(Debug)]
 pub struct DetectorConfig {
     pub class: String,
     pub config: HashMap<String, Box<dyn Any>>,
+    /// Files larger than this many bytes are not scanned, `None` means unlimited
+    pub max_file_size: Option<u64>,
+    /// Which files are scanned at all
+    pub scan_only: ScanFilter,
+    /// Whether detections are enforced or only reported
+    pub mode: DetectorMode,
+    /// What happens to detected files in enforce mode
+    pub action: FileAction,
+    /// Maximum number of scanned files per second, `None` means unlimited
+    pub max_files_per_sec: Option<u32>,
+    /// Number of files that can be scanned at once before the rate limit applies
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetectorMode {
+    /// Deny access to detected files and quarantine them
+    Enforce,
+    /// Only log and alert, never deny access or quarantine
+    Observe,
+}
+
+/// What happens to a detected file after access to it was denied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileAction {
+    /// Move the file to quarantine, if the quarantine is enabled
+    Quarantine,
+    /// Delete the file
+    Remove,
+    /// Leave the file in place
+    Keep,
+}
+
+/// File types passed to the detector, other files are allowed without scanning
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScanFilter {
+    /// Scan every file
+    All,
+    /// Only scan ELF binaries and scripts starting with `#!`
+    Executable,
 }
 
 #[derive(Debug)]
 pub struct DatabaseConfig {
+    /// First database file, the one kept up to date by simbiota-update
     pub database_path: PathBuf,
+    /// Further database files, their objects are merged after the ones of `database_path`
+    pub extra_database_paths: Vec<PathBuf>,
+    /// Read objects from the database file on demand instead of loading the whole file
     pub(crate) low_memory: bool,
 }
 
+impl DatabaseConfig {
+    /// Every database file in the configured order
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.database_path.as_path())
+            .chain(self.extra_database_paths.iter().map(PathBuf::as_path))
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct ClientConfig {
@@ -25,37 +80,96 @@ pub struct ClientConfig {
     pub(crate) raw_config: Yaml,
 }
 
+/// Overrides the first file of `database.database_file`
+pub const ENV_DATABASE_PATH: &str = "SIMBIOTA_DATABASE_PATH";
+
+#[derive(Debug)]
+pub enum ConfigLoadError {
+    Missing(PathBuf),
+    Read(PathBuf, std::io::Error),
+    /// The file is not a valid TOML or YAML config
+    Invalid(PathBuf, String),
+}
+
+impl Display for ConfigLoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing(path) => write!(f, "config file not found at {}", path.display()),
+            Self::Read(path, e) => write!(f, "cannot read config {}: {}", path.display(), e),
+            Self::Invalid(path, e) => write!(f, "config {} is not valid: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigLoadError {}
+
 impl ClientConfig {
+    /// Load the config file, then apply the environment overrides
+    ///
+    /// Precedence: command line flags > environment > config file > defaults
+    ///
+    /// Exits the process if the config file is missing or invalid, see
+    /// [`ClientConfig::try_load_from`]
     pub fn load_from(path: &Path, use_default: bool) -> Self {
+        match Self::try_load_from(path, use_default) {
+            Ok(config) => config,
+            Err(ConfigLoadError::Invalid(_, e)) => {
+                eprintln!("The specified config is not valid: {}", e);
+                exit(1);
+            }
+            Err(_) => {
+                eprintln!("The specified config file does not exists! Bailing out...");
+                exit(1);
+            }
+        }
+    }
+
+    /// [`ClientConfig::load_from`] that returns an error instead of exiting
+    ///
+    /// Only unparsable files are reported, missing or malformed values in a YAML config still
+    /// panic.
+    pub fn try_load_from(path: &Path, use_default: bool) -> Result<Self, ConfigLoadError> {
+        let mut config = Self::load_file(path, use_default)?;
+        config.apply_env_overrides();
+        Ok(config)
+    }
+
+    /// Values from set environment variables replace the ones read from the file
+    pub fn apply_env_overrides(&mut self) {
+        if let Some(path) = std::env::var_os(ENV_DATABASE_PATH) {
+            info!("database path overridden by {}", ENV_DATABASE_PATH);
+            self.database.database_path = PathBuf::from(path);
+        }
+    }
+
+    fn load_file(path: &Path, use_default: bool) -> Result<Self, ConfigLoadError> {
         info!("loading config from {}", path.display());
 
         if !path.exists() {
             if use_default {
                 warn!("config file not found at the default location! Using default config");
                 let config = ClientConfig::default();
-                return config;
+                return Ok(config);
             } else {
-                eprintln!("The specified config file does not exists! Bailing out...");
-                exit(1);
+                return Err(ConfigLoadError::Missing(path.to_path_buf()));
             }
         }
 
-        let config_content = {
-            let maybe_contents = std::fs::read_to_string(path);
-            let Ok(content) = maybe_contents else {
-                eprintln!("The specified config file does not exists! Bailing out...");
-                exit(1);
-            };
-            content
-        };
+        let config_content = std::fs::read_to_string(path)
+            .map_err(|e| ConfigLoadError::Read(path.to_path_buf(), e))?;
 
-        let maybe_doc = YamlLoader::load_from_str(config_content.as_str());
-        let Ok(doc) = maybe_doc else {
-            warn!("failed to parse YAML config: {}", maybe_doc.unwrap_err().to_string());
-            eprintln!("The specified config is not valid! Bailing out...");
-            exit(1);
-        };
-        Self::from_yaml(doc)
+        if is_toml_path(path) {
+            return Self::load_toml(&config_content).map_err(|e| {
+                warn!("failed to parse TOML config: {}", e);
+                ConfigLoadError::Invalid(path.to_path_buf(), e.to_string())
+            });
+        }
+
+        let doc = YamlLoader::load_from_str(config_content.as_str()).map_err(|e| {
+            warn!("failed to parse YAML config: {}", e);
+            ConfigLoadError::Invalid(path.to_path_buf(), e.to_string())
+        })?;
+        Ok(Self::from_yaml(doc))
     }
 
     fn from_yaml(mut yaml: Vec<Yaml>) -> Self {
@@ -76,27 +190,120 @@ impl ClientConfig {
             }
         }
 
+        let max_file_size = max_file_size_from_mb(
+            detector_cfg
+                .get(&Yaml::String("max_file_size_mb".to_string()))
+                .and_then(|v| v.as_i64()),
+        );
+
+        let scan_only = parse_scan_filter(
+            detector_cfg
+                .get(&Yaml::String("scan_only".to_string()))
+                .and_then(|v| v.as_str()),
+        );
+
+        let mode = parse_detector_mode(
+            detector_cfg
+                .get(&Yaml::String("mode".to_string()))
+                .and_then(|v| v.as_str()),
+        );
+
+        let action = parse_file_action(
+            detector_cfg
+                .get(&Yaml::String("action".to_string()))
+                .and_then(|v| v.as_str()),
+        );
+
+        let max_files_per_sec = rate_from_config(
+            detector_cfg
+                .get(&Yaml::String("max_files_per_sec".to_string()))
+                .and_then(|v| v.as_i64()),
+        );
+        let burst = burst_from_config(
+            detector_cfg
+                .get(&Yaml::String("burst".to_string()))
+                .and_then(|v| v.as_i64()),
+            max_files_per_sec,
+        );
+
         // Load database config
         let database_cfg = doc["database"].as_hash().expect("database config expected");
-        let path = database_cfg[&Yaml::String("database_file".to_owned())]
-            .as_str()
-            .expect("database file config missing");
+        let paths = match &database_cfg[&Yaml::String("database_file".to_owned())] {
+            Yaml::Array(files) => files
+                .iter()
+                .map(|file| PathBuf::from(file.as_str().expect("invalid database file config")))
+                .collect(),
+            file => vec![PathBuf::from(
+                file.as_str().expect("database file config missing"),
+            )],
+        };
+        let (database_path, extra_database_paths) = split_database_files(paths);
+
+        let low_memory = database_cfg
+            .get(&Yaml::String("low_memory".to_owned()))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
         let database_config = DatabaseConfig {
-            database_path: PathBuf::from(path),
-            low_memory: false,
+            database_path,
+            extra_database_paths,
+            low_memory,
         };
 
         Self {
             detector: DetectorConfig {
                 class: class.to_string(),
                 config,
+                max_file_size,
+                scan_only,
+                mode,
+                action,
+                max_files_per_sec,
+                burst,
             },
             database: database_config,
             raw_config: doc,
         }
     }
 
+    /// Load the config from a TOML document
+    ///
+    /// The detector and database sections are deserialized with serde, so errors name the
+    /// offending key. The whole document is also kept as YAML for the options read from
+    /// the raw config.
+    pub fn load_toml(content: &str) -> Result<Self, toml::de::Error> {
+        let raw: toml::Value = toml::from_str(content)?;
+        let config: TomlClientConfig = toml::from_str(content)?;
+        let detector = config.detector;
+        let max_files_per_sec = rate_from_config(detector.max_files_per_sec);
+        Ok(Self {
+            detector: DetectorConfig {
+                class: detector.class,
+                config: detector
+                    .config
+                    .iter()
+                    .map(|(key, value)| (key.clone(), toml_to_any(value)))
+                    .collect(),
+                max_file_size: max_file_size_from_mb(detector.max_file_size_mb),
+                scan_only: parse_scan_filter(detector.scan_only.as_deref()),
+                mode: parse_detector_mode(detector.mode.as_deref()),
+                action: parse_file_action(detector.action.as_deref()),
+                max_files_per_sec,
+                burst: burst_from_config(detector.burst, max_files_per_sec),
+            },
+            database: {
+                let (database_path, extra_database_paths) =
+                    split_database_files(config.database.database_file.into_paths());
+                DatabaseConfig {
+                    database_path,
+                    extra_database_paths,
+                    low_memory: config.database.low_memory,
+                }
+            },
+            raw_config: toml_to_yaml(&raw),
+        })
+    }
+
     fn yaml_to_any(yaml: &Yaml) -> Box<dyn Any> {
         let value: Box<dyn Any> = match yaml {
             Yaml::Real(v) => Box::new(v.clone()),
@@ -113,7 +320,11 @@ impl ClientConfig {
             Yaml::Hash(v) => {
                 let mut map = HashMap::new();
                 for (key, val) in v {
-                    let key = key.as_str().unwrap().to_string();
+                    // integer keys are kept as strings, e.g. TLSH colors
+                    let key = match key {
+                        Yaml::Integer(i) => i.to_string(),
+                        key => key.as_str().unwrap().to_string(),
+                    };
                     let val = ClientConfig::yaml_to_any(val);
                     map.insert(key, val);
                 }
@@ -131,12 +342,217 @@ impl Default for ClientConfig {
             detector: DetectorConfig {
                 class: "dummy".to_string(),
                 config: Default::default(),
+                max_file_size: None,
+                scan_only: ScanFilter::All,
+                mode: DetectorMode::Enforce,
+                action: FileAction::Quarantine,
+                max_files_per_sec: None,
+                burst: 1,
             },
             database: DatabaseConfig {
                 database_path: PathBuf::from("/var/lib/simbiota/database.sdb"),
+                extra_database_paths: Vec::new(),
                 low_memory: false,
             },
             raw_config: Yaml::Null,
         }
     }
 }
+
+#[derive(Deserialize)]
+struct TomlClientConfig {
+    detector: TomlDetectorConfig,
+    database: TomlDatabaseConfig,
+}
+
+#[derive(Deserialize)]
+struct TomlDetectorConfig {
+    class: String,
+    #[serde(default)]
+    config: HashMap<String, toml::Value>,
+    max_file_size_mb: Option<i64>,
+    scan_only: Option<String>,
+    mode: Option<String>,
+    action: Option<String>,
+    max_files_per_sec: Option<i64>,
+    burst: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TomlDatabaseConfig {
+    database_file: TomlDatabaseFiles,
+    #[serde(default)]
+    low_memory: bool,
+}
+
+/// `database_file` is either a single path or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TomlDatabaseFiles {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+impl TomlDatabaseFiles {
+    fn into_paths(self) -> Vec<PathBuf> {
+        match self {
+            Self::One(path) => vec![path],
+            Self::Many(paths) => paths,
+        }
+    }
+}
+
+/// Split the configured database files into the first one and the rest
+fn split_database_files(mut paths: Vec<PathBuf>) -> (PathBuf, Vec<PathBuf>) {
+    if paths.is_empty() {
+        panic!("database file config missing");
+    }
+    let first = paths.remove(0);
+    (first, paths)
+}
+
+/// Whether the config file should be parsed as TOML, based on its extension
+pub fn is_toml_path(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "toml")
+}
+
+/// Convert a TOML document to the YAML representation the rest of the config code uses
+pub fn toml_to_yaml(value: &toml::Value) -> Yaml {
+    match value {
+        toml::Value::String(v) => Yaml::String(v.clone()),
+        toml::Value::Integer(v) => Yaml::Integer(*v),
+        toml::Value::Float(v) => Yaml::Real(v.to_string()),
+        toml::Value::Boolean(v) => Yaml::Boolean(*v),
+        toml::Value::Datetime(v) => Yaml::String(v.to_string()),
+        toml::Value::Array(v) => Yaml::Array(v.iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(v) => {
+            let mut hash = Hash::new();
+            for (key, val) in v {
+                hash.insert(Yaml::String(key.clone()), toml_to_yaml(val));
+            }
+            Yaml::Hash(hash)
+        }
+    }
+}
+
+/// Same value types as `ClientConfig::yaml_to_any`, so detectors work with both formats
+fn toml_to_any(value: &toml::Value) -> Box<dyn Any> {
+    match value {
+        toml::Value::String(v) => Box::new(v.clone()),
+        toml::Value::Integer(v) => Box::new(*v),
+        toml::Value::Float(v) => Box::new(v.to_string()),
+        toml::Value::Boolean(v) => Box::new(*v),
+        toml::Value::Datetime(v) => Box::new(v.to_string()),
+        toml::Value::Array(v) => Box::new(v.iter().map(toml_to_any).collect::<Vec<_>>()),
+        toml::Value::Table(v) => Box::new(
+            v.iter()
+                .map(|(key, val)| (key.clone(), toml_to_any(val)))
+                .collect::<HashMap<_, _>>(),
+        ),
+    }
+}
+
+fn max_file_size_from_mb(mb: Option<i64>) -> Option<u64> {
+    mb.filter(|mb| *mb > 0).map(|mb| mb as u64 * 1024 * 1024)
+}
+
+/// 0 or unset means unlimited
+fn rate_from_config(value: Option<i64>) -> Option<u32> {
+    value
+        .filter(|rate| *rate > 0)
+        .map(|rate| rate.min(u32::MAX as i64) as u32)
+}
+
+/// One second worth of files by default
+fn burst_from_config(value: Option<i64>, rate: Option<u32>) -> u32 {
+    value
+        .filter(|burst| *burst > 0)
+        .map(|burst| burst.min(u32::MAX as i64) as u32)
+        .or(rate)
+        .unwrap_or(1)
+}
+
+fn parse_scan_filter(value: Option<&str>) -> ScanFilter {
+    match value {
+        None | Some("all") => ScanFilter::All,
+        Some("executable") => ScanFilter::Executable,
+        Some(other) => panic!("invalid scan_only value: {}", other),
+    }
+}
+
+fn parse_detector_mode(value: Option<&str>) -> DetectorMode {
+    match value {
+        None | Some("enforce") => DetectorMode::Enforce,
+        Some("observe") => DetectorMode::Observe,
+        Some(other) => panic!("invalid detector mode: {}", other),
+    }
+}
+
+fn parse_file_action(value: Option<&str>) -> FileAction {
+    match value {
+        None | Some("quarantine") => FileAction::Quarantine,
+        Some("remove") | Some("delete") => FileAction::Remove,
+        Some("none") => FileAction::Keep,
+        Some(other) => panic!("invalid detector action: {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the only test touching ENV_DATABASE_PATH, the environment is shared by every test
+    #[test]
+    fn env_overrides_database_path_from_file() {
+        let config_path =
+            std::env::temp_dir().join(format!("simbiota-env-test-{}.toml", std::process::id()));
+        std::fs::write(
+            &config_path,
+            r#"
+[detector]
+class = "tlsh"
+
+[database]
+database_file = ["/file/base.sdb", "/file/extra.sdb"]
+"#,
+        )
+        .unwrap();
+
+        std::env::set_var(ENV_DATABASE_PATH, "/env/override.sdb");
+        let overridden = ClientConfig::load_from(&config_path, false);
+        std::env::remove_var(ENV_DATABASE_PATH);
+        let from_file = ClientConfig::load_from(&config_path, false);
+        std::fs::remove_file(&config_path).unwrap();
+
+        assert_eq!(
+            overridden.database.database_path,
+            PathBuf::from("/env/override.sdb")
+        );
+        // only the first file is replaced
+        assert_eq!(
+            overridden.database.extra_database_paths,
+            vec![PathBuf::from("/file/extra.sdb")]
+        );
+        assert_eq!(
+            from_file.database.database_path,
+            PathBuf::from("/file/base.sdb")
+        );
+    }
+
+    #[test]
+    fn try_load_reports_unusable_files() {
+        let missing =
+            std::env::temp_dir().join(format!("simbiota-missing-{}.toml", std::process::id()));
+        assert!(matches!(
+            ClientConfig::try_load_from(&missing, false),
+            Err(ConfigLoadError::Missing(_))
+        ));
+
+        let invalid =
+            std::env::temp_dir().join(format!("simbiota-invalid-{}.toml", std::process::id()));
+        std::fs::write(&invalid, "[detector").unwrap();
+        let result = ClientConfig::try_load_from(&invalid, false);
+        std::fs::remove_file(&invalid).unwrap();
+        assert!(matches!(result, Err(ConfigLoadError::Invalid(..))));
+    }
+}
@@ -0,0 +1,77 @@
+use crate::system_database::SystemDatabase;
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Reload `database` whenever one of the database files in `paths` is replaced
+///
+/// The files are watched from a new thread, `on_reload` is called after every reload. Detectors
+/// see the reloaded objects on their next check.
+pub fn watch_database(
+    database: Arc<Mutex<SystemDatabase>>,
+    paths: &[PathBuf],
+    on_reload: impl Fn() + Send + 'static,
+) -> std::io::Result<()> {
+    let mut inotify = Inotify::init()?;
+    // Watch the directories instead of the files themselves: the updater replaces the
+    // database with a rename, and a watch on the old inode would never fire again.
+    let mut watched_names: HashMap<WatchDescriptor, Vec<OsString>> = HashMap::new();
+    for path in paths {
+        let database_dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let Some(database_name) = path.file_name().map(|name| name.to_os_string()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid database file path: {}", path.display()),
+            ));
+        };
+        // files in the same directory share the watch
+        let watch = inotify
+            .watches()
+            .add(&database_dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+        watched_names.entry(watch).or_default().push(database_name);
+    }
+
+    let file_count = paths.len();
+    thread::Builder::new()
+        .name("DatabaseWatcher".to_string())
+        .spawn(move || {
+            debug!("database file watcher started");
+            let mut buffer = [0; 1024];
+            info!("watching {} database files for changes", file_count);
+            loop {
+                let events = match inotify.read_events_blocking(&mut buffer) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        error!("inotify wait failed, database changes are not followed: {e}");
+                        return;
+                    }
+                };
+
+                for event in events {
+                    let Some(name) = event.name else {
+                        continue;
+                    };
+                    let is_database = watched_names
+                        .get(&event.wd)
+                        .map_or(false, |names| names.iter().any(|watched| watched == name));
+                    if !is_database {
+                        continue;
+                    }
+                    info!("database file {:?} changed, reloading...", name);
+                    let mut database_lock = database.lock().unwrap();
+                    database_lock.pre_update();
+                    database_lock.mark_update();
+                    drop(database_lock);
+                    on_reload();
+                }
+            }
+        })?;
+    Ok(())
+}
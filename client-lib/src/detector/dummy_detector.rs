@@ -0,0 +1,69 @@
+//! A detector that does not use a database
+//!
+//! It is the detector class of the default config, and is useful for testing the code around
+//! the detectors.
+use crate::api::detector::{DetectionResult, Detector};
+use crate::detector::DetectorProvider;
+use crate::system_database::SystemDatabase;
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+/// Returns the same result for every input
+pub struct DummyDetector {
+    result: DetectionResult,
+}
+
+impl DummyDetector {
+    pub fn new(result: DetectionResult) -> Self {
+        Self { result }
+    }
+}
+
+impl Detector for DummyDetector {
+    fn check_bytes(&mut self, _bytes: &[u8]) -> Result<DetectionResult, Box<dyn Error>> {
+        Ok(self.result)
+    }
+
+    fn check_reader(&mut self, _reader: &mut dyn Read) -> Result<DetectionResult, Box<dyn Error>> {
+        Ok(self.result)
+    }
+}
+
+/// Provides [`DummyDetector`]s for the `dummy` detector class
+///
+/// The `match` config option selects the result, every input matches if it is true.
+/// The database is not used.
+pub struct DummyDetectorProvider;
+impl DummyDetectorProvider {
+    pub fn new() -> Self {
+        Self
+    }
+}
+impl DetectorProvider for DummyDetectorProvider {
+    fn get_detector(
+        &self,
+        configuration: &HashMap<String, Box<dyn Any>>,
+        _system_database: Arc<Mutex<SystemDatabase>>,
+    ) -> Box<dyn Detector> {
+        let matches = if let Some(matches) = configuration.get("match") {
+            let Some(matches) = matches.downcast_ref::<bool>() else {
+                panic!("invalid match config")
+            };
+            *matches
+        } else {
+            false
+        };
+        let result = if matches {
+            DetectionResult::Match {
+                signature: None,
+                value: None,
+            }
+        } else {
+            DetectionResult::NoMatch
+        };
+        Box::new(DummyDetector::new(result))
+    }
+}
@@ -1,11 +1,23 @@
 use crate::api::detector::Detector;
+use crate::detector::dummy_detector::DummyDetectorProvider;
+use crate::detector::tlsh_detector::SimpleTLSHDetectorProvider;
 use crate::system_database::SystemDatabase;
 use std::any::Any;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
+pub mod dummy_detector;
 pub mod tlsh_detector;
 
+/// The provider of a detector class built into the library
+pub fn builtin_provider(class: &str) -> Option<Box<dyn DetectorProvider>> {
+    match class {
+        "simple_tlsh" => Some(Box::new(SimpleTLSHDetectorProvider::new())),
+        "dummy" => Some(Box::new(DummyDetectorProvider::new())),
+        _ => None,
+    }
+}
+
 pub trait DetectorProvider {
     fn get_detector(
         &self,
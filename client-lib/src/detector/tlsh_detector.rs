@@ -3,6 +3,7 @@ use log::{debug, warn};
 use crate::api::detector::Detector;
 use crate::api::hash::{
     AbstractHashBasedDetector, ComparableHash, CompareAgainstAllDetector, HashAlg, HashDatabase,
+    DEFAULT_READ_BUFFER_SIZE,
 };
 use crate::detector::DetectorProvider;
 use crate::system_database::{SystemDatabase, SystemDatabaseObject};
@@ -12,14 +13,49 @@ use simbiota_database::ObjectImpl;
 use simbiota_tlsh::{TLSHBuilder, TLSH};
 use std::any::Any;
 use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
 
 pub struct ComparableTLSHHash {
     pub(crate) inner: TLSH,
     detection_distance: u8,
+    /// Position of the entry in the merged entries of the database files
+    entry_index: Option<usize>,
+    /// Quartile ratio byte of the header, `None` if the raw hash has an unexpected layout
+    q_ratio: Option<u8>,
 }
 
+/// Raw TLSH length with a 1 byte checksum: checksum, length, quartile ratios and 32 body bytes
+const TLSH_RAW_LEN: usize = 35;
+
 impl ComparableTLSHHash {
+    fn new(inner: TLSH, detection_distance: u8, entry_index: Option<usize>) -> Self {
+        let raw = inner.to_raw();
+        let q_ratio = if raw.len() == TLSH_RAW_LEN {
+            Some(raw[2])
+        } else {
+            None
+        };
+        Self {
+            inner,
+            detection_distance,
+            entry_index,
+            q_ratio,
+        }
+    }
+
+    /// Distance of the quartile ratios in the headers, a lower bound of [`ComparableHash::diff`]
+    ///
+    /// The full diff adds the length, checksum and body distances to this, so a stored hash
+    /// whose header distance already reaches the threshold cannot match. Only the two nibbles
+    /// of one byte are used, which makes the bound independent of their order.
+    pub fn header_diff(&self, other: &Self) -> i32 {
+        match (self.q_ratio, other.q_ratio) {
+            (Some(a), Some(b)) => q_ratio_diff(a & 0x0f, b & 0x0f) + q_ratio_diff(a >> 4, b >> 4),
+            _ => 0,
+        }
+    }
+
     pub fn detection_distance(&self) -> u8 {
         if self.detection_distance == 0 {
             panic!("detection distance not set");
@@ -28,6 +64,17 @@ impl ComparableTLSHHash {
     }
 }
 
+/// Distance of two quartile ratio nibbles, scored like the reference TLSH implementation
+fn q_ratio_diff(a: u8, b: u8) -> i32 {
+    let diff = (a as i32 - b as i32).rem_euclid(16);
+    let diff = diff.min(16 - diff);
+    if diff <= 1 {
+        diff
+    } else {
+        (diff - 1) * 12
+    }
+}
+
 pub struct TLSHHashAlg {
     builder: TLSHBuilder,
 }
@@ -52,10 +99,7 @@ impl HashAlg<ComparableTLSHHash> for TLSHHashAlg {
             return None;
         };
         debug!("TLSH hash: {}", raw_hash.to_digest());
-        Some(ComparableTLSHHash {
-            inner: raw_hash,
-            detection_distance: 0,
-        })
+        Some(ComparableTLSHHash::new(raw_hash, 0, None))
     }
 }
 
@@ -77,6 +121,10 @@ impl ComparableHash for ComparableTLSHHash {
     fn color(&self) -> u8 {
         self.inner.color
     }
+
+    fn signature_id(&self) -> Option<usize> {
+        self.entry_index
+    }
 }
 
 pub struct SimpleTLSHDetectorProvider;
@@ -91,6 +139,39 @@ impl DetectorProvider for SimpleTLSHDetectorProvider {
         configuration: &HashMap<String, Box<dyn Any>>,
         system_database: Arc<Mutex<SystemDatabase>>,
     ) -> Box<dyn Detector> {
+        let color_thresholds = color_thresholds(configuration);
+        let threads = if let Some(threads) = configuration.get("threads") {
+            let Some(threads) = threads.downcast_ref::<i64>() else {
+                panic!("invalid threads config")
+            };
+            (*threads).max(0) as usize
+        } else {
+            1
+        };
+        let report_distance = if let Some(report) = configuration.get("report_distance") {
+            let Some(report) = report.downcast_ref::<bool>() else {
+                panic!("invalid report_distance config")
+            };
+            *report
+        } else {
+            false
+        };
+        let header_filter = if let Some(filter) = configuration.get("header_filter") {
+            let Some(filter) = filter.downcast_ref::<bool>() else {
+                panic!("invalid header_filter config")
+            };
+            *filter
+        } else {
+            false
+        };
+        let read_buffer_size = if let Some(size) = configuration.get("read_buffer_size") {
+            let Some(size) = size.downcast_ref::<i64>() else {
+                panic!("invalid read_buffer_size config")
+            };
+            (*size).max(1) as usize
+        } else {
+            DEFAULT_READ_BUFFER_SIZE
+        };
         let mut system_database = system_database.lock().unwrap();
         let comparator = if let Some(object) =
             system_database.get_object::<ColoredTLSHWithDistanceObject>(0x0003)
@@ -99,18 +180,15 @@ impl DetectorProvider for SimpleTLSHDetectorProvider {
             CompareAgainstAllDetector::new(
                 Box::new(database),
                 Box::new(move |hash, stored_hash| {
-                    let diff = stored_hash.diff(hash);
-                    if diff < stored_hash.detection_distance() as i32 {
-                        debug!(
-                            "TLSH below threshold: {} < {}",
-                            diff,
-                            stored_hash.detection_distance()
-                        );
-                        return true;
-                    }
-                    false
+                    let threshold = color_thresholds
+                        .get(&stored_hash.color())
+                        .copied()
+                        .unwrap_or(stored_hash.detection_distance() as i32);
+                    below_threshold(hash, stored_hash, threshold, header_filter)
                 }),
             )
+            .with_threads(threads)
+            .with_closest_distance(report_distance)
         } else if let Some(legacy_object) = system_database.get_object::<ColoredTLSHObject>(0x0002)
         {
             warn!("using legacy database format, please update the database");
@@ -126,29 +204,129 @@ impl DetectorProvider for SimpleTLSHDetectorProvider {
             CompareAgainstAllDetector::new(
                 Box::new(database),
                 Box::new(move |hash, stored_hash| {
-                    let diff = stored_hash.diff(hash);
-                    if diff < threshold {
-                        debug!("TLSH below threshold: {} < {threshold}", diff);
-                        return true;
-                    }
-                    false
+                    let threshold = color_thresholds
+                        .get(&stored_hash.color())
+                        .copied()
+                        .unwrap_or(threshold);
+                    below_threshold(hash, stored_hash, threshold, header_filter)
                 }),
             )
+            .with_threads(threads)
+            .with_closest_distance(report_distance)
         } else {
             panic!(
                 "no usable object found in database. Please update the database to a later version"
             )
         };
         let detector: AbstractHashBasedDetector<TLSHHashAlg, ComparableTLSHHash> =
-            AbstractHashBasedDetector::new(Box::from(comparator));
+            AbstractHashBasedDetector::new(Box::from(comparator))
+                .with_read_buffer_size(read_buffer_size);
 
         Box::new(detector)
     }
 }
 
+/// The match decision of the comparators: the diff of the hashes is strictly below `threshold`
+///
+/// With `header_filter`, stored hashes whose header distance alone reaches the threshold are
+/// rejected without computing the full diff.
+fn below_threshold(
+    hash: &ComparableTLSHHash,
+    stored_hash: &ComparableTLSHHash,
+    threshold: i32,
+    header_filter: bool,
+) -> bool {
+    if header_filter && stored_hash.header_diff(hash) >= threshold {
+        return false;
+    }
+    let diff = stored_hash.diff(hash);
+    if diff < threshold {
+        debug!("TLSH below threshold: {} < {}", diff, threshold);
+        return true;
+    }
+    false
+}
+
+/// Reads `color_thresholds`, a map from TLSH color to the detection distance of the hashes
+/// with that color, which overrides `threshold` and the distances stored in the database
+fn color_thresholds(configuration: &HashMap<String, Box<dyn Any>>) -> HashMap<u8, i32> {
+    let Some(thresholds) = configuration.get("color_thresholds") else {
+        return HashMap::new();
+    };
+    let Some(thresholds) = thresholds.downcast_ref::<HashMap<String, Box<dyn Any>>>() else {
+        panic!("invalid color_thresholds config, expected a map")
+    };
+    thresholds
+        .iter()
+        .map(|(color, threshold)| {
+            let Ok(color) = color.parse::<u8>() else {
+                panic!("invalid TLSH color in color_thresholds: {color}")
+            };
+            let Some(threshold) = threshold.downcast_ref::<i64>() else {
+                panic!("invalid threshold for color {color}")
+            };
+            (color, *threshold as i32)
+        })
+        .collect()
+}
+
+/// Concatenate the `(raw hash, TLSH, distance)` entries of every database file in order
+///
+/// An entry with the same raw hash as an earlier one replaces it at its position, so later
+/// files win on conflicts. The position in the merged list is the signature id of the entry.
+fn merge_entries(entries: Vec<(Vec<u8>, TLSH, u8)>) -> Vec<ComparableTLSHHash> {
+    let mut positions: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut hashes: Vec<ComparableTLSHHash> = Vec::new();
+    for (raw, tlsh, distance) in entries {
+        let index = *positions.entry(raw).or_insert(hashes.len());
+        let hash = ComparableTLSHHash::new(tlsh, distance, Some(index));
+        if index == hashes.len() {
+            hashes.push(hash);
+        } else {
+            hashes[index] = hash;
+        }
+    }
+    hashes
+}
+
+/// Database hashes grouped by their TLSH color
+///
+/// The hashes are kept in one vector sorted by color, so both every hash and the hashes of a
+/// single color can be returned as a slice.
+#[derive(Default)]
+struct ColoredHashes {
+    hashes: Vec<ComparableTLSHHash>,
+    colors: HashMap<u8, Range<usize>>,
+}
+
+impl ColoredHashes {
+    fn new(mut hashes: Vec<ComparableTLSHHash>) -> Self {
+        hashes.sort_by_key(|hash| hash.color());
+        let mut colors: HashMap<u8, Range<usize>> = HashMap::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            colors
+                .entry(hash.color())
+                .and_modify(|range| range.end = i + 1)
+                .or_insert(i..i + 1);
+        }
+        Self { hashes, colors }
+    }
+
+    fn all(&self) -> &[ComparableTLSHHash] {
+        &self.hashes
+    }
+
+    fn with_color(&self, color: u8) -> &[ComparableTLSHHash] {
+        match self.colors.get(&color) {
+            Some(range) => &self.hashes[range.clone()],
+            None => &[],
+        }
+    }
+}
+
 pub(crate) struct DistancedTLSHDatabase {
     sdo: Arc<SystemDatabaseObject>,
-    hashes: HashMap<u8, Vec<ComparableTLSHHash>>,
+    hashes: ColoredHashes,
 }
 
 impl HashDatabase<ComparableTLSHHash> for DistancedTLSHDatabase {
@@ -156,37 +334,42 @@ impl HashDatabase<ComparableTLSHHash> for DistancedTLSHDatabase {
         if self.sdo.has_changed() {
             self.reload();
         }
-        self.hashes[&0u8].as_slice()
+        self.hashes.all()
+    }
+
+    fn get_hashes_for_color(&mut self, color: u8) -> &[ComparableTLSHHash] {
+        if self.sdo.has_changed() {
+            self.reload();
+        }
+        self.hashes.with_color(color)
     }
 }
 
 impl DistancedTLSHDatabase {
     pub fn reload(&mut self) {
         debug!("Reloading TLSH store");
-        self.hashes.clear();
-
-        let object = self.sdo.object().lock().unwrap().clone();
-        let tlsh_obj =
-            ColoredTLSHWithDistanceObject::from_object(object).expect("invalid database object");
-
-        for hash in tlsh_obj.get_entries() {
-            let tlsh_hash = TLSH::from_raw(&hash.tlsh_bytes);
-            self.hashes
-                .entry(tlsh_hash.color)
-                .or_insert_with(Default::default);
-            let colored_hashes = self.hashes.get_mut(&tlsh_hash.color).unwrap();
-            colored_hashes.push(ComparableTLSHHash {
-                inner: tlsh_hash,
-                detection_distance: hash.distance,
-            });
+
+        let objects = self.sdo.objects().lock().unwrap().clone();
+        let mut entries = Vec::new();
+        for object in objects {
+            let tlsh_obj = ColoredTLSHWithDistanceObject::from_object(object)
+                .expect("invalid database object");
+            for hash in tlsh_obj.get_entries() {
+                entries.push((
+                    hash.tlsh_bytes.to_vec(),
+                    TLSH::from_raw(&hash.tlsh_bytes),
+                    hash.distance,
+                ));
+            }
         }
-        debug!("{} hashes in database", self.hashes[&0].len());
+        self.hashes = ColoredHashes::new(merge_entries(entries));
+        debug!("{} hashes in database", self.hashes.all().len());
     }
 
     pub fn new(sdo: Arc<SystemDatabaseObject>) -> Self {
         let mut db = Self {
             sdo,
-            hashes: HashMap::new(),
+            hashes: ColoredHashes::default(),
         };
         db.reload();
         db
@@ -195,7 +378,7 @@ impl DistancedTLSHDatabase {
 
 pub(crate) struct LegacyTLSHDatabase {
     sdo: Arc<SystemDatabaseObject>,
-    hashes: HashMap<u8, Vec<ComparableTLSHHash>>,
+    hashes: ColoredHashes,
 }
 
 impl HashDatabase<ComparableTLSHHash> for LegacyTLSHDatabase {
@@ -203,38 +386,86 @@ impl HashDatabase<ComparableTLSHHash> for LegacyTLSHDatabase {
         if self.sdo.has_changed() {
             self.reload();
         }
-        self.hashes[&0u8].as_slice()
+        self.hashes.all()
+    }
+
+    fn get_hashes_for_color(&mut self, color: u8) -> &[ComparableTLSHHash] {
+        if self.sdo.has_changed() {
+            self.reload();
+        }
+        self.hashes.with_color(color)
     }
 }
 
 impl LegacyTLSHDatabase {
     pub fn reload(&mut self) {
         debug!("Reloading TLSH store");
-        self.hashes.clear();
-
-        let object = self.sdo.object().lock().unwrap().clone();
-        let tlsh_obj = ColoredTLSHObject::from_object(object).expect("invalid database object");
-
-        for hash in tlsh_obj.get_entries() {
-            let tlsh_hash = TLSH::from_raw(&hash.tlsh_bytes);
-            self.hashes
-                .entry(tlsh_hash.color)
-                .or_insert_with(Default::default);
-            let colored_hashes = self.hashes.get_mut(&tlsh_hash.color).unwrap();
-            colored_hashes.push(ComparableTLSHHash {
-                inner: tlsh_hash,
-                detection_distance: 0,
-            });
+
+        let objects = self.sdo.objects().lock().unwrap().clone();
+        let mut entries = Vec::new();
+        for object in objects {
+            let tlsh_obj = ColoredTLSHObject::from_object(object).expect("invalid database object");
+            for hash in tlsh_obj.get_entries() {
+                entries.push((
+                    hash.tlsh_bytes.to_vec(),
+                    TLSH::from_raw(&hash.tlsh_bytes),
+                    0,
+                ));
+            }
         }
-        debug!("{} hashes in database", self.hashes[&0].len());
+        self.hashes = ColoredHashes::new(merge_entries(entries));
+        debug!("{} hashes in database", self.hashes.all().len());
     }
 
     pub fn new(sdo: Arc<SystemDatabaseObject>) -> Self {
         let mut db = Self {
             sdo,
-            hashes: HashMap::new(),
+            hashes: ColoredHashes::default(),
         };
         db.reload();
         db
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TLSH of pseudo-random bytes, with the bytes in `changed` flipped
+    fn hash(changed: Range<usize>) -> ComparableTLSHHash {
+        let mut state = 0x2545_f491_u32;
+        let mut data: Vec<u8> = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        for byte in &mut data[changed] {
+            *byte = !*byte;
+        }
+        let mut builder = TLSHBuilder::default();
+        builder.update(&data);
+        builder.finalize();
+        let Ok(tlsh) = builder.get_hashes()[0] else {
+            panic!("no TLSH for the test data");
+        };
+        ComparableTLSHHash::new(tlsh, 40, Some(0))
+    }
+
+    #[test]
+    fn matches_strictly_below_threshold() {
+        let input = hash(0..0);
+        let stored = hash(0..256);
+        let diff = stored.diff(&input);
+        assert!(diff > 0, "the test hashes must differ");
+
+        for header_filter in [false, true] {
+            assert!(below_threshold(&input, &stored, diff + 1, header_filter));
+            assert!(!below_threshold(&input, &stored, diff, header_filter));
+            assert!(!below_threshold(&input, &stored, diff - 1, header_filter));
+        }
+        // identical hashes have no distance, a threshold of 0 never matches
+        assert!(below_threshold(&input, &input, 1, false));
+        assert!(!below_threshold(&input, &input, 0, false));
+    }
+}
@@ -0,0 +1,121 @@
+//! Detection without the daemon, for embedding SIMBIoTA into other programs
+use crate::api::detector::{DetectionResult, Detector};
+use crate::client_config::{ClientConfig, ConfigLoadError};
+use crate::database_watcher;
+use crate::detector::builtin_provider;
+use crate::system_database::{DatabaseLoadError, SystemDatabase};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The loaded database with the configured detector
+///
+/// ```no_run
+/// use simbiota_clientlib::Engine;
+/// use std::path::Path;
+///
+/// let mut engine = Engine::from_config_file(Path::new("/etc/simbiota/client.yaml")).unwrap();
+/// let result = engine.scan_path(Path::new("/tmp/suspicious.bin")).unwrap();
+/// println!("detected: {}", result.is_match());
+/// ```
+pub struct Engine {
+    database: Arc<Mutex<SystemDatabase>>,
+    database_paths: Vec<PathBuf>,
+    detector: Box<dyn Detector>,
+}
+
+#[derive(Debug)]
+pub enum EngineError {
+    Config(ConfigLoadError),
+    Database(DatabaseLoadError),
+    /// No built-in detector has the configured class
+    UnknownDetector(String),
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "{}", e),
+            Self::Database(e) => write!(f, "{}", e),
+            Self::UnknownDetector(class) => write!(f, "unknown detector class: {}", class),
+        }
+    }
+}
+
+impl Error for EngineError {}
+
+impl Engine {
+    /// Load the client config file, then see [`Engine::new`]
+    ///
+    /// A missing or unparsable config file is returned as [`EngineError::Config`].
+    pub fn from_config_file(path: &Path) -> Result<Self, EngineError> {
+        Self::new(&ClientConfig::try_load_from(path, false).map_err(EngineError::Config)?)
+    }
+
+    /// Load the configured database and build the configured detector
+    ///
+    /// The default config uses the `dummy` detector, which never matches:
+    ///
+    /// ```
+    /// use simbiota_clientlib::client_config::ClientConfig;
+    /// use simbiota_clientlib::Engine;
+    ///
+    /// # let database_path = std::env::temp_dir()
+    /// #     .join(format!("simbiota-engine-doctest-{}.sdb", std::process::id()));
+    /// # let database = simbiota_database::Database::new(1);
+    /// # std::fs::write(&database_path, database.to_bytes()).unwrap();
+    /// let mut config = ClientConfig::default();
+    /// config.database.database_path = database_path.clone();
+    ///
+    /// let mut engine = Engine::new(&config).unwrap();
+    /// let result = engine.scan_bytes(b"contents of a file").unwrap();
+    /// assert!(!result.is_match());
+    /// # std::fs::remove_file(&database_path).unwrap();
+    /// ```
+    pub fn new(config: &ClientConfig) -> Result<Self, EngineError> {
+        let class = &config.detector.class;
+        let provider =
+            builtin_provider(class).ok_or_else(|| EngineError::UnknownDetector(class.clone()))?;
+        let database = SystemDatabase::try_load(config).map_err(EngineError::Database)?;
+        let database = Arc::new(Mutex::new(database));
+        let detector = provider.get_detector(&config.detector.config, database.clone());
+        Ok(Self {
+            database,
+            database_paths: config.database.paths().map(Path::to_path_buf).collect(),
+            detector,
+        })
+    }
+
+    /// Scan a file, large files are memory-mapped, see [`Detector::check_file`]
+    pub fn scan_path(&mut self, path: &Path) -> Result<DetectionResult, Box<dyn Error>> {
+        let mut file = File::open(path)?;
+        self.detector.check_file(&mut file)
+    }
+
+    pub fn scan_bytes(&mut self, bytes: &[u8]) -> Result<DetectionResult, Box<dyn Error>> {
+        self.detector.check_bytes(bytes)
+    }
+
+    pub fn scan_reader(
+        &mut self,
+        reader: &mut dyn Read,
+    ) -> Result<DetectionResult, Box<dyn Error>> {
+        self.detector.check_reader(reader)
+    }
+
+    /// The loaded database, shared with the detector
+    pub fn database(&self) -> Arc<Mutex<SystemDatabase>> {
+        self.database.clone()
+    }
+
+    /// Reload the database whenever one of the database files is replaced, e.g. by the updater
+    ///
+    /// Scans started after a reload use the new signatures, `on_reload` is called after every
+    /// reload from the watcher thread.
+    pub fn watch_database(&self, on_reload: impl Fn() + Send + 'static) -> std::io::Result<()> {
+        database_watcher::watch_database(self.database.clone(), &self.database_paths, on_reload)
+    }
+}
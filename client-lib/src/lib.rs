@@ -1,4 +1,10 @@
 pub mod api;
 pub mod client_config;
+pub mod database_watcher;
 pub mod detector;
+pub mod engine;
+pub mod rate_limit;
 pub mod system_database;
+pub mod updater;
+
+pub use engine::Engine;
@@ -0,0 +1,43 @@
+//! Limiting how many files are scanned per second
+
+use std::time::{Duration, Instant};
+
+/// Token bucket allowing bursts of up to `burst` files, refilled at `rate` files per second
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// `rate` must be positive, a `burst` of 0 is treated as 1
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            rate: rate as f64,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token, sleeping until one is available
+    pub fn acquire(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            std::thread::sleep(wait);
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+}
@@ -1,10 +1,11 @@
 use simbiota_database::{Database, LazyLoadedDatabase, Object, ObjectImpl};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
 
 use crate::client_config::ClientConfig;
 use crate::system_database::DatabaseHolder::{LowMemory, LowMemoryUpdate, Normal};
-use log::debug;
+use log::{debug, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
@@ -14,98 +15,171 @@ enum DatabaseHolder {
     LowMemoryUpdate,
 }
 
-pub struct SystemDatabase {
+/// One of the configured database files
+struct DatabaseFile {
+    path: PathBuf,
     holder: DatabaseHolder,
-    database_path: PathBuf,
+}
+
+/// The loaded database files and the objects handed out to detectors
+///
+/// Every object id is read from the files once, further [`SystemDatabase::get_object`] calls
+/// for the same id share the same [`SystemDatabaseObject`], so several detectors can use their
+/// own objects from one loaded database. All of them are refreshed by
+/// [`SystemDatabase::mark_update`].
+///
+/// With several files, a [`SystemDatabaseObject`] holds the object from every file that has
+/// it, in the configured order. Its user decides how they are merged.
+pub struct SystemDatabase {
+    files: Vec<DatabaseFile>,
+    low_memory: bool,
     sdos: HashMap<u64, Arc<SystemDatabaseObject>>,
 }
 
+/// Reasons the database could not be loaded at startup
+#[derive(Debug)]
+pub enum DatabaseLoadError {
+    /// The database file does not exist, usually because no update has run yet
+    Missing(PathBuf),
+    Read(PathBuf, std::io::Error),
+    /// The file is not a valid database
+    Invalid(PathBuf, String),
+}
+
+impl Display for DatabaseLoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing(path) => write!(
+                f,
+                "database not found at {}; run simbiota-update first",
+                path.display()
+            ),
+            Self::Read(path, e) => write!(f, "cannot read database {}: {}", path.display(), e),
+            Self::Invalid(path, e) => write!(
+                f,
+                "database {} is corrupt: {}; run simbiota-update to download it again",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseLoadError {}
+
 impl SystemDatabase {
+    /// Load the database, panics if it is missing or invalid, see [`SystemDatabase::try_load`]
     pub fn load(config: &ClientConfig) -> Self {
-        debug!(
-            "loading database from: {}",
-            config.database.database_path.display()
-        );
-        let dbpath = &config.database.database_path;
-        if !dbpath.exists() {
-            panic!("missing database file");
-        }
+        Self::try_load(config).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        let holder = if config.database.low_memory {
-            let database = LazyLoadedDatabase::new(dbpath).expect("failed to load database");
-            LowMemory(database)
-        } else {
-            let database_raw = std::fs::read(dbpath).expect("failed to read database file");
-            let database =
-                Database::from_bytes(database_raw.as_slice()).expect("failed to load database");
-            Normal(database)
-        };
+    pub fn try_load(config: &ClientConfig) -> Result<Self, DatabaseLoadError> {
+        let low_memory = config.database.low_memory;
+        let mut files = Vec::new();
+        for path in config.database.paths() {
+            debug!("loading database from: {}", path.display());
+            if !path.exists() {
+                return Err(DatabaseLoadError::Missing(path.to_path_buf()));
+            }
+            files.push(DatabaseFile {
+                path: path.to_path_buf(),
+                holder: Self::load_holder(path, low_memory)?,
+            });
+        }
 
-        Self {
-            holder,
-            database_path: dbpath.clone(),
+        Ok(Self {
+            files,
+            low_memory,
             sdos: HashMap::new(),
+        })
+    }
+
+    fn load_holder(path: &Path, low_memory: bool) -> Result<DatabaseHolder, DatabaseLoadError> {
+        if low_memory {
+            let database = LazyLoadedDatabase::new(path)
+                .map_err(|e| DatabaseLoadError::Invalid(path.to_path_buf(), format!("{:?}", e)))?;
+            Ok(LowMemory(database))
+        } else {
+            let database_raw =
+                std::fs::read(path).map_err(|e| DatabaseLoadError::Read(path.to_path_buf(), e))?;
+            let database = Database::from_bytes(database_raw.as_slice())
+                .map_err(|e| DatabaseLoadError::Invalid(path.to_path_buf(), format!("{:?}", e)))?;
+            Ok(Normal(database))
         }
     }
 
     pub fn pre_update(&mut self) {
-        if let LowMemory(_) = &self.holder {
-            let holder = std::mem::replace(&mut self.holder, LowMemoryUpdate);
-            let LowMemory(db) = holder else {
-                panic!("impossible state");
-            };
-            db.close();
+        for file in self.files.iter_mut() {
+            if let LowMemory(_) = &file.holder {
+                let holder = std::mem::replace(&mut file.holder, LowMemoryUpdate);
+                let LowMemory(db) = holder else {
+                    panic!("impossible state");
+                };
+                db.close();
+            }
         }
     }
 
+    /// Reload every database file, then refresh the objects handed out
     pub fn mark_update(&mut self) {
-        if let LowMemoryUpdate = &self.holder {
-            let database =
-                LazyLoadedDatabase::new(&self.database_path).expect("failed to load database");
-            self.holder = LowMemory(database)
-        } else if let Normal(_) = &self.holder {
-            let database_raw =
-                std::fs::read(&self.database_path).expect("failed to read database file");
-            let database =
-                Database::from_bytes(database_raw.as_slice()).expect("failed to load database");
-            self.holder = Normal(database)
-        } else {
-            panic!("pre_update must be called for low_memory database updates")
+        for file in self.files.iter_mut() {
+            if self.low_memory && !matches!(file.holder, LowMemoryUpdate) {
+                panic!("pre_update must be called for low_memory database updates")
+            }
+            file.holder = Self::load_holder(&file.path, self.low_memory)
+                .unwrap_or_else(|e| panic!("failed to load database: {}", e));
         }
-        debug!("reloading objects");
-        for (id, sdo) in self.sdos.iter_mut() {
-            let object = if let LowMemory(database) = &self.holder {
-                database.get_object(*id).expect("invalid database")
-            } else if let Normal(database) = &self.holder {
-                database.get_object(*id).unwrap().clone()
-            } else {
-                panic!("cannot request objects while the database is being updated");
-            };
-
-            let mut obj = sdo.object.lock().unwrap();
-            *obj = object;
+        debug!("reloading {} objects", self.sdos.len());
+        for (id, sdo) in self.sdos.iter() {
+            // every holder is reloaded above, every registered object is refreshed
+            let objects = self.read_objects(*id);
+            if objects.is_empty() {
+                warn!(
+                    "object {:#06x} missing from the updated database, keeping the old one",
+                    id
+                );
+                continue;
+            }
+
+            let mut obj = sdo.objects.lock().unwrap();
+            *obj = objects;
             sdo.changed.store(true, Ordering::SeqCst)
         }
     }
 
+    /// The object with the given id from every file that has it, in the configured order
+    fn read_objects(&self, id: u64) -> Vec<Object> {
+        self.files
+            .iter()
+            .filter_map(|file| Self::read_object(&file.holder, id))
+            .collect()
+    }
+
+    fn read_object(holder: &DatabaseHolder, id: u64) -> Option<Object> {
+        match holder {
+            LowMemory(database) => database.get_object(id).ok(),
+            Normal(database) => database.get_object(id).cloned(),
+            LowMemoryUpdate => {
+                panic!("cannot request objects while the database is being updated")
+            }
+        }
+    }
+
     pub fn get_object<I: ObjectImpl>(&mut self, id: u64) -> Option<Arc<SystemDatabaseObject>> {
         if self.sdos.contains_key(&id) {
             return Some(self.sdos[&id].clone());
         }
-        let object = if let LowMemory(database) = &self.holder {
-            database.get_object(id).ok()
-        } else if let Normal(database) = &self.holder {
-            database.get_object(id).cloned()
-        } else {
-            panic!("cannot request objects while the database is being updated");
-        };
-        let Some(object) = object else {
-            return None
-        };
-        let parsed = I::from_object(object)?;
+        let objects: Vec<Object> = self
+            .read_objects(id)
+            .into_iter()
+            .filter_map(|object| I::from_object(object).map(|parsed| parsed.to_object()))
+            .collect();
+        if objects.is_empty() {
+            return None;
+        }
         let sdo = SystemDatabaseObject {
             changed: AtomicBool::new(false),
-            object: Mutex::new(parsed.to_object()),
+            objects: Mutex::new(objects),
         };
         self.sdos.insert(id, Arc::new(sdo));
         Some(self.sdos[&id].clone())
@@ -113,16 +187,120 @@ impl SystemDatabase {
 }
 
 pub struct SystemDatabaseObject {
-    object: Mutex<Object>,
+    objects: Mutex<Vec<Object>>,
     changed: AtomicBool,
 }
 
 impl SystemDatabaseObject {
-    pub fn object(&self) -> &Mutex<Object> {
+    /// The object from every database file that has it, in the configured order
+    ///
+    /// Objects later in the list win when they conflict with earlier ones.
+    pub fn objects(&self) -> &Mutex<Vec<Object>> {
         self.changed.store(false, Ordering::SeqCst);
-        &self.object
+        &self.objects
     }
     pub fn has_changed(&self) -> bool {
         self.changed.load(Ordering::SeqCst)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts every object, the tests only check which objects are handed out
+    struct RawObject(Object);
+
+    impl ObjectImpl for RawObject {
+        fn from_object(object: Object) -> Option<Self> {
+            Some(Self(object))
+        }
+
+        fn to_object(&self) -> Object {
+            self.0.clone()
+        }
+    }
+
+    fn write_database(path: &Path, objects: &[(u64, &str)]) {
+        let mut database = Database::new(1);
+        for (id, data) in objects {
+            database.add_object(Object::new(*id, data.as_bytes().to_vec()));
+        }
+        std::fs::write(path, database.to_bytes()).unwrap();
+    }
+
+    fn load(path: &Path, low_memory: bool) -> SystemDatabase {
+        let mut config = ClientConfig::default();
+        config.database.database_path = path.to_path_buf();
+        config.database.low_memory = low_memory;
+        SystemDatabase::try_load(&config).unwrap()
+    }
+
+    fn two_objects_refreshed_on_update(low_memory: bool) {
+        let path = std::env::temp_dir().join(format!(
+            "simbiota-database-test-{}-{}.sdb",
+            std::process::id(),
+            low_memory
+        ));
+        write_database(&path, &[(0x10, "first"), (0x20, "second")]);
+        let mut database = load(&path, low_memory);
+
+        let first = database.get_object::<RawObject>(0x10).unwrap();
+        let second = database.get_object::<RawObject>(0x20).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        // later requests for an id share the object
+        assert!(Arc::ptr_eq(
+            &first,
+            &database.get_object::<RawObject>(0x10).unwrap()
+        ));
+        assert!(database.get_object::<RawObject>(0x30).is_none());
+        assert_contents(&first, 0x10, "first");
+        assert_contents(&second, 0x20, "second");
+        assert!(!first.has_changed() && !second.has_changed());
+
+        if low_memory {
+            database.pre_update();
+        }
+        write_database(
+            &path,
+            &[(0x10, "first, updated"), (0x20, "second, updated")],
+        );
+        database.mark_update();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(first.has_changed());
+        assert!(second.has_changed());
+        assert_contents(&first, 0x10, "first, updated");
+        assert_contents(&second, 0x20, "second, updated");
+        assert!(!first.has_changed() && !second.has_changed());
+    }
+
+    /// The object holds exactly the object `id` with `data`, compared through their serialized
+    /// form as objects are only accessible through an [`ObjectImpl`]
+    fn assert_contents(object: &SystemDatabaseObject, id: u64, data: &str) {
+        let single = |object: Object| {
+            let mut database = Database::new(1);
+            database.add_object(object);
+            database.to_bytes()
+        };
+        let objects = object.objects().lock().unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(
+            single(objects[0].clone()),
+            single(Object::new(id, data.as_bytes().to_vec())),
+            "object {:#x} does not hold {:?}",
+            id,
+            data
+        );
+    }
+
+    #[test]
+    fn two_objects_refreshed_on_update_normal() {
+        two_objects_refreshed_on_update(false);
+    }
+
+    #[test]
+    fn two_objects_refreshed_on_update_low_memory() {
+        two_objects_refreshed_on_update(true);
+    }
+}
@@ -0,0 +1,1328 @@
+//! Downloading and installing database releases
+//!
+//! Used by `simbiota --update-database` (run by the `simbiota-update` timer through
+//! `simbiota-update.sh`) and by `simbiotactl update` inside the daemon.
+
+use log::{debug, info, warn};
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, OwnedTrustAnchor, RootCertStore, ServerName};
+use simbiota_database::{Database, Object};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Settings of the updater, shared with `simbiota-update.sh`
+pub const UPDATE_SETTINGS_PATH: &str = "/etc/default/simbiota-update";
+/// Overrides `RELEASES_URL` of the settings file
+pub const ENV_UPDATE_SERVER: &str = "SIMBIOTA_UPDATE_SERVER";
+const DEFAULT_RELEASES_URL: &str =
+    "https://api.github.com/repos/simbiota/database-releases/releases/latest";
+/// Hard limit for a redirect chain, the GitHub API only redirects once to the asset storage
+const MAX_REDIRECTS: usize = 5;
+/// Release information is tiny, anything larger is not a release
+const MAX_RELEASE_SIZE: u64 = 1024 * 1024;
+const MAX_SIGNATURE_SIZE: u64 = 64 * 1024;
+const MAX_DELTA_SIZE: u64 = 256 * 1024 * 1024;
+/// Database files for this architecture are the release assets named `*-arm-*`
+const RELEASE_ARCH: &str = "arm";
+/// Signature of a delta, which has no url of its own for a detached signature
+const SIGNATURE_HEADER: &str = "x-simbiota-signature";
+
+/// Settings read from [`UPDATE_SETTINGS_PATH`], see the comments in that file
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateSettings {
+    /// Release urls tried in order, `RELEASES_URL` followed by `MIRRORS`
+    pub release_urls: Vec<String>,
+    /// Base64 encoded SHA-256 hashes of the accepted server public keys, empty to not pin
+    pub pinned_pubkeys: Vec<String>,
+    /// Base url of the delta updates, full databases are downloaded without it
+    pub delta_url: Option<String>,
+    /// PEM public key verifying the detached signature of the database
+    pub public_key: Option<PathBuf>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub timeout: Duration,
+    /// Log every download attempt at info instead of debug level
+    pub verbose: bool,
+}
+
+impl Default for UpdateSettings {
+    fn default() -> Self {
+        Self {
+            release_urls: vec![DEFAULT_RELEASES_URL.to_string()],
+            delta_url: None,
+            pinned_pubkeys: Vec::new(),
+            public_key: None,
+            retries: 3,
+            retry_delay: Duration::from_millis(1000),
+            timeout: Duration::from_secs(60),
+            verbose: false,
+        }
+    }
+}
+
+impl UpdateSettings {
+    /// Load [`UPDATE_SETTINGS_PATH`], a missing file means the defaults
+    ///
+    /// [`ENV_UPDATE_SERVER`] from the environment wins over `RELEASES_URL`.
+    pub fn load() -> Result<Self, UpdateError> {
+        let content = match std::fs::read_to_string(UPDATE_SETTINGS_PATH) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(UpdateError::Config(format!(
+                    "failed to read {}: {}",
+                    UPDATE_SETTINGS_PATH, e
+                )))
+            }
+        };
+        Self::parse(&content, std::env::var(ENV_UPDATE_SERVER).ok())
+    }
+
+    /// Parse the `KEY=value` lines of the settings file
+    ///
+    /// Only plain assignments are supported, values may be quoted like in a shell script.
+    pub fn parse(content: &str, update_server: Option<String>) -> Result<Self, UpdateError> {
+        let mut settings = Self::default();
+        let mut releases_url = DEFAULT_RELEASES_URL.to_string();
+        let mut mirrors = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(UpdateError::Config(format!(
+                    "line {}: expected KEY=value",
+                    number + 1
+                )));
+            };
+            let value = unquote(value.trim());
+            let invalid = |what: &str| {
+                UpdateError::Config(format!("line {}: invalid {}: {}", number + 1, what, value))
+            };
+            match key.trim() {
+                "RELEASES_URL" => releases_url = value.to_string(),
+                "MIRRORS" => mirrors = value.split_whitespace().map(str::to_string).collect(),
+                "PINNED_PUBKEY" => {
+                    settings.pinned_pubkeys = value
+                        .split(';')
+                        .filter(|pin| !pin.is_empty())
+                        .map(|pin| pin.strip_prefix("sha256//").map(str::to_string))
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| invalid("PINNED_PUBKEY"))?
+                }
+                "DELTA_URL" if value.is_empty() => settings.delta_url = None,
+                "DELTA_URL" => settings.delta_url = Some(value.to_string()),
+                "PUBLIC_KEY" if value.is_empty() => settings.public_key = None,
+                "PUBLIC_KEY" => settings.public_key = Some(PathBuf::from(value)),
+                "RETRIES" => settings.retries = value.parse().map_err(|_| invalid("RETRIES"))?,
+                "RETRY_DELAY_MS" => {
+                    settings.retry_delay =
+                        Duration::from_millis(value.parse().map_err(|_| invalid("RETRY_DELAY_MS"))?)
+                }
+                "TIMEOUT_SECS" => {
+                    settings.timeout =
+                        Duration::from_secs(value.parse().map_err(|_| invalid("TIMEOUT_SECS"))?)
+                }
+                "VERBOSE" => settings.verbose = !value.is_empty(),
+                other => warn!("ignoring unknown update setting {}", other),
+            }
+        }
+        if let Some(update_server) = update_server.filter(|url| !url.is_empty()) {
+            info!("update server overridden by {}", ENV_UPDATE_SERVER);
+            releases_url = update_server;
+        }
+        settings.release_urls = std::iter::once(releases_url).chain(mirrors).collect();
+        // Only ever talk HTTPS, redirects are checked in `Downloader::get`
+        if let Some(url) = settings
+            .release_urls
+            .iter()
+            .chain(&settings.delta_url)
+            .find(|url| !url.starts_with("https://"))
+        {
+            return Err(UpdateError::Config(format!(
+                "refusing to update from a non-HTTPS url: {}",
+                url
+            )));
+        }
+        Ok(settings)
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+/// Versions of the database before and after an update, `None` if unknown
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateOutcome {
+    pub previous_version: Option<String>,
+    pub version: Option<String>,
+}
+
+impl UpdateOutcome {
+    /// Whether a different release was installed, `false` if the versions are unknown
+    pub fn updated(&self) -> bool {
+        self.version.is_some() && self.version != self.previous_version
+    }
+}
+
+/// Why an update failed, the current database is kept in every case
+#[derive(Debug)]
+pub enum UpdateError {
+    /// The update settings are invalid
+    Config(String),
+    /// No update server could be reached, or the connection broke
+    Network(String),
+    /// An update server answered with an error status
+    HttpStatus(u16),
+    /// The new database could not be installed
+    Io(std::io::Error),
+    /// The signature of the downloaded database is not valid
+    BadSignature,
+    /// The release information or the downloaded database is invalid
+    ParseFailed(String),
+    /// An update server did not respond in time
+    Timeout,
+}
+
+impl UpdateError {
+    /// Failures that may go away by trying again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Network(_) | Self::Timeout => true,
+            Self::HttpStatus(code) => *code >= 500,
+            _ => false,
+        }
+    }
+
+    /// Exit code of `simbiota --update-database`, kept from the former shell updater
+    ///
+    /// 2 is used by `--check-update` for an available update.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 1,
+            Self::Network(_) => 3,
+            Self::Timeout => 4,
+            Self::HttpStatus(_) => 5,
+            Self::BadSignature => 6,
+            Self::ParseFailed(_) => 7,
+            Self::Io(_) => 8,
+        }
+    }
+}
+
+impl Display for UpdateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(message) => write!(f, "invalid update settings: {}", message),
+            Self::Network(message) => write!(f, "network error: {}", message),
+            Self::HttpStatus(code) => write!(f, "update server returned HTTP status {}", code),
+            Self::Io(e) => write!(f, "update failed: {}", e),
+            Self::BadSignature => write!(f, "database signature verification failed"),
+            Self::ParseFailed(message) => write!(f, "invalid update: {}", message),
+            Self::Timeout => write!(f, "update server timed out"),
+        }
+    }
+}
+
+impl Error for UpdateError {}
+
+impl From<std::io::Error> for UpdateError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Installed and latest version, the result of [`check_update`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateCheck {
+    pub current_version: Option<String>,
+    pub latest_version: String,
+}
+
+impl UpdateCheck {
+    pub fn update_available(&self) -> bool {
+        self.current_version.as_deref() != Some(self.latest_version.as_str())
+    }
+}
+
+/// A release picked from the release information
+struct Release {
+    version: Option<String>,
+    url: String,
+    size: u64,
+}
+
+impl Release {
+    fn parse(raw: &[u8]) -> Result<Self, UpdateError> {
+        let json: serde_json::Value = serde_json::from_slice(raw)
+            .map_err(|e| UpdateError::ParseFailed(format!("invalid release information: {}", e)))?;
+        let version = json["tag_name"]
+            .as_str()
+            .filter(|version| !version.is_empty())
+            .map(str::to_string);
+        let asset = json["assets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|asset| {
+                asset["name"]
+                    .as_str()
+                    .is_some_and(|name| name.contains(&format!("-{}-", RELEASE_ARCH)))
+            })
+            .ok_or_else(|| {
+                UpdateError::ParseFailed("no database found in the latest release".to_string())
+            })?;
+        let url = asset["browser_download_url"]
+            .as_str()
+            .ok_or_else(|| UpdateError::ParseFailed("database asset has no url".to_string()))?;
+        let size = asset["size"]
+            .as_u64()
+            .ok_or_else(|| UpdateError::ParseFailed("database asset has no size".to_string()))?;
+        Ok(Self {
+            version,
+            url: url.to_string(),
+            size,
+        })
+    }
+}
+
+/// Check whether a newer release than the installed one is available
+pub fn check_update(
+    database_path: &Path,
+    settings: &UpdateSettings,
+) -> Result<UpdateCheck, UpdateError> {
+    let downloader = Downloader::new(settings)?;
+    let current_version = installed_version(database_path);
+    let mut last_error = None;
+    for release_url in &settings.release_urls {
+        let latest = downloader
+            .fetch(release_url, MAX_RELEASE_SIZE)
+            .and_then(|raw| Release::parse(&raw));
+        match latest {
+            Ok(Release {
+                version: Some(latest_version),
+                ..
+            }) => {
+                return Ok(UpdateCheck {
+                    current_version,
+                    latest_version,
+                })
+            }
+            Ok(_) => {
+                return Err(UpdateError::ParseFailed(format!(
+                    "the latest release at {} has no version",
+                    release_url
+                )))
+            }
+            Err(e) => {
+                warn!("checking {} failed: {}", release_url, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| UpdateError::Config("no release url".to_string())))
+}
+
+/// Download the latest release and install it as the database at `database_path`
+///
+/// With `DELTA_URL` set, only the changes since the installed version are downloaded and
+/// applied to the installed database. Otherwise, or if the delta cannot be used, the release
+/// urls are tried in order until one provides a database with a valid signature that can be
+/// loaded. The database is replaced with an atomic rename, so a running daemon reloads it
+/// through its database watcher.
+pub fn update_database(
+    database_path: &Path,
+    settings: &UpdateSettings,
+) -> Result<UpdateOutcome, UpdateError> {
+    update_with(&Downloader::new(settings)?, database_path)
+}
+
+fn update_with(
+    downloader: &Downloader,
+    database_path: &Path,
+) -> Result<UpdateOutcome, UpdateError> {
+    info!("running database update");
+    let settings = downloader.settings;
+    let public_key = match &settings.public_key {
+        Some(path) => Some(PublicKey::load(path)?),
+        None => {
+            warn!("PUBLIC_KEY is not set, database signature is not verified");
+            None
+        }
+    };
+    let previous_version = installed_version(database_path);
+    // a delta needs a known base, a missing or broken database is downloaded in full
+    if let (Some(delta_url), Some(version)) = (&settings.delta_url, &previous_version) {
+        match download_delta(
+            downloader,
+            delta_url,
+            version,
+            database_path,
+            public_key.as_ref(),
+        ) {
+            Ok(downloaded) => {
+                return finish_update(database_path, previous_version, downloaded);
+            }
+            // no delta support on the server, or the installed version is too old
+            Err(UpdateError::HttpStatus(404 | 410 | 501)) => {
+                info!(
+                    "no delta available since {}, downloading the full database",
+                    version
+                )
+            }
+            Err(e) => warn!("delta update failed: {}, downloading the full database", e),
+        }
+    }
+    let mut last_error = None;
+    for release_url in &settings.release_urls {
+        match download_release(
+            downloader,
+            release_url,
+            public_key.as_ref(),
+            &previous_version,
+        ) {
+            Ok(downloaded) => {
+                if downloaded.is_some() {
+                    info!("database downloaded from {}", release_url);
+                }
+                return finish_update(database_path, previous_version, downloaded);
+            }
+            Err(e) => {
+                warn!("update from {} failed: {}", release_url, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    warn!("no mirror could provide a valid database, keeping the current database");
+    Err(last_error.unwrap_or_else(|| UpdateError::Config("no release url".to_string())))
+}
+
+/// Install the downloaded version and database, `None` if the installed one is the latest
+fn finish_update(
+    database_path: &Path,
+    previous_version: Option<String>,
+    downloaded: Option<(Option<String>, Vec<u8>)>,
+) -> Result<UpdateOutcome, UpdateError> {
+    let Some((version, database)) = downloaded else {
+        info!(
+            "database is up to date ({})",
+            previous_version.as_deref().unwrap_or("unknown")
+        );
+        return Ok(UpdateOutcome {
+            version: previous_version.clone(),
+            previous_version,
+        });
+    };
+    install(database_path, &database, version.as_deref())?;
+    let outcome = UpdateOutcome {
+        previous_version,
+        version,
+    };
+    info!(
+        "database update finished, version: {}",
+        outcome.version.as_deref().unwrap_or("unknown")
+    );
+    Ok(outcome)
+}
+
+/// The version and the verified database of the latest release, `None` if it is installed
+fn download_release(
+    downloader: &Downloader,
+    release_url: &str,
+    public_key: Option<&PublicKey>,
+    current_version: &Option<String>,
+) -> Result<Option<(Option<String>, Vec<u8>)>, UpdateError> {
+    let release = Release::parse(&downloader.fetch(release_url, MAX_RELEASE_SIZE)?)?;
+    if release.version.is_some() && release.version == *current_version {
+        return Ok(None);
+    }
+    info!(
+        "downloading database {} ({} bytes)",
+        release.version.as_deref().unwrap_or("unknown"),
+        release.size
+    );
+    let database = downloader.fetch(&release.url, release.size)?;
+    // Never replace the database with a truncated download
+    if database.len() as u64 != release.size {
+        return Err(UpdateError::Network(format!(
+            "downloaded {} bytes instead of {}, the download is truncated",
+            database.len(),
+            release.size
+        )));
+    }
+    if let Some(public_key) = public_key {
+        let signature_url = format!("{}.sig", release.url);
+        let signature = downloader.fetch(&signature_url, MAX_SIGNATURE_SIZE)?;
+        public_key.verify(&database, &signature)?;
+        info!("database signature verified");
+    }
+    check_loadable(&database)?;
+    Ok(Some((release.version, database)))
+}
+
+/// Changes between two releases, served by `DELTA_URL/update/{arch}?since={version}`
+///
+/// The body is `{"base": "<since>", "version": "<latest>", "objects": [{"id": 3, "data":
+/// "<base64>"}]}`, every object replaces the object with the same id in the installed
+/// database. With `PUBLIC_KEY` set, the `X-Simbiota-Signature` header holds the base64
+/// encoded signature of the body.
+struct Delta {
+    version: String,
+    objects: Vec<(u64, Vec<u8>)>,
+}
+
+impl Delta {
+    fn parse(raw: &[u8], base: &str) -> Result<Self, UpdateError> {
+        let invalid =
+            |message: &str| UpdateError::ParseFailed(format!("invalid delta: {}", message));
+        let json: serde_json::Value =
+            serde_json::from_slice(raw).map_err(|e| invalid(&e.to_string()))?;
+        if json["base"].as_str() != Some(base) {
+            return Err(invalid("not based on the installed version"));
+        }
+        let version = json["version"]
+            .as_str()
+            .filter(|version| !version.is_empty())
+            .ok_or_else(|| invalid("no version"))?;
+        let objects = json["objects"]
+            .as_array()
+            .ok_or_else(|| invalid("no objects"))?
+            .iter()
+            .map(|object| {
+                let id = object["id"].as_u64()?;
+                let data = base64_decode(object["data"].as_str()?)?;
+                Some((id, data))
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid("objects need an id and base64 data"))?;
+        Ok(Self {
+            version: version.to_string(),
+            objects,
+        })
+    }
+
+    /// The installed database with the objects of the delta
+    fn apply(&self, database: &[u8]) -> Result<Vec<u8>, UpdateError> {
+        let mut database = Database::from_bytes(database).map_err(|e| {
+            UpdateError::ParseFailed(format!("installed database cannot be loaded: {:?}", e))
+        })?;
+        for (id, data) in &self.objects {
+            database.add_object(Object::new(*id, data.clone()));
+        }
+        Ok(database.to_bytes())
+    }
+}
+
+/// The version and the database with the latest delta applied, `None` if it is installed
+fn download_delta(
+    downloader: &Downloader,
+    delta_url: &str,
+    current_version: &str,
+    database_path: &Path,
+    public_key: Option<&PublicKey>,
+) -> Result<Option<(Option<String>, Vec<u8>)>, UpdateError> {
+    let url = format!(
+        "{}/update/{}?since={}",
+        delta_url.trim_end_matches('/'),
+        RELEASE_ARCH,
+        query_escape(current_version)
+    );
+    let (raw, signature) = downloader.fetch_with_signature(&url, MAX_DELTA_SIZE)?;
+    if let Some(public_key) = public_key {
+        let signature = signature
+            .as_deref()
+            .and_then(base64_decode)
+            .ok_or(UpdateError::BadSignature)?;
+        public_key.verify(&raw, &signature)?;
+        info!("delta signature verified");
+    }
+    let delta = Delta::parse(&raw, current_version)?;
+    if delta.version == current_version {
+        return Ok(None);
+    }
+    info!(
+        "applying delta {} -> {} ({} objects)",
+        current_version,
+        delta.version,
+        delta.objects.len()
+    );
+    let database = delta.apply(&std::fs::read(database_path)?)?;
+    check_loadable(&database)?;
+    Ok(Some((Some(delta.version), database)))
+}
+
+/// A database the daemon cannot load must never replace the current one
+fn check_loadable(database: &[u8]) -> Result<(), UpdateError> {
+    Database::from_bytes(database)
+        .map(|_| ())
+        .map_err(|e| UpdateError::ParseFailed(format!("database cannot be loaded: {:?}", e)))
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The recorded version only counts while the database it belongs to is present and
+/// loadable, a deleted or corrupted database is downloaded again.
+fn installed_version(database_path: &Path) -> Option<String> {
+    let version = read_version(database_path)?;
+    let database = std::fs::read(database_path).ok()?;
+    if database.is_empty() || Database::from_bytes(&database).is_err() {
+        return None;
+    }
+    Some(version)
+}
+
+/// Install the database and its version next to it
+///
+/// Both are staged next to their final path, so the rename is atomic and the daemon never
+/// sees a partially written database. The version is only recorded once the database is in
+/// place.
+fn install(database_path: &Path, database: &[u8], version: Option<&str>) -> std::io::Result<()> {
+    replace_file(database_path, database)?;
+    if let Some(version) = version {
+        replace_file(
+            &version_path(database_path),
+            format!("{}\n", version).as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+fn replace_file(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut staging = path.to_path_buf().into_os_string();
+    staging.push(".tmp");
+    let staging = PathBuf::from(staging);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(&staging)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&staging, path).map_err(|e| {
+        let _ = std::fs::remove_file(&staging);
+        e
+    })
+}
+
+/// HTTPS client with retries and optional public key pinning
+struct Downloader<'a> {
+    agent: ureq::Agent,
+    settings: &'a UpdateSettings,
+}
+
+impl<'a> Downloader<'a> {
+    fn new(settings: &'a UpdateSettings) -> Result<Self, UpdateError> {
+        let tls_config = (!settings.pinned_pubkeys.is_empty()).then(|| {
+            let verifier = PinnedVerifier::new(settings.pinned_pubkeys.clone());
+            let tls_config = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth();
+            Arc::new(tls_config)
+        });
+        Ok(Self::with_tls_config(settings, tls_config))
+    }
+
+    /// `None` uses the default TLS config of ureq, with the webpki roots
+    fn with_tls_config(
+        settings: &'a UpdateSettings,
+        tls_config: Option<Arc<rustls::ClientConfig>>,
+    ) -> Self {
+        let mut builder = ureq::AgentBuilder::new()
+            .timeout_connect(settings.timeout)
+            .timeout_read(settings.timeout)
+            .timeout_write(settings.timeout)
+            .https_only(true)
+            // followed by hand, to refuse redirects to plain HTTP
+            .redirects(0)
+            .user_agent(concat!("simbiota/", env!("CARGO_PKG_VERSION")));
+        if let Some(tls_config) = tls_config {
+            builder = builder.tls_config(tls_config);
+        }
+        Self {
+            agent: builder.build(),
+            settings,
+        }
+    }
+
+    /// Download at most `limit` bytes from `url`
+    ///
+    /// Retries connection errors, timeouts and 5xx responses with exponential backoff.
+    /// Other failures (4xx, pinning mismatch, ...) are not retried.
+    fn fetch(&self, url: &str, limit: u64) -> Result<Vec<u8>, UpdateError> {
+        self.fetch_with_signature(url, limit).map(|(body, _)| body)
+    }
+
+    /// [`Downloader::fetch`] that also returns the `X-Simbiota-Signature` header
+    fn fetch_with_signature(
+        &self,
+        url: &str,
+        limit: u64,
+    ) -> Result<(Vec<u8>, Option<String>), UpdateError> {
+        let mut attempt = 1;
+        let mut delay = self.settings.retry_delay;
+        loop {
+            if self.settings.verbose {
+                info!("downloading {} (attempt {})", url, attempt);
+            } else {
+                debug!("downloading {} (attempt {})", url, attempt);
+            }
+            let error = match self.get(url).and_then(|response| {
+                let signature = response.header(SIGNATURE_HEADER).map(str::to_string);
+                read_body(response, limit).map(|body| (body, signature))
+            }) {
+                Ok(downloaded) => return Ok(downloaded),
+                Err(e) => e,
+            };
+            if !error.is_transient() || attempt > self.settings.retries {
+                warn!(
+                    "downloading {} failed after {} attempt(s): {}",
+                    url, attempt, error
+                );
+                return Err(error);
+            }
+            debug!(
+                "attempt {} failed: {}, retrying in {}ms",
+                attempt,
+                error,
+                delay.as_millis()
+            );
+            std::thread::sleep(delay);
+            attempt += 1;
+            delay *= 2;
+        }
+    }
+
+    /// Request `url`, following HTTPS redirects
+    fn get(&self, url: &str) -> Result<ureq::Response, UpdateError> {
+        let mut url = url.to_string();
+        for _ in 0..=MAX_REDIRECTS {
+            if !url.starts_with("https://") {
+                return Err(UpdateError::Network(format!(
+                    "refusing to follow a redirect to a non-HTTPS url: {}",
+                    url
+                )));
+            }
+            let response = match self.agent.get(&url).call() {
+                Ok(response) => response,
+                Err(ureq::Error::Status(code, response)) if (300..400).contains(&code) => response,
+                Err(ureq::Error::Status(code, _)) => return Err(UpdateError::HttpStatus(code)),
+                Err(ureq::Error::Transport(transport)) => return Err(transport_error(transport)),
+            };
+            if !(300..400).contains(&response.status()) {
+                return Ok(response);
+            }
+            let location = response.header("location").ok_or_else(|| {
+                UpdateError::Network(format!("redirect from {} without a location", url))
+            })?;
+            url = redirect_target(&url, location);
+        }
+        Err(UpdateError::Network(format!(
+            "too many redirects for {}",
+            url
+        )))
+    }
+}
+
+/// The absolute url of a `Location` header, relative to `url` if it is an absolute path
+fn redirect_target(url: &str, location: &str) -> String {
+    if !location.starts_with('/') || location.starts_with("//") {
+        return location.to_string();
+    }
+    let authority_end = url
+        .find("://")
+        .and_then(|scheme_end| {
+            let authority = scheme_end + 3;
+            url[authority..].find('/').map(|path| authority + path)
+        })
+        .unwrap_or(url.len());
+    format!("{}{}", &url[..authority_end], location)
+}
+
+fn read_body(response: ureq::Response, limit: u64) -> Result<Vec<u8>, UpdateError> {
+    // the release size is the limit of database downloads
+    let expected = response
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(limit);
+    let mut body = Vec::new();
+    ProgressReader::new(response.into_reader(), expected)
+        .take(limit + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| match e.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => UpdateError::Timeout,
+            _ => UpdateError::Network(e.to_string()),
+        })?;
+    if body.len() as u64 > limit {
+        return Err(UpdateError::ParseFailed(format!(
+            "response is larger than {} bytes",
+            limit
+        )));
+    }
+    Ok(body)
+}
+
+/// Logs the bytes received by a download against its expected size, every 10%
+struct ProgressReader<R> {
+    inner: R,
+    received: u64,
+    expected: u64,
+    reported_percent: u64,
+}
+
+impl<R: Read> ProgressReader<R> {
+    fn new(inner: R, expected: u64) -> Self {
+        Self {
+            inner,
+            received: 0,
+            expected,
+            reported_percent: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.received += read as u64;
+        let percent = (self.received.saturating_mul(100) / self.expected.max(1)).min(100);
+        if percent / 10 > self.reported_percent / 10 {
+            self.reported_percent = percent;
+            info!(
+                "received {} of {} bytes ({}%)",
+                self.received, self.expected, percent
+            );
+        }
+        Ok(read)
+    }
+}
+
+fn transport_error(transport: ureq::Transport) -> UpdateError {
+    let timed_out = transport
+        .source()
+        .and_then(|source| source.downcast_ref::<std::io::Error>())
+        .is_some_and(|e| matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock));
+    if timed_out {
+        UpdateError::Timeout
+    } else {
+        UpdateError::Network(transport.to_string())
+    }
+}
+
+/// Certificate verification with the webpki roots that also requires the public key of the
+/// server certificate to match one of the pins, like `--pinnedpubkey` of curl
+struct PinnedVerifier {
+    roots: WebPkiVerifier,
+    pins: Vec<String>,
+}
+
+impl PinnedVerifier {
+    fn new(pins: Vec<String>) -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        Self {
+            roots: WebPkiVerifier::new(roots, None),
+            pins,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.roots.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let spki = certificate_spki(&end_entity.0).ok_or_else(|| {
+            rustls::Error::General("cannot read the server public key".to_string())
+        })?;
+        let hash = base64_encode(ring::digest::digest(&ring::digest::SHA256, spki).as_ref());
+        if !self.pins.contains(&hash) {
+            return Err(rustls::Error::General(format!(
+                "server public key sha256//{} is not pinned",
+                hash
+            )));
+        }
+        Ok(verified)
+    }
+}
+
+/// Public key verifying the detached signature of the database, made with
+/// `openssl dgst -sha256 -sign`
+struct PublicKey {
+    algorithm: &'static dyn VerificationAlgorithm,
+    key: Vec<u8>,
+}
+
+impl PublicKey {
+    const RSA_ENCRYPTION: &'static [u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+    const EC_PUBLIC_KEY: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+    const PRIME256V1: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+    const SECP384R1: &'static [u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+    fn load(path: &Path) -> Result<Self, UpdateError> {
+        let pem = std::fs::read_to_string(path).map_err(|e| {
+            UpdateError::Config(format!("failed to read {}: {}", path.display(), e))
+        })?;
+        Self::from_pem(&pem).ok_or_else(|| {
+            UpdateError::Config(format!(
+                "{} is not a PEM RSA, P-256 or P-384 public key",
+                path.display()
+            ))
+        })
+    }
+
+    fn from_pem(pem: &str) -> Option<Self> {
+        let base64: String = pem
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let der = base64_decode(&base64)?;
+        // SubjectPublicKeyInfo ::= SEQUENCE { algorithm AlgorithmIdentifier, subjectPublicKey BIT STRING }
+        let (spki, _) = der_element(&der, DER_SEQUENCE)?;
+        let (algorithm, rest) = der_element(spki, DER_SEQUENCE)?;
+        let (key, _) = der_element(rest, DER_BIT_STRING)?;
+        // no unused bits
+        let key = key.strip_prefix(&[0])?;
+        let (oid, parameters) = der_element(algorithm, DER_OID)?;
+        let algorithm: &'static dyn VerificationAlgorithm = if oid == Self::RSA_ENCRYPTION {
+            &signature::RSA_PKCS1_2048_8192_SHA256
+        } else if oid == Self::EC_PUBLIC_KEY {
+            match der_element(parameters, DER_OID)?.0 {
+                curve if curve == Self::PRIME256V1 => &signature::ECDSA_P256_SHA256_ASN1,
+                curve if curve == Self::SECP384R1 => &signature::ECDSA_P384_SHA256_ASN1,
+                _ => return None,
+            }
+        } else {
+            return None;
+        };
+        Some(Self {
+            algorithm,
+            key: key.to_vec(),
+        })
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), UpdateError> {
+        UnparsedPublicKey::new(self.algorithm, &self.key)
+            .verify(message, signature)
+            .map_err(|_| UpdateError::BadSignature)
+    }
+}
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_BIT_STRING: u8 = 0x03;
+const DER_OID: u8 = 0x06;
+const DER_CONTEXT_0: u8 = 0xa0;
+
+/// The content of the DER element with `tag` at the start of `input` and the bytes after it
+fn der_element(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&actual, rest) = input.split_first()?;
+    if actual != tag {
+        return None;
+    }
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > std::mem::size_of::<usize>() || rest.len() < count {
+            return None;
+        }
+        let (bytes, rest) = rest.split_at(count);
+        let length = bytes
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, rest)
+    };
+    if rest.len() < length {
+        return None;
+    }
+    Some(rest.split_at(length))
+}
+
+/// Skip the DER element at the start of `input`, whatever its tag
+fn der_skip(input: &[u8]) -> Option<&[u8]> {
+    let tag = *input.first()?;
+    der_element(input, tag).map(|(_, rest)| rest)
+}
+
+/// The DER encoded SubjectPublicKeyInfo of an X.509 certificate
+fn certificate_spki(certificate: &[u8]) -> Option<&[u8]> {
+    let (certificate, _) = der_element(certificate, DER_SEQUENCE)?;
+    let (tbs, _) = der_element(certificate, DER_SEQUENCE)?;
+    // version is optional
+    let rest = match der_element(tbs, DER_CONTEXT_0) {
+        Some((_, rest)) => rest,
+        None => tbs,
+    };
+    // serialNumber, signature, issuer, validity, subject
+    let mut rest = rest;
+    for _ in 0..5 {
+        rest = der_skip(rest)?;
+    }
+    let after = der_skip(rest)?;
+    Some(&rest[..rest.len() - after.len()])
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.decode(input).ok()
+}
+
+/// The release tag recorded next to the database
+pub fn read_version(database_path: &Path) -> Option<String> {
+    std::fs::read_to_string(version_path(database_path))
+        .ok()
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty())
+}
+
+fn version_path(database_path: &Path) -> PathBuf {
+    let mut path = database_path.to_path_buf().into_os_string();
+    path.push(".version");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_settings_file() {
+        let content = r#"
+# comment
+RELEASES_URL=https://updates.example.com/latest
+MIRRORS="https://mirror1.example.com/latest https://mirror2.example.com/latest"
+PINNED_PUBKEY='sha256//AAAA;sha256//BBBB'
+PUBLIC_KEY=/etc/simbiota/update.pub
+RETRIES=5
+VERBOSE=1
+"#;
+        let settings = UpdateSettings::parse(content, None).unwrap();
+        assert_eq!(
+            settings.release_urls,
+            vec![
+                "https://updates.example.com/latest",
+                "https://mirror1.example.com/latest",
+                "https://mirror2.example.com/latest"
+            ]
+        );
+        assert_eq!(settings.pinned_pubkeys, vec!["AAAA", "BBBB"]);
+        assert_eq!(
+            settings.public_key,
+            Some(PathBuf::from("/etc/simbiota/update.pub"))
+        );
+        assert_eq!(settings.retries, 5);
+        assert_eq!(settings.timeout, Duration::from_secs(60));
+        assert!(settings.verbose);
+
+        let overridden =
+            UpdateSettings::parse(content, Some("https://env.example.com".to_string())).unwrap();
+        assert_eq!(overridden.release_urls[0], "https://env.example.com");
+
+        assert!(matches!(
+            UpdateSettings::parse("MIRRORS=http://mirror.example.com", None),
+            Err(UpdateError::Config(_))
+        ));
+        assert!(matches!(
+            UpdateSettings::parse("PINNED_PUBKEY=AAAA", None),
+            Err(UpdateError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn verifies_database_signature() {
+        let pem = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEJRRO4y0J2rcBVrAVZ3I303y2lhMR
+jUSnvOe0OYtPmi1GHy29rLSfBY4429rZyvJPNY5rqRKJ90q6SqNAJfswpw==
+-----END PUBLIC KEY-----
+";
+        // openssl dgst -sha256 -sign key.pem over "simbiota database"
+        let signature = base64_decode(
+            "MEQCIEc28p0r9gsQ9SSh8rEdC6+TbB+ijlOP2EiU0ZJOorY8AiA7tNVNTwVrc6ErOyvlRl2AUL+yzU3HvQTfMcLaXo5HZw==",
+        )
+        .unwrap();
+        let key = PublicKey::from_pem(pem).unwrap();
+        assert!(key.verify(b"simbiota database", &signature).is_ok());
+        assert!(matches!(
+            key.verify(b"simbiota database!", &signature),
+            Err(UpdateError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn pins_the_certificate_public_key() {
+        // self-signed certificate of the key above
+        let certificate = base64_decode(
+            "MIIBhTCCASugAwIBAgIUPVLh9DluoP5NMOK3sYuZ2Fw9VZMwCgYIKoZIzj0EAwIwGDEWMBQGA1UEAwwNc2ltYmlvdGEudGVzdDAeFw0yNjEwMTYwMjM0MjFaFw0yNjEwMTcwMjM0MjFaMBgxFjAUBgNVBAMMDXNpbWJpb3RhLnRlc3QwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQlFE7jLQnatwFWsBVncjfTfLaWExGNRKe857Q5i0+aLUYfLb2stJ8Fjjjb2tnK8k81jmupEon3SrpKo0Al+zCno1MwUTAdBgNVHQ4EFgQU5Xtbw3gh/Pkv1L2tuo+dgUn9LoowHwYDVR0jBBgwFoAU5Xtbw3gh/Pkv1L2tuo+dgUn9LoowDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiBA9zRV2ASkADorEGw7oln52AoeJRSiHz1aNCKLd+gMVwIhAN4V3Jd53aaRLzKOeeU3wN1MOXn+U+z6LrJxtDfCXAoN",
+        )
+        .unwrap();
+        let spki = certificate_spki(&certificate).unwrap();
+        // openssl x509 -pubkey | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary
+        assert_eq!(
+            base64_encode(ring::digest::digest(&ring::digest::SHA256, spki).as_ref()),
+            "FVmSriU8JlayPpbvAHAN+mOZi0oXKk92KLeZ0b3D4/Y="
+        );
+    }
+
+    #[test]
+    fn reports_download_progress() {
+        let data = vec![7u8; 1000];
+        let mut reader = ProgressReader::new(&data[..], data.len() as u64);
+        let mut chunk = [0u8; 250];
+        reader.read_exact(&mut chunk).unwrap();
+        assert_eq!(reader.reported_percent, 25);
+        let mut body = chunk.to_vec();
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(body, data);
+        assert_eq!(reader.received, 1000);
+        assert_eq!(reader.reported_percent, 100);
+    }
+
+    #[test]
+    fn resolves_redirect_targets() {
+        assert_eq!(
+            redirect_target("https://api.example.com/a/b", "/c"),
+            "https://api.example.com/c"
+        );
+        assert_eq!(
+            redirect_target("https://api.example.com/a", "https://cdn.example.com/d"),
+            "https://cdn.example.com/d"
+        );
+        assert_eq!(
+            redirect_target("https://api.example.com/a", "http://cdn.example.com/d"),
+            "http://cdn.example.com/d"
+        );
+    }
+
+    /// Self-signed certificate for localhost and its PKCS#8 key, for the mock update server
+    const MOCK_CERTIFICATE: &str = "MIIBtTCCAVygAwIBAgIUTXCAkh5ILijw68QBynDhPIEBE9AwCgYIKoZIzj0EAwIwFDESMBAGA1UEAwwJbG9jYWxob3N0MCAXDTI2MTAxNjAyMzcwMFoYDzIxMjYwOTIyMDIzNzAwWjAUMRIwEAYDVQQDDAlsb2NhbGhvc3QwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQ+Zef0DHVZE1m6Mq70MSf+9LtyKocXWNO3u5arUhZCmRh2uNzQLHMBBMfpaz3XdGDahZKY3bvuLakbbxW7EXOKo4GJMIGGMB0GA1UdDgQWBBR+MPW9r48p4lRQphn8zwYCPpOcOTAfBgNVHSMEGDAWgBR+MPW9r48p4lRQphn8zwYCPpOcOTAUBgNVHREEDTALgglsb2NhbGhvc3QwDAYDVR0TAQH/BAIwADALBgNVHQ8EBAMCB4AwEwYDVR0lBAwwCgYIKwYBBQUHAwEwCgYIKoZIzj0EAwIDRwAwRAIgFpCKSqL0LYAQ4u3DCq00FX7MTYuR21ZV4uMZNZoIjUsCICo1dkDpFsygMy2xL06bD5lMcweKX7iagZFxKWv7VoaN";
+    const MOCK_KEY: &str = "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQg5jJxK7twFeeuIkeN5wPS84qhr/cdSX4kjDxHZDkMRauhRANCAAQ+Zef0DHVZE1m6Mq70MSf+9LtyKocXWNO3u5arUhZCmRh2uNzQLHMBBMfpaz3XdGDahZKY3bvuLakbbxW7EXOK";
+
+    /// Serve `respond(base url, path)` over HTTPS until the test ends, returns the base url
+    fn mock_server(respond: impl Fn(&str, &str) -> (u16, Vec<u8>) + Send + 'static) -> String {
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(base64_decode(MOCK_CERTIFICATE).unwrap())],
+                rustls::PrivateKey(base64_decode(MOCK_KEY).unwrap()),
+            )
+            .unwrap();
+        let server_config = Arc::new(server_config);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "https://localhost:{}",
+            listener.local_addr().unwrap().port()
+        );
+        let base_url = url.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a failed request fails the update under test
+                let _ = serve_mock(stream, server_config.clone(), &base_url, &respond);
+            }
+        });
+        url
+    }
+
+    fn serve_mock(
+        stream: std::net::TcpStream,
+        server_config: Arc<rustls::ServerConfig>,
+        base_url: &str,
+        respond: &impl Fn(&str, &str) -> (u16, Vec<u8>),
+    ) -> std::io::Result<()> {
+        use std::io::BufRead;
+        let connection = rustls::ServerConnection::new(server_config)
+            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
+        let mut stream = rustls::StreamOwned::new(connection, stream);
+        let mut request_line = String::new();
+        {
+            let mut request = std::io::BufReader::new(&mut stream);
+            request.read_line(&mut request_line)?;
+            let mut header = String::new();
+            while request.read_line(&mut header)? > 2 {
+                header.clear();
+            }
+        }
+        let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let (status, body) = respond(base_url, path);
+        write!(
+            stream,
+            "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        stream.conn.send_close_notify();
+        stream.flush()
+    }
+
+    /// A downloader trusting the certificate of the mock server
+    fn mock_downloader(settings: &UpdateSettings) -> Downloader<'_> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(base64_decode(MOCK_CERTIFICATE).unwrap()))
+            .unwrap();
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Downloader::with_tls_config(settings, Some(Arc::new(tls_config)))
+    }
+
+    fn mock_settings(url: &str) -> UpdateSettings {
+        UpdateSettings {
+            release_urls: vec![format!("{}/releases/latest", url)],
+            delta_url: Some(url.to_string()),
+            retries: 0,
+            timeout: Duration::from_secs(5),
+            ..UpdateSettings::default()
+        }
+    }
+
+    fn database_bytes(objects: &[(u64, &str)]) -> Vec<u8> {
+        let mut database = Database::new(1);
+        for (id, data) in objects {
+            database.add_object(Object::new(*id, data.as_bytes().to_vec()));
+        }
+        database.to_bytes()
+    }
+
+    /// The object with `id` as the only object of a database, to compare objects
+    fn object_bytes(database: &Database, id: u64) -> Vec<u8> {
+        let mut single = Database::new(1);
+        single.add_object(database.get_object(id).unwrap().clone());
+        single.to_bytes()
+    }
+
+    fn installed_database(name: &str, version: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "simbiota-update-test-{}-{}.sdb",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, database_bytes(&[(0x10, "old"), (0x20, "kept")])).unwrap();
+        std::fs::write(version_path(&path), format!("{}\n", version)).unwrap();
+        path
+    }
+
+    #[test]
+    fn applies_delta_from_server() {
+        let path = installed_database("delta", "v1");
+        let delta = serde_json::json!({
+            "base": "v1",
+            "version": "v2",
+            "objects": [{"id": 0x10, "data": base64_encode(b"new")}],
+        })
+        .to_string();
+        let url = mock_server(move |_, path| match path {
+            "/update/arm?since=v1" => (200, delta.as_bytes().to_vec()),
+            // the full database must not be downloaded
+            _ => (500, Vec::new()),
+        });
+        let settings = mock_settings(&url);
+
+        let outcome = update_with(&mock_downloader(&settings), &path).unwrap();
+        assert_eq!(outcome.previous_version.as_deref(), Some("v1"));
+        assert_eq!(outcome.version.as_deref(), Some("v2"));
+        assert_eq!(read_version(&path).as_deref(), Some("v2"));
+        let database = Database::from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            object_bytes(&database, 0x10),
+            database_bytes(&[(0x10, "new")])
+        );
+        assert_eq!(
+            object_bytes(&database, 0x20),
+            database_bytes(&[(0x20, "kept")])
+        );
+
+        std::fs::remove_file(version_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_full_download_without_delta() {
+        let path = installed_database("full", "v1");
+        let full = database_bytes(&[(0x10, "full")]);
+        let served = full.clone();
+        let url = mock_server(move |base_url, path| match path {
+            // the installed version is too old for a delta
+            "/update/arm?since=v1" => (410, Vec::new()),
+            "/releases/latest" => {
+                let release = serde_json::json!({
+                    "tag_name": "v2",
+                    "assets": [{
+                        "name": "simbiota-arm-v2.sdb",
+                        "browser_download_url": format!("{}/simbiota-arm-v2.sdb", base_url),
+                        "size": served.len(),
+                    }],
+                });
+                (200, release.to_string().into_bytes())
+            }
+            "/simbiota-arm-v2.sdb" => (200, served.clone()),
+            _ => (404, Vec::new()),
+        });
+        let settings = mock_settings(&url);
+
+        let outcome = update_with(&mock_downloader(&settings), &path).unwrap();
+        assert_eq!(outcome.version.as_deref(), Some("v2"));
+        assert_eq!(std::fs::read(&path).unwrap(), full);
+        assert_eq!(read_version(&path).as_deref(), Some("v2"));
+
+        std::fs::remove_file(version_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+//! Message framing on the control socket
+//!
+//! Every message is one JSON object followed by a newline. JSON escapes newlines inside
+//! strings, so paths containing `\n` cannot break a message, and several messages can be
+//! sent on one connection.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{BufRead, Error, ErrorKind, Read, Write};
+
+/// Messages longer than this are rejected, so a peer cannot make the reader buffer forever
+pub const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Write one message and flush the writer
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()
+}
+
+/// Read one message, `None` if the peer closed the connection before sending anything
+///
+/// Invalid messages are reported as [`ErrorKind::InvalidData`].
+pub fn read_message<R: BufRead, T: DeserializeOwned>(reader: &mut R) -> std::io::Result<Option<T>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_MESSAGE_SIZE + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "message too long"));
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "connection closed in the middle of a message",
+        ));
+    }
+    serde_json::from_slice(&line)
+        .map(Some)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
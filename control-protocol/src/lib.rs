@@ -2,22 +2,109 @@ use serde::{Deserialize, Serialize};
 use std::os::linux::net::SocketAddrExt;
 use std::os::unix::net::SocketAddr;
 
+pub mod framing;
+
+/// Control socket of the daemon when nothing else is configured
+pub const DEFAULT_SOCKET: &str = "@simbiota";
+
+/// Selects the control socket of `simbiotactl`, same format as [`parse_socket_address`]
+pub const ENV_SOCKET: &str = "SIMBIOTA_SOCKET";
+
 pub fn socket_address() -> SocketAddr {
-    SocketAddr::from_abstract_name("simbiota").unwrap()
+    parse_socket_address(DEFAULT_SOCKET).unwrap()
+}
+
+/// `@name` is a socket in the abstract namespace, anything else a filesystem path
+pub fn parse_socket_address(socket: &str) -> std::io::Result<SocketAddr> {
+    match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(socket),
+    }
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
-    ManualScan { path: String, recursive: bool },
+    ManualScan {
+        path: String,
+        recursive: bool,
+    },
     ManualScanStatus,
     ManualScanCancel,
 
     QueryQuarantine,
+    /// Like `QueryQuarantine`, answered with the metadata of every entry
+    QueryQuarantineDetails,
     RestoreQuarantine(String),
+    /// Restore into the `dest` directory instead of the original location
+    RestoreQuarantineTo {
+        id_or_path: String,
+        dest: String,
+        force: bool,
+    },
     DeleteQuarantine(String),
+    /// Restore every entry to its original location
+    RestoreAllQuarantine,
+    /// Permanently delete every entry
+    DeleteAllQuarantine,
+    QuarantineStats,
+    /// Archive every quarantine entry into a new tar file at `dest`
+    ExportQuarantine {
+        dest: String,
+    },
+
+    QueryStats,
+    /// The most recent `limit` detections
+    QueryEvents {
+        limit: usize,
+    },
+
+    AddMonitorPath {
+        path: String,
+        mask: Vec<String>,
+        dir: bool,
+        event_on_children: bool,
+        mount: bool,
+        filesystem: bool,
+    },
+    RemoveMonitorPath {
+        path: String,
+    },
+
+    /// Start downloading the latest database on a worker thread, answered right away with
+    /// the [`DatabaseUpdateStatus`]
+    UpdateDatabase,
+    /// State of the last database update
+    UpdateDatabaseStatus,
 
     Restart,
 }
 
+impl Command {
+    /// Commands that change the system or read files as the daemon, the rest only query state
+    pub fn is_privileged(&self) -> bool {
+        match self {
+            Command::ManualScanStatus
+            | Command::QueryQuarantine
+            | Command::QueryQuarantineDetails
+            | Command::QuarantineStats
+            | Command::QueryStats
+            | Command::QueryEvents { .. }
+            | Command::UpdateDatabaseStatus => false,
+            Command::ManualScan { .. }
+            | Command::ManualScanCancel
+            | Command::RestoreQuarantine(_)
+            | Command::RestoreQuarantineTo { .. }
+            | Command::DeleteQuarantine(_)
+            | Command::RestoreAllQuarantine
+            | Command::DeleteAllQuarantine
+            | Command::ExportQuarantine { .. }
+            | Command::AddMonitorPath { .. }
+            | Command::RemoveMonitorPath { .. }
+            | Command::UpdateDatabase
+            | Command::Restart => true,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CommandStatus {
     Success,
@@ -28,7 +115,106 @@ pub enum CommandStatus {
 pub enum Response {
     None,
     QuarantineQueryResponse(Vec<(usize, String)>),
+    QuarantineDetailsResponse(Vec<QuarantineEntryDetails>),
     QuarantineActionResponse(bool),
+    QuarantineStatsResponse(QuarantineStats),
+    /// Number of exported entries
+    QuarantineExportResponse(usize),
+    /// Result of restoring or deleting every entry
+    QuarantineBatchResponse(QuarantineBatchResult),
+    Stats(DaemonStats),
+    Events(Vec<DetectionEvent>),
+    DatabaseUpdateStatus(DatabaseUpdateStatus),
+}
+
+/// State of the database update started by [`Command::UpdateDatabase`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DatabaseUpdateStatus {
+    /// No update was started since the daemon started
+    Idle,
+    Running,
+    /// Version of the installed database, if known
+    Finished(Option<String>),
+    /// Reason of the failure, the previous database is kept
+    Failed(String),
+}
+
+/// What the daemon did with a detected file
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionAction {
+    /// Access was denied, the file stayed in place
+    Blocked,
+    /// Access was denied and the file was moved to quarantine
+    Quarantined,
+    /// Only reported, observe mode was enabled
+    Observed,
+    /// Access was denied and the file was deleted
+    Removed,
+}
+
+/// A positive detection, as stored in the event log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetectionEvent {
+    pub path: String,
+    /// RFC 3339 time of the detection
+    pub time: String,
+    /// Detector class that reported the file
+    pub detector: String,
+    pub action: DetectionAction,
+    pub quarantine_id: Option<String>,
+    /// Identifier of the matched database entry, if the detector reports it
+    #[serde(default)]
+    pub signature: Option<usize>,
+    /// Comparison value of the match, the TLSH distance for TLSH detectors
+    #[serde(default)]
+    pub distance: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuarantineEntryDetails {
+    pub id: usize,
+    pub path: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Size of the file before it was quarantined, 0 if unknown
+    pub size: u64,
+    /// Unix timestamp, 0 if unknown
+    pub quarantined_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuarantineStats {
+    pub entries: usize,
+    pub total_size: u64,
+    pub max_entries: Option<usize>,
+    pub max_size: Option<u64>,
+}
+
+/// Outcome of an operation on every quarantine entry, failed entries do not stop the others
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuarantineBatchResult {
+    pub succeeded: usize,
+    pub failed: Vec<QuarantineBatchFailure>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuarantineBatchFailure {
+    /// Original path of the entry
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonStats {
+    pub files_scanned: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub detections: u64,
+    pub quarantine_entries: usize,
+    pub database_hashes: Option<usize>,
+    pub uptime_secs: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1,6 +1,6 @@
 pub use libc::c_int;
 use std::fs::read_link;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 mod low_level;
 pub mod monitor;
@@ -10,6 +10,12 @@ pub enum FanotifyEventResponse {
     Deny,
 }
 
+/// Path of the file behind `fd`, which may not be valid UTF-8
+pub fn get_path_from_fd(fd: c_int) -> Option<PathBuf> {
+    let path_str = format!("/proc/self/fd/{}", fd);
+    read_link(path_str).ok()
+}
+
 pub fn get_filename_from_fd(fd: c_int) -> Option<String> {
     let path_str = format!("/proc/self/fd/{}", fd);
     let path = Path::new(path_str.as_str());
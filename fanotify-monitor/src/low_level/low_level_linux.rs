@@ -5,23 +5,29 @@ use crate::low_level::FanotifyMarkError::{
     AllocationError, InvalidValue, NotADirectory, NotExists, OperationNotSupported, OutOfMarks,
     UnknownError, UnsupportedFilesystem,
 };
-use crate::low_level::FANOTIFY_PERM_EVENTS;
+use crate::low_level::{
+    FANOTIFY_PERM_EVENTS, FAN_EVENT_INFO_TYPE_DFID, FAN_EVENT_INFO_TYPE_DFID_NAME,
+    FAN_EVENT_INFO_TYPE_FID, FAN_NOFD,
+};
 use crate::FanotifyEventResponse;
 use crate::FanotifyEventResponse::Allow;
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
 use libc::{
     c_uint, close, fanotify_event_metadata, fanotify_init, fanotify_mark, fanotify_response,
-    perror, poll, pollfd, read, ssize_t, write, AT_FDCWD, EINVAL, EMFILE, ENODEV, ENOENT, ENOMEM,
-    ENOSPC, ENOSYS, ENOTDIR, EOPNOTSUPP, EPERM, EXDEV, FAN_ALLOW, FAN_CLASS_CONTENT,
-    FAN_CLASS_NOTIF, FAN_CLASS_PRE_CONTENT, FAN_DENY, POLLIN,
+    perror, poll, pollfd, read, ssize_t, write, AT_FDCWD, EINTR, EINVAL, EMFILE, ENODEV, ENOENT,
+    ENOMEM, ENOSPC, ENOSYS, ENOTDIR, EOPNOTSUPP, EPERM, EXDEV, FAN_ALLOW, FAN_CLASS_CONTENT,
+    FAN_CLASS_NOTIF, FAN_CLASS_PRE_CONTENT, FAN_DENY, POLLIN, POLLNVAL,
 };
-use log::warn;
-use std::ffi::{c_void, CString};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::ffi::{c_void, CString, OsStr, OsString};
+use std::os::fd::{FromRawFd, OwnedFd};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 
 #[warn(non_camel_case_types)]
 /// Used for fanotify_init to indicate which content setting the caller want to use for
@@ -162,7 +168,15 @@ pub fn monitor_close(fanotify_fd: FanotifyDescriptor) -> Result<(), i32> {
     Ok(())
 }
 
-const MSG_BUFFER_SIZE: usize = 1024;
+/// Size of the buffer events are read into when nothing else is configured, one event
+/// metadata is 24 bytes
+pub const DEFAULT_MSG_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Smallest accepted buffer size, large enough for an event with a file handle and a name
+pub const MIN_MSG_BUFFER_SIZE: usize = 4096;
+
+/// The buffer is not grown past this size when the kernel reports it as too small
+const MAX_MSG_BUFFER_SIZE: usize = 1024 * 1024;
 
 struct FanotifyEventIterator<'a> {
     read_len: ssize_t,
@@ -207,6 +221,120 @@ impl<'a> Iterator for FanotifyEventIterator<'a> {
     }
 }
 
+/// Decoded `fanotify_event_info_fid` record of an event, available when the group was
+/// initialized with one of the FAN_REPORT_*FID flags
+#[derive(Debug, Clone)]
+pub struct FanotifyFidInfo {
+    /// One of the FAN_EVENT_INFO_TYPE_* values
+    pub info_type: u8,
+    /// Filesystem id of the object
+    pub fsid: [i32; 2],
+    /// Type of the file handle, as used by `open_by_handle_at(2)`
+    pub handle_type: i32,
+    /// Opaque file handle
+    pub handle: Vec<u8>,
+    /// Name of the directory entry, only for FAN_EVENT_INFO_TYPE_DFID_NAME records
+    pub name: Option<OsString>,
+}
+
+/// Decode the info records following the fixed part of the event metadata
+///
+/// Safety: `event` must point into the buffer the event was read into, with at least
+/// `event_len` valid bytes.
+unsafe fn decode_fid_info(event: &fanotify_event_metadata) -> Vec<FanotifyFidInfo> {
+    let mut records = Vec::new();
+    let event_len = event.event_len as usize;
+    let mut offset = event.metadata_len as usize;
+    if event_len <= offset {
+        return records;
+    }
+    let data = std::slice::from_raw_parts(event as *const _ as *const u8, event_len);
+    let read_u32 = |at: usize| u32::from_ne_bytes(data[at..at + 4].try_into().unwrap());
+
+    // struct fanotify_event_info_header { __u8 info_type; __u8 pad; __u16 len; }
+    while offset + 4 <= event_len {
+        let info_type = data[offset];
+        let len = u16::from_ne_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        if len == 0 || offset + len > event_len {
+            break;
+        }
+        let record = &data[offset..offset + len];
+        offset += len;
+        if !matches!(
+            info_type,
+            FAN_EVENT_INFO_TYPE_FID | FAN_EVENT_INFO_TYPE_DFID | FAN_EVENT_INFO_TYPE_DFID_NAME
+        ) {
+            continue;
+        }
+        // header, __kernel_fsid_t fsid, struct file_handle { handle_bytes, handle_type, f_handle[] }
+        if record.len() < 20 {
+            continue;
+        }
+        let record_start = offset - len;
+        let fsid = [
+            read_u32(record_start + 4) as i32,
+            read_u32(record_start + 8) as i32,
+        ];
+        let handle_bytes = read_u32(record_start + 12) as usize;
+        let handle_type = read_u32(record_start + 16) as i32;
+        if 20 + handle_bytes > record.len() {
+            continue;
+        }
+        let handle = record[20..20 + handle_bytes].to_vec();
+        let name = if info_type == FAN_EVENT_INFO_TYPE_DFID_NAME {
+            let name_bytes = &record[20 + handle_bytes..];
+            let name_len = name_bytes
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(name_bytes.len());
+            Some(OsStr::from_bytes(&name_bytes[..name_len]).to_os_string())
+        } else {
+            None
+        };
+        records.push(FanotifyFidInfo {
+            info_type,
+            fsid,
+            handle_type,
+            handle,
+            name,
+        });
+    }
+    records
+}
+
+/// Write a single permission response to the fanotify descriptor
+fn write_response(fd: i32, event_fd: i32, response: FanotifyEventResponse) -> std::io::Result<()> {
+    let resp = fanotify_response {
+        fd: event_fd,
+        response: response.as_libc(),
+    };
+    let write_res = unsafe {
+        write(
+            fd,
+            (&resp) as *const _ as *const c_void,
+            std::mem::size_of::<fanotify_response>(),
+        )
+    };
+    if write_res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Answer a permission event. If the response cannot be written, the event is allowed
+/// so the accessing process does not stay blocked.
+fn respond(fd: i32, event_fd: i32, response: FanotifyEventResponse) {
+    let was_allow = matches!(response, Allow);
+    if let Err(e) = write_response(fd, event_fd, response) {
+        error!("fanotify response write failed: {}", e);
+        if !was_allow {
+            if let Err(e) = write_response(fd, event_fd, Allow) {
+                error!("fanotify fallback allow failed: {}", e);
+            }
+        }
+    }
+}
+
 impl FanotifyEventResponse {
     pub fn as_libc(&self) -> u32 {
         match self {
@@ -216,21 +344,38 @@ impl FanotifyEventResponse {
     }
 }
 
+/// The response callback borrows the fd of the event, it is closed by the monitor after the
+/// callback returned and the response was written
 pub type MonitorResponseCallback =
     Arc<dyn Fn(&fanotify_event_metadata) -> FanotifyEventResponse + Send + Sync>;
-pub type MonitorEventCallback = Arc<dyn Fn(&fanotify_event_metadata) + Send + Sync>;
+/// The event callback owns the fd of the event, `fd` is the descriptor of `metadata.fd`. It
+/// stays open until `fd` is dropped, so the event can be processed on another thread.
+pub type MonitorEventCallback = Arc<dyn Fn(&fanotify_event_metadata, OwnedFd) + Send + Sync>;
+pub type MonitorFidEventCallback =
+    Arc<dyn Fn(&fanotify_event_metadata, &[FanotifyFidInfo]) + Send + Sync>;
+
+/// Permission events that were not answered yet, keyed by the event fd, with the deadline
+/// of the response. Removing an entry claims the right to answer the event, so a response
+/// is never written twice.
+type OutstandingEvents = Arc<Mutex<HashMap<i32, Instant>>>;
+
+/// Longest sleep between two checks of the watchdog
+const WATCHDOG_MAX_INTERVAL: Duration = Duration::from_millis(100);
 
 struct MonitorResponder {
     receiver: Receiver<MonitorEvent>,
     fd: i32,
     response_callback: MonitorResponseCallback,
     event_callback: MonitorEventCallback,
+    fid_event_callback: Option<MonitorFidEventCallback>,
     write_lock: Arc<Mutex<()>>,
+    outstanding: Option<OutstandingEvents>,
 }
 
 enum MonitorEvent {
     PermEvent(fanotify_event_metadata),
     NormalEvent(fanotify_event_metadata),
+    FidEvent(fanotify_event_metadata, Vec<FanotifyFidInfo>),
 }
 
 impl MonitorResponder {
@@ -238,78 +383,165 @@ impl MonitorResponder {
         fd: i32,
         response_callback: MonitorResponseCallback,
         event_callback: MonitorEventCallback,
+        fid_event_callback: Option<MonitorFidEventCallback>,
         write_lock: Arc<Mutex<()>>,
+        outstanding: Option<OutstandingEvents>,
+        queue_capacity: usize,
     ) -> (Self, Sender<MonitorEvent>) {
-        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (sender, receiver) = crossbeam_channel::bounded(queue_capacity);
         (
             Self {
                 receiver,
                 fd,
                 event_callback,
+                fid_event_callback,
                 response_callback,
                 write_lock,
+                outstanding,
             },
             sender,
         )
     }
 
-    pub fn start(&self) -> ! {
-        loop {
-            let data = self.receiver.recv();
-            match data {
-                Ok(event) => match event {
-                    MonitorEvent::PermEvent(meta) => {
-                        let result = self.response_callback.as_ref()(&meta);
-                        let resp = fanotify_response {
-                            fd: meta.fd,
-                            response: result.as_libc(),
-                        };
-
+    /// Process events until the sending side of the channel is closed
+    pub fn start(&self) {
+        while let Ok(event) = self.receiver.recv() {
+            match event {
+                MonitorEvent::PermEvent(meta) => {
+                    let result = self.response_callback.as_ref()(&meta);
+                    // unless the watchdog already allowed the event
+                    if claim_event(&self.outstanding, meta.fd) {
                         let _lock = self.write_lock.lock().unwrap();
-                        let write_res = unsafe {
-                            write(
-                                self.fd,
-                                (&resp) as *const _ as *const c_void,
-                                std::mem::size_of::<fanotify_response>(),
-                            )
-                        };
-                        if write_res < 0 {
-                            panic!("response write failed");
-                        }
+                        respond(self.fd, meta.fd, result);
                     }
-                    MonitorEvent::NormalEvent(meta) => {
-                        self.event_callback.as_ref()(&meta);
+                    // closed only after the response, the fd number identifies the event
+                    unsafe { close(meta.fd) };
+                }
+                MonitorEvent::NormalEvent(meta) => {
+                    // SAFETY: the reader handed the open fd of the event over to the responder
+                    let fd = unsafe { OwnedFd::from_raw_fd(meta.fd) };
+                    self.event_callback.as_ref()(&meta, fd);
+                }
+                MonitorEvent::FidEvent(meta, info) => {
+                    if let Some(callback) = &self.fid_event_callback {
+                        callback.as_ref()(&meta, &info);
                     }
-                },
-                Err(e) => panic!("monitor responder died: {}", e),
+                }
             }
         }
+        info!("monitor event channel closed, responder stopped");
     }
 }
 
+/// Whether a permission event was caused by the monitoring process itself
+///
+/// These events are allowed by the reader before they are queued, so the callbacks never
+/// see them and the process never waits for a verdict on its own file access. Events report
+/// the thread group id, which is the same for every thread of the process, including the
+/// ones moving files into quarantine. Subprocesses have their own pid and are checked like
+/// any other process.
+///
+/// This matters when the process touches marked files itself: moving a detected file into
+/// a directory under a mount or filesystem mark opens the destination, which creates a new
+/// permission event. Queued behind the event that is being answered, it could never get a
+/// verdict if the responder had to handle it.
+fn is_own_event(event: &fanotify_event_metadata, own_pid: i32) -> bool {
+    event.pid == own_pid
+}
+
+/// Take the right to answer a permission event. Returns false if the event was already
+/// answered by the watchdog.
+fn claim_event(outstanding: &Option<OutstandingEvents>, event_fd: i32) -> bool {
+    match outstanding {
+        Some(outstanding) => outstanding.lock().unwrap().remove(&event_fd).is_some(),
+        None => true,
+    }
+}
+
+/// Allow every permission event that was not answered within `timeout`
+fn start_watchdog(
+    fd: i32,
+    timeout: Duration,
+    outstanding: OutstandingEvents,
+    write_lock: Arc<Mutex<()>>,
+) {
+    let interval = timeout.min(WATCHDOG_MAX_INTERVAL);
+    thread::Builder::new()
+        .name("MonitorWatchdog".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let now = Instant::now();
+            let expired: Vec<i32> = {
+                let mut outstanding = outstanding.lock().unwrap();
+                let expired = outstanding
+                    .iter()
+                    .filter(|(_, deadline)| **deadline <= now)
+                    .map(|(event_fd, _)| *event_fd)
+                    .collect::<Vec<_>>();
+                for event_fd in &expired {
+                    outstanding.remove(event_fd);
+                }
+                expired
+            };
+            for event_fd in expired {
+                warn!(
+                    "no verdict for fd {} within {} ms, allowing access",
+                    event_fd,
+                    timeout.as_millis()
+                );
+                let _lock = write_lock.lock().unwrap();
+                respond(fd, event_fd, Allow);
+            }
+        })
+        .unwrap();
+}
+
 /// Start listening to the fanotify monitor using the `poll` function
 ///
-/// Safety: This has to be tested extensively as the current implementation _can_ be overwhelmed. The
-/// memory safety is depending on the kernel's ability to process a huge number of PERM events
+/// At most `queue_capacity` events wait for the responder. When the responder cannot keep
+/// up, further permission events are allowed without a verdict and other events are
+/// dropped, so a slow detection neither grows the queue without limit nor blocks the
+/// accessing processes.
+///
+/// Events are read into a buffer of `msg_buffer_size` bytes, a larger buffer drains more
+/// events with a single read. The kernel only returns whole events, when the next one does
+/// not fit the read fails with EINVAL and the buffer is grown.
 pub fn monitor_listen(
     fanotify_fd: &FanotifyDescriptor,
     response_callback: MonitorResponseCallback,
     event_callback: MonitorEventCallback,
+    fid_event_callback: Option<MonitorFidEventCallback>,
+    response_timeout: Option<Duration>,
+    queue_capacity: usize,
+    msg_buffer_size: usize,
 ) -> ! {
-    let poll_array = [pollfd {
+    let mut poll_array = [pollfd {
         fd: fanotify_fd.fd,
         events: POLLIN,
         revents: 0,
     }; 1];
 
-    let mut msg_buffer: [u8; MSG_BUFFER_SIZE] = [0; MSG_BUFFER_SIZE];
+    let mut msg_buffer = vec![0u8; msg_buffer_size.max(MIN_MSG_BUFFER_SIZE)];
     let mypid = unsafe { libc::getpid() };
     let write_lock = Arc::new(Mutex::new(()));
+    let outstanding = response_timeout.map(|timeout| {
+        let outstanding = OutstandingEvents::default();
+        start_watchdog(
+            fanotify_fd.fd,
+            timeout,
+            outstanding.clone(),
+            write_lock.clone(),
+        );
+        outstanding
+    });
     let (processor, sender) = MonitorResponder::new(
         fanotify_fd.fd,
         response_callback,
         event_callback,
+        fid_event_callback,
         write_lock.clone(),
+        outstanding.clone(),
+        queue_capacity,
     );
 
     thread::Builder::new()
@@ -321,58 +553,208 @@ pub fn monitor_listen(
 
     loop {
         unsafe {
-            if poll(poll_array.as_ptr() as *mut pollfd, 1, -1) < 0 {
+            if poll(poll_array.as_mut_ptr(), 1, -1) < 0 {
                 let error = CString::new("poll()").unwrap();
                 perror(error.as_ptr());
             }
         }
+        if poll_array[0].revents & POLLNVAL > 0 {
+            // the descriptor was closed by `FilesystemMonitor::close`, no events will come
+            info!("fanotify descriptor closed, monitor stopped");
+            drop(sender);
+            loop {
+                thread::park();
+            }
+        }
 
-        unsafe {
-            if poll_array[0].revents & POLLIN > 0 {
+        // Events that did not fit into the queue during this drain
+        let mut allowed_unscanned = 0;
+        let mut dropped = 0;
+
+        // Drain every queued event before going back to poll, permission events block
+        // the accessing processes until they are answered
+        while poll_array[0].revents & POLLIN > 0 {
+            unsafe {
                 let read_len = read(
                     poll_array[0].fd,
                     msg_buffer.as_mut_ptr() as *mut c_void,
-                    MSG_BUFFER_SIZE,
+                    msg_buffer.len(),
                 );
-                if read_len > 0 {
-                    let event_iterator = FanotifyEventIterator {
-                        read_len,
-                        data_buffer: &msg_buffer,
-                        start_ptr: std::ptr::null(),
-                    };
-                    for event_meta in event_iterator {
-                        if event_meta.mask & FANOTIFY_PERM_EVENTS > 0 {
-                            let pid = event_meta.pid;
-                            // Always allow events from this process
-                            if pid == mypid {
-                                let _lock = write_lock.lock().unwrap();
-                                let resp = fanotify_response {
-                                    fd: event_meta.fd,
-                                    response: Allow.as_libc(),
-                                };
-
-                                let write_res = write(
-                                    fanotify_fd.fd,
-                                    (&resp) as *const _ as *const c_void,
-                                    std::mem::size_of::<fanotify_response>(),
-                                );
-                                if write_res < 0 {
-                                    let error = CString::new("write").unwrap();
-                                    perror(error.as_ptr());
-                                    panic!("response write failed");
+                if read_len < 0 {
+                    let error = std::io::Error::last_os_error();
+                    match error.raw_os_error() {
+                        Some(EINTR) => continue,
+                        // the next event does not fit into the buffer
+                        Some(EINVAL) if msg_buffer.len() < MAX_MSG_BUFFER_SIZE => {
+                            let new_size = (msg_buffer.len() * 2).min(MAX_MSG_BUFFER_SIZE);
+                            warn!(
+                                "fanotify event larger than the {} bytes buffer, growing it to {} bytes",
+                                msg_buffer.len(),
+                                new_size
+                            );
+                            msg_buffer.resize(new_size, 0);
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    warn!("fanotify read failed: {}", error);
+                    break;
+                }
+                if read_len == 0 {
+                    break;
+                }
+                let mut event_iterator = FanotifyEventIterator {
+                    read_len,
+                    data_buffer: &msg_buffer,
+                    start_ptr: std::ptr::null(),
+                };
+                for event_meta in event_iterator.by_ref() {
+                    if event_meta.mask & FANOTIFY_PERM_EVENTS > 0 {
+                        if is_own_event(event_meta, mypid) {
+                            let _lock = write_lock.lock().unwrap();
+                            respond(fanotify_fd.fd, event_meta.fd, Allow);
+                            close(event_meta.fd);
+                        } else {
+                            if let (Some(outstanding), Some(timeout)) =
+                                (&outstanding, response_timeout)
+                            {
+                                outstanding
+                                    .lock()
+                                    .unwrap()
+                                    .insert(event_meta.fd, Instant::now() + timeout);
+                            }
+                            if let Err(e) = sender.try_send(MonitorEvent::PermEvent(*event_meta)) {
+                                if let TrySendError::Full(_) = e {
+                                    allowed_unscanned += 1;
+                                }
+                                // Queue is full or the responder is gone, fail open instead
+                                // of blocking the process
+                                if claim_event(&outstanding, event_meta.fd) {
+                                    let _lock = write_lock.lock().unwrap();
+                                    respond(fanotify_fd.fd, event_meta.fd, Allow);
                                 }
                                 close(event_meta.fd);
-                            } else {
-                                let event_meta = *event_meta;
-                                sender.send(MonitorEvent::PermEvent(event_meta)).unwrap();
                             }
-                        } else {
-                            let event_meta = *event_meta;
-                            sender.send(MonitorEvent::NormalEvent(event_meta)).unwrap();
                         }
+                    } else if event_meta.fd == FAN_NOFD {
+                        // FID mode, the object is identified by the info records
+                        let info = decode_fid_info(event_meta);
+                        if let Err(TrySendError::Full(_)) =
+                            sender.try_send(MonitorEvent::FidEvent(*event_meta, info))
+                        {
+                            dropped += 1;
+                        }
+                    } else if let Err(e) = sender.try_send(MonitorEvent::NormalEvent(*event_meta)) {
+                        if let TrySendError::Full(_) = e {
+                            dropped += 1;
+                        }
+                        close(event_meta.fd);
                     }
                 }
+
+                // reads never split an event, anything left over is invalid
+                if event_iterator.read_len > 0 {
+                    warn!(
+                        "dropping {} bytes of invalid fanotify event data",
+                        event_iterator.read_len
+                    );
+                }
+
+                // Check for more events without blocking, the descriptor itself may be blocking
+                if poll(poll_array.as_mut_ptr(), 1, 0) <= 0 {
+                    break;
+                }
             }
         }
+
+        if allowed_unscanned > 0 || dropped > 0 {
+            warn!(
+                "event queue is full ({} events), allowed {} permission events without scanning and dropped {} events",
+                queue_capacity, allowed_unscanned, dropped
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METADATA_LEN: usize = std::mem::size_of::<fanotify_event_metadata>();
+
+    fn event(fd: i32, event_len: usize) -> fanotify_event_metadata {
+        // SAFETY: fanotify_event_metadata is a plain C struct, all zeroes is valid
+        let mut event: fanotify_event_metadata = unsafe { std::mem::zeroed() };
+        event.event_len = event_len as u32;
+        event.metadata_len = METADATA_LEN as u16;
+        event.fd = fd;
+        event
+    }
+
+    /// Iterate the first `read_len` bytes of `events`, returning the fds of the yielded
+    /// events and the number of bytes left over
+    fn iterate(events: &[fanotify_event_metadata], read_len: usize) -> (Vec<i32>, ssize_t) {
+        // SAFETY: the slice is reinterpreted as bytes, which keeps the metadata alignment
+        let data_buffer = unsafe {
+            std::slice::from_raw_parts(events.as_ptr() as *const u8, std::mem::size_of_val(events))
+        };
+        let mut iterator = FanotifyEventIterator {
+            read_len: read_len as ssize_t,
+            data_buffer,
+            start_ptr: std::ptr::null(),
+        };
+        let fds = iterator.by_ref().map(|event| event.fd).collect();
+        (fds, iterator.read_len)
+    }
+
+    #[test]
+    fn iterates_every_whole_event() {
+        let events = [
+            event(3, METADATA_LEN),
+            event(4, METADATA_LEN),
+            event(5, METADATA_LEN),
+        ];
+        assert_eq!(iterate(&events, 3 * METADATA_LEN), (vec![3, 4, 5], 0));
+    }
+
+    #[test]
+    fn stops_before_truncated_metadata() {
+        let events = [event(3, METADATA_LEN), event(4, METADATA_LEN)];
+        let read_len = METADATA_LEN + METADATA_LEN / 2;
+        assert_eq!(
+            iterate(&events, read_len),
+            (vec![3], (METADATA_LEN / 2) as ssize_t)
+        );
+    }
+
+    #[test]
+    fn stops_before_event_longer_than_the_buffer() {
+        // the second event claims info records past the end of the read data
+        let events = [event(3, METADATA_LEN), event(4, 3 * METADATA_LEN)];
+        assert_eq!(
+            iterate(&events, 2 * METADATA_LEN),
+            (vec![3], METADATA_LEN as ssize_t)
+        );
+    }
+
+    #[test]
+    fn stops_at_invalid_event_len() {
+        let events = [event(3, METADATA_LEN), event(4, 0), event(5, METADATA_LEN)];
+        assert_eq!(
+            iterate(&events, 3 * METADATA_LEN),
+            (vec![3], 2 * METADATA_LEN as ssize_t)
+        );
+    }
+
+    #[test]
+    fn recognizes_own_events() {
+        // SAFETY: getpid is always successful
+        let own_pid = unsafe { libc::getpid() };
+        let mut own = event(3, METADATA_LEN);
+        own.pid = own_pid;
+        let mut foreign = event(4, METADATA_LEN);
+        foreign.pid = own_pid + 1;
+        assert!(is_own_event(&own, own_pid));
+        assert!(!is_own_event(&foreign, own_pid));
     }
 }
@@ -15,3 +15,25 @@ pub use low_level_dummy::*;
 pub const FAN_OPEN_EXEC: u64 = 0x0000_1000;
 pub const FAN_OPEN_EXEC_PERM: u64 = 0x0004_0000;
 pub const FANOTIFY_PERM_EVENTS: u64 = FAN_OPEN_EXEC_PERM | FAN_ACCESS_PERM | FAN_OPEN_PERM;
+
+/// Directory entry events, only available with one of the FAN_REPORT_*FID modes
+pub const FAN_MOVED_FROM: u64 = 0x0000_0040;
+pub const FAN_MOVED_TO: u64 = 0x0000_0080;
+pub const FAN_CREATE: u64 = 0x0000_0100;
+pub const FAN_DELETE: u64 = 0x0000_0200;
+pub const FAN_DELETE_SELF: u64 = 0x0000_0400;
+pub const FAN_MOVE_SELF: u64 = 0x0000_0800;
+
+/// fanotify_init flags to report file handles instead of file descriptors
+pub const FAN_REPORT_FID: u32 = 0x0000_0200;
+pub const FAN_REPORT_DIR_FID: u32 = 0x0000_0400;
+pub const FAN_REPORT_NAME: u32 = 0x0000_0800;
+pub const FAN_REPORT_DFID_NAME: u32 = FAN_REPORT_DIR_FID | FAN_REPORT_NAME;
+
+/// Info record types following the event metadata
+pub const FAN_EVENT_INFO_TYPE_FID: u8 = 1;
+pub const FAN_EVENT_INFO_TYPE_DFID_NAME: u8 = 2;
+pub const FAN_EVENT_INFO_TYPE_DFID: u8 = 3;
+
+/// Value of the event fd when the event carries a file handle instead
+pub const FAN_NOFD: i32 = -1;
@@ -1,9 +1,13 @@
 use crate::low_level::{
-    monitor_close, monitor_init, monitor_listen, monitor_mark, FanotifyDescriptor, FAN_OPEN_EXEC,
-    FAN_OPEN_EXEC_PERM,
+    monitor_close, monitor_init, monitor_listen, monitor_mark, FanotifyDescriptor,
+    MonitorFidEventCallback, FAN_CREATE, FAN_DELETE, FAN_DELETE_SELF, FAN_MOVED_FROM, FAN_MOVED_TO,
+    FAN_MOVE_SELF, FAN_OPEN_EXEC, FAN_OPEN_EXEC_PERM, FAN_REPORT_DFID_NAME, FAN_REPORT_DIR_FID,
+    FAN_REPORT_FID, FAN_REPORT_NAME, MIN_MSG_BUFFER_SIZE,
 };
 
-pub use crate::low_level::{FanotifyInitError, FanotifyMarkError};
+pub use crate::low_level::{
+    FanotifyFidInfo, FanotifyInitError, FanotifyMarkError, DEFAULT_MSG_BUFFER_SIZE,
+};
 use crate::FanotifyEventResponse;
 use libc::{
     c_int, c_uint, AT_FDCWD, FAN_ACCESS, FAN_ACCESS_PERM, FAN_CLOEXEC, FAN_CLOSE_NOWRITE,
@@ -17,23 +21,57 @@ use libc::{
 pub use libc::fanotify_event_metadata;
 
 use bitflags::bitflags;
+use log::warn;
+use std::collections::HashMap;
+use std::os::fd::OwnedFd;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub use crate::low_level::FANClass;
 
+/// Number of events that may wait for a verdict when nothing else is configured
+pub const DEFAULT_QUEUE_CAPACITY: usize = 4096;
+
 pub struct FilesystemMonitor {
     fanotify_fd: FanotifyDescriptor,
 
     paths_to_add: Vec<(MarkFlags, EventMask, PathBuf)>,
+
+    /// Paths currently marked, with the flags and mask they were added with
+    marked_paths: Mutex<HashMap<PathBuf, (MarkFlags, EventMask)>>,
+
+    /// Permission events without a verdict after this long are allowed
+    response_timeout: Option<Duration>,
+
+    /// Events waiting for the callbacks, permission events beyond this are allowed unscanned
+    queue_capacity: usize,
+
+    /// Size of the buffer fanotify events are read into
+    msg_buffer_size: usize,
+
+    /// Receives the events of a group initialized with one of the REPORT_*FID flags
+    fid_event_callback: Option<MonitorFidEventCallback>,
+
+    /// Set once the descriptor was closed by [`FilesystemMonitor::close`]
+    closed: AtomicBool,
+
+    /// Set once the paths to add were marked
+    marks_applied: AtomicBool,
+
+    /// Called by [`FilesystemMonitor::start`] once every path is marked
+    ready_callback: Option<Box<dyn Fn() + Send + Sync>>,
 }
 
 impl Drop for FilesystemMonitor {
     fn drop(&mut self) {
-        monitor_close(FanotifyDescriptor {
-            fd: self.fanotify_fd.fd,
-        })
-        .expect("FilesystemMonitor failed to close the file descriptor");
+        if !self.closed.load(Ordering::SeqCst) {
+            monitor_close(FanotifyDescriptor {
+                fd: self.fanotify_fd.fd,
+            })
+            .expect("FilesystemMonitor failed to close the file descriptor");
+        }
     }
 }
 
@@ -48,14 +86,99 @@ impl FilesystemMonitor {
         Ok(Self {
             fanotify_fd: monitor_fd,
             paths_to_add: Vec::new(),
+            marked_paths: Mutex::new(HashMap::new()),
+            response_timeout: None,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            msg_buffer_size: DEFAULT_MSG_BUFFER_SIZE,
+            fid_event_callback: None,
+            closed: AtomicBool::new(false),
+            marks_applied: AtomicBool::new(false),
+            ready_callback: None,
         })
     }
 
+    /// Close the fanotify descriptor while the monitor may still be listening
+    ///
+    /// The kernel allows every permission event that did not get a response yet, so no
+    /// process stays blocked after the daemon exits. No events are delivered afterwards.
+    pub fn close(&self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Err(errno) = monitor_close(FanotifyDescriptor {
+            fd: self.fanotify_fd.fd,
+        }) {
+            warn!("failed to close fanotify descriptor: errno {}", errno);
+        }
+    }
+
+    /// Allow permission events automatically if no verdict is produced within `timeout`,
+    /// so a slow detection cannot block the accessing process indefinitely.
+    pub fn set_response_timeout(&mut self, timeout: Option<Duration>) {
+        self.response_timeout = timeout;
+    }
+
+    /// Limit the number of events waiting for the callbacks. When the queue is full,
+    /// permission events are allowed without calling the response callback and other
+    /// events are dropped.
+    pub fn set_queue_capacity(&mut self, capacity: usize) {
+        self.queue_capacity = capacity.max(1);
+    }
+
+    /// Set the size of the buffer events are read into. A larger buffer drains more events
+    /// per read, sizes below [`MIN_MSG_BUFFER_SIZE`] are raised to it.
+    pub fn set_msg_buffer_size(&mut self, size: usize) {
+        self.msg_buffer_size = size.max(MIN_MSG_BUFFER_SIZE);
+    }
+
+    /// Set the callback for events that identify the object by a file handle instead of
+    /// a file descriptor. The group must be created with one of the
+    /// [`MonitorFlags::REPORT_FID`] style flags and, on most kernels, [`FANClass::ClassNotif`].
+    pub fn set_fid_event_callback(
+        &mut self,
+        callback: Arc<dyn Fn(&fanotify_event_metadata, &[FanotifyFidInfo]) + Send + Sync>,
+    ) {
+        self.fid_event_callback = Some(callback);
+    }
+
+    /// Set a callback that is called when the paths are marked and events are about to be
+    /// processed
+    pub fn set_ready_callback(&mut self, callback: Box<dyn Fn() + Send + Sync>) {
+        self.ready_callback = Some(callback);
+    }
+
     pub fn add_path(&mut self, path: &Path, flags: MarkFlags, mask: EventMask) {
         let flags_val = flags | MarkFlags::ADD;
         self.paths_to_add.push((flags_val, mask, path.to_owned()));
     }
 
+    /// Mark a path right away, also while the monitor is listening
+    pub fn mark_path(
+        &self,
+        path: &Path,
+        flags: MarkFlags,
+        mask: EventMask,
+    ) -> Result<(), FanotifyMarkError> {
+        self.mark(flags | MarkFlags::ADD, mask, path)?;
+        self.marked_paths
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), (flags, mask));
+        Ok(())
+    }
+
+    /// Remove the mark of a path previously added with [`FilesystemMonitor::add_path`] or
+    /// [`FilesystemMonitor::mark_path`]
+    pub fn unmark_path(&self, path: &Path) -> Result<(), FanotifyMarkError> {
+        let mut marked_paths = self.marked_paths.lock().unwrap();
+        let Some((flags, mask)) = marked_paths.get(path).copied() else {
+            return Err(FanotifyMarkError::NotExists);
+        };
+        self.remove_path(path, flags, mask)?;
+        marked_paths.remove(path);
+        Ok(())
+    }
+
     pub fn remove_path(
         &self,
         path: &Path,
@@ -92,29 +215,76 @@ impl FilesystemMonitor {
         Ok(())
     }
 
+    /// Mark the paths added with [`FilesystemMonitor::add_path`]
+    ///
+    /// Called by [`FilesystemMonitor::start`], but can be called earlier, e.g. to drop
+    /// privileges after marking. The paths are only marked once. A path that cannot be
+    /// marked is logged and skipped, this panics only if none of the paths could be marked.
+    pub fn apply_marks(&self) {
+        if self.marks_applied.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut marked = 0;
+        for (flags, event, path) in &self.paths_to_add {
+            match self.mark(*flags, *event, path) {
+                Ok(()) => {
+                    self.marked_paths
+                        .lock()
+                        .unwrap()
+                        .insert(path.clone(), (*flags - MarkFlags::ADD, *event));
+                    marked += 1;
+                }
+                Err(FanotifyMarkError::InvalidValue)
+                    if event.intersects(
+                        EventMask::ACCESS_PERM | EventMask::OPEN_PERM | EventMask::OPEN_EXEC_PERM,
+                    ) =>
+                {
+                    warn!(
+                        "failed to mark {}: {:?}, the path is not monitored. Please make sure that CONFIG_FANOTIFY_ACCESS_PERMISSIONS kernel config option is enabled. See docs for more instructions.",
+                        path.display(),
+                        FanotifyMarkError::InvalidValue
+                    );
+                }
+                Err(e) => {
+                    warn!(
+                        "failed to mark {}: {:?}, the path is not monitored",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+        if marked == 0 && !self.paths_to_add.is_empty() {
+            panic!(
+                "none of the {} monitored paths could be marked",
+                self.paths_to_add.len()
+            );
+        }
+        if marked < self.paths_to_add.len() {
+            warn!("monitoring {} of {} paths", marked, self.paths_to_add.len());
+        }
+    }
+
     pub fn start(
         &self,
-        event_callback: Arc<dyn Fn(&fanotify_event_metadata) + Send + Sync>,
+        event_callback: Arc<dyn Fn(&fanotify_event_metadata, OwnedFd) + Send + Sync>,
         response_callback: Arc<
             dyn Fn(&fanotify_event_metadata) -> FanotifyEventResponse + Send + Sync,
         >,
     ) -> ! {
-        for (flags, event, path) in &self.paths_to_add {
-            self.mark(*flags, *event, path).unwrap_or_else(|e| {
-                if let FanotifyMarkError::InvalidValue = e {
-                    if event.contains(EventMask::ACCESS_PERM) | event.contains(EventMask::OPEN_PERM)
-                        || event.contains(EventMask::OPEN_EXEC_PERM)
-                    {
-                        panic!(
-                            "failed to mark {}: {e:?}\n\nPlease make sure that CONFIG_FANOTIFY_ACCESS_PERMISSIONS kernel config option is enabled. See docs for more instructions.\n",
-                            path.display()
-                        )
-                    }
-                    panic!("failed to mark {}: {e:?}", path.display())
-                }
-            });
+        self.apply_marks();
+        if let Some(ready_callback) = &self.ready_callback {
+            ready_callback();
         }
-        monitor_listen(&self.fanotify_fd, response_callback, event_callback)
+        monitor_listen(
+            &self.fanotify_fd,
+            response_callback,
+            event_callback,
+            self.fid_event_callback.clone(),
+            self.response_timeout,
+            self.queue_capacity,
+            self.msg_buffer_size,
+        )
     }
 }
 
@@ -131,6 +301,12 @@ bitflags! {
         const ACCESS_PERM = FAN_ACCESS_PERM;
         const ONDIR = FAN_ONDIR;
         const EVENT_ON_CHILD = FAN_EVENT_ON_CHILD;
+        const CREATE = FAN_CREATE;
+        const DELETE = FAN_DELETE;
+        const MOVED_FROM = FAN_MOVED_FROM;
+        const MOVED_TO = FAN_MOVED_TO;
+        const DELETE_SELF = FAN_DELETE_SELF;
+        const MOVE_SELF = FAN_MOVE_SELF;
     }
 }
 
@@ -148,6 +324,12 @@ impl EventMask {
                 "OPEN_PERM" => value.insert(Self::OPEN_PERM),
                 "OPEN_EXEC_PERM" => value.insert(Self::OPEN_EXEC_PERM),
                 "ACCESS_PERM" => value.insert(Self::ACCESS_PERM),
+                "CREATE" => value.insert(Self::CREATE),
+                "DELETE" => value.insert(Self::DELETE),
+                "MOVED_FROM" => value.insert(Self::MOVED_FROM),
+                "MOVED_TO" => value.insert(Self::MOVED_TO),
+                "DELETE_SELF" => value.insert(Self::DELETE_SELF),
+                "MOVE_SELF" => value.insert(Self::MOVE_SELF),
 
                 _ => return Err(format!("invalid mask: {}", flag)),
             }
@@ -176,6 +358,10 @@ bitflags! {
         const NONBLOCK = FAN_NONBLOCK;
         const UNLIMITED_QUEUE = FAN_UNLIMITED_QUEUE;
         const UNLIMITED_MARKS = FAN_UNLIMITED_MARKS;
+        const REPORT_FID = FAN_REPORT_FID;
+        const REPORT_DIR_FID = FAN_REPORT_DIR_FID;
+        const REPORT_NAME = FAN_REPORT_NAME;
+        const REPORT_DFID_NAME = FAN_REPORT_DFID_NAME;
     }
 }
 
@@ -188,6 +374,10 @@ impl MonitorFlags {
                 "NONBLOCK" => value.insert(Self::NONBLOCK),
                 "UNLIMITED_QUEUE" => value.insert(Self::UNLIMITED_QUEUE),
                 "UNLIMITED_MARKS" => value.insert(Self::UNLIMITED_MARKS),
+                "REPORT_FID" => value.insert(Self::REPORT_FID),
+                "REPORT_DIR_FID" => value.insert(Self::REPORT_DIR_FID),
+                "REPORT_NAME" => value.insert(Self::REPORT_NAME),
+                "REPORT_DFID_NAME" => value.insert(Self::REPORT_DFID_NAME),
                 _ => return Err(format!("invalid monitor flags: {}", flag)),
             }
         }
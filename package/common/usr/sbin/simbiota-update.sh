@@ -3,18 +3,19 @@
 set -o errexit
 set -o nounset
 
-# Dropping privileges because downloading a file doesn't need root.
-# The --inh-caps part is essentially the same as -all but on legacy raspbian
-# `setpriv` returns 'setpriv: libcap-ng is too old for "all" caps' so
-# we list all priviliges one-by-one with --list-caps.
-setpriv --reuid=nobody --regid=nogroup --init-groups --inh-caps="$(setpriv --list-caps | xargs -I{} printf '-{},' | head -c-1)" sh -s <<- EOF
-umask 0000
-curl -s https://api.github.com/repos/simbiota/database-releases/releases/latest \
-| jq '.assets[] | select(.name | match(".*-arm-.*")).browser_download_url' \
-| tr -d '"\n' \
-| xargs -0 -I{} curl -L -o /tmp/database.sdb '{}'
-EOF
+# The download, the signature check and the installation are done by the daemon binary,
+# with the settings from /etc/default/simbiota-update. Exit codes besides 0 (success or
+# already up to date) and 1 (other errors) are listed in simbiota(8).
+#
+# --check: only compare the installed version with the latest release.
+# Exits 0 if the database is up to date, 2 if an update is available.
+SIMBIOTA="${SIMBIOTA:-/usr/sbin/simbiota}"
 
-mv /tmp/database.sdb /var/lib/simbiota/database.sdb
-chown root:root /var/lib/simbiota/database.sdb
-chmod 644 /var/lib/simbiota/database.sdb
\ No newline at end of file
+case "$*" in
+    "") exec "${SIMBIOTA}" --update-database ;;
+    --check) exec "${SIMBIOTA}" --check-update ;;
+    *)
+        echo "usage: $0 [--check]" >&2
+        exit 1
+        ;;
+esac
@@ -0,0 +1,88 @@
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+type Sha256Hash = [u8; 32];
+
+/// Files that are never reported, identified by the SHA-256 hash of their content
+///
+/// The allowlist file contains one hex encoded hash per line, lines starting with `#`
+/// are comments. Since the decision depends on the content only, renaming or moving an
+/// allowed file keeps it allowed, while any modification removes it from the list.
+pub struct Allowlist {
+    path: PathBuf,
+    hashes: HashSet<Sha256Hash>,
+}
+
+impl Allowlist {
+    pub fn new(path: &Path) -> Self {
+        let mut allowlist = Self {
+            path: path.to_path_buf(),
+            hashes: HashSet::new(),
+        };
+        allowlist.reload();
+        allowlist
+    }
+
+    /// Read the allowlist file again, keeping the previous entries if it cannot be read
+    pub fn reload(&mut self) {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("failed to read allowlist {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        let mut hashes = HashSet::new();
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_hash(line) {
+                Some(hash) => {
+                    hashes.insert(hash);
+                }
+                None => warn!(
+                    "invalid hash in allowlist {} line {}",
+                    self.path.display(),
+                    line_number + 1
+                ),
+            }
+        }
+        info!("loaded {} allowlist entries", hashes.len());
+        self.hashes = hashes;
+    }
+
+    /// Whether the content of the file is allowed. Reads with `pread`, so the file offset
+    /// is not changed.
+    pub fn contains(&self, file: &File) -> std::io::Result<bool> {
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; 64 * 1024];
+        let mut offset = 0;
+        loop {
+            let len = file.read_at(&mut buffer, offset)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buffer[..len]);
+            offset += len as u64;
+        }
+        let hash: Sha256Hash = hasher.finalize().into();
+        Ok(self.hashes.contains(&hash))
+    }
+}
+
+fn parse_hash(hex: &str) -> Option<Sha256Hash> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
@@ -7,11 +7,39 @@ pub(crate) struct ClientArgs {
     #[arg(short, long, value_name = "FILE")]
     pub(crate) config: Option<PathBuf>,
 
-    /// Run in daemon mode
+    /// Run in daemon mode, detached from the terminal with stdio redirected to /dev/null
     #[arg(long)]
     pub(crate) bg: bool,
 
+    /// Stay attached to the terminal and also log to it, for service managers
+    #[arg(long, conflicts_with = "bg")]
+    pub(crate) foreground: bool,
+
     /// Verbose output
     #[arg(short, long)]
     pub(crate) verbose: bool,
+
+    /// Only log and alert detections, never block or quarantine files
+    #[arg(long)]
+    pub(crate) observe: bool,
+
+    /// Validate the config file and exit without starting monitoring
+    #[arg(long)]
+    pub(crate) check_config: bool,
+
+    /// Check that FILE is a loadable database and exit
+    #[arg(long, value_name = "FILE")]
+    pub(crate) verify_database: Option<PathBuf>,
+
+    /// Download and install the latest database release and exit
+    #[arg(long, conflicts_with_all = ["bg", "foreground"])]
+    pub(crate) update_database: bool,
+
+    /// Only check whether a newer database release is available, exits with 2 if it is
+    #[arg(long, conflicts_with = "update_database")]
+    pub(crate) check_update: bool,
+
+    /// Wait for the database file to appear instead of exiting when it is missing
+    #[arg(long)]
+    pub(crate) wait_for_database: bool,
 }
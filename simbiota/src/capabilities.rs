@@ -0,0 +1,139 @@
+//! Dropping root capabilities after the fanotify group is set up
+//!
+//! Capabilities are per-thread on Linux, so [`drop_capabilities`] must be called before
+//! the worker threads are started, threads created later inherit the reduced set.
+
+use log::{debug, info};
+
+const CAP_CHOWN: u32 = 0;
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_FOWNER: u32 = 3;
+
+/// Needed by the quarantine to move files and restore their ownership
+const RETAINED_CAPABILITIES: [u32; 4] =
+    [CAP_CHOWN, CAP_DAC_OVERRIDE, CAP_DAC_READ_SEARCH, CAP_FOWNER];
+
+const CAPABILITY_NAMES: [&str; 41] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Reduce the capabilities of the calling thread to [`RETAINED_CAPABILITIES`]
+///
+/// The bounding set is reduced too, so executed programs cannot regain the dropped
+/// capabilities.
+pub fn drop_capabilities() -> std::io::Result<()> {
+    let last_cap = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        .unwrap_or(CAPABILITY_NAMES.len() as u32 - 1);
+
+    // the bounding set can only be changed while CAP_SETPCAP is still effective
+    for cap in 0..=last_cap {
+        if RETAINED_CAPABILITIES.contains(&cap) {
+            continue;
+        }
+        /// SAFETY: Standard LibC call
+        let res = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    let mut data = [CapUserData::default(); 2];
+    for cap in RETAINED_CAPABILITIES {
+        let bit = 1 << (cap % 32);
+        data[cap as usize / 32].effective |= bit;
+        data[cap as usize / 32].permitted |= bit;
+    }
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    /// SAFETY: The kernel reads two data structs for version 3
+    let res = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    debug!("capabilities dropped");
+    Ok(())
+}
+
+/// Log the effective capabilities of the calling thread
+pub fn log_capabilities() {
+    let mut data = [CapUserData::default(); 2];
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    /// SAFETY: The kernel writes two data structs for version 3
+    let res = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+    if res < 0 {
+        debug!(
+            "failed to read capabilities: {}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+    let names: Vec<&str> = (0..CAPABILITY_NAMES.len())
+        .filter(|cap| data[cap / 32].effective & (1 << (cap % 32)) != 0)
+        .map(|cap| CAPABILITY_NAMES[cap])
+        .collect();
+    info!("effective capabilities: {}", names.join(", "));
+}
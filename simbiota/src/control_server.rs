@@ -1,16 +1,26 @@
+use crate::daemon_config::ControlConfig;
 use crate::detection_system;
 use crate::detection_system::Action::QueryQuarantine;
 use crate::detection_system::{Action, CommandResult, DetectionSystem, DetectorCommand};
+use crate::peer_auth;
 use crossbeam_channel::{Receiver, Sender};
 use libc::c_char;
-use log::{debug, error, info};
-use simbiota_protocol::{Command, CommandRequest, CommandResponse, CommandStatus, Response};
+use log::{debug, error, info, warn};
+use simbiota_clientlib::updater;
+use simbiota_monitor::monitor::{EventMask, MarkFlags};
+use simbiota_protocol::framing;
+use simbiota_protocol::{
+    Command, CommandRequest, CommandResponse, CommandStatus, DatabaseUpdateStatus,
+    QuarantineEntryDetails, Response,
+};
 use std::ffi::CString;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, ErrorKind, Write};
 use std::os::fd::OwnedFd;
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 
 pub struct ControlServer {
@@ -18,33 +28,92 @@ pub struct ControlServer {
     client_tx: Sender<detection_system::DetectorCommand>,
     client_id: usize,
     client_rx: Receiver<detection_system::CommandResult>,
+    database_file: PathBuf,
+    /// Besides root, members of this group may use every command
+    admin_gid: Option<u32>,
+    /// Members of this group may use the read-only commands
+    read_gid: Option<u32>,
+    /// State of the last update, shared with the update thread
+    update_status: Arc<Mutex<DatabaseUpdateStatus>>,
 }
 
 impl ControlServer {
-    pub fn new(com: (usize, Receiver<CommandResult>, Sender<DetectorCommand>)) -> Self {
+    pub fn new(
+        com: (usize, Receiver<CommandResult>, Sender<DetectorCommand>),
+        database_file: PathBuf,
+        config: &ControlConfig,
+    ) -> Self {
+        let socket = config.socket.as_str();
+        let group_id = |key: &str, group: &String| {
+            peer_auth::group_id(group).unwrap_or_else(|e| {
+                error!("invalid control.{} {}: {}", key, group, e);
+                eprintln!("Unknown control socket group: {}", group);
+                exit(1);
+            })
+        };
+        let admin_gid = config
+            .admin_group
+            .as_ref()
+            .map(|group| group_id("admin_group", group));
+        let read_gid = config
+            .read_group
+            .as_ref()
+            .map(|group| group_id("read_group", group));
+        let address = simbiota_protocol::parse_socket_address(socket).unwrap_or_else(|e| {
+            error!("invalid control socket {}: {}", socket, e);
+            eprintln!("Invalid control socket: {}", socket);
+            exit(1);
+        });
         // check whether we can connect
-        let connection = UnixStream::connect_addr(&simbiota_protocol::socket_address());
+        let connection = UnixStream::connect_addr(&address);
         if let Ok(_) = connection {
             error!("cannot start control server: already running");
             eprintln!("Another instance of SIMBIoTA is already running");
             exit(1);
         }
+        // a socket file left behind by a daemon that is not running anymore
+        if let Some(path) = address.as_pathname() {
+            let _ = std::fs::remove_file(path);
+        }
 
         /*unsafe {
             let path = CString::new("/var/run/simbiota.sock").unwrap();
             libc::unlink(path.as_ptr() as *const c_char);
         }*/
-        let listener = UnixListener::bind_addr(&simbiota_protocol::socket_address())
-            .expect("Failed to bind to socket");
+        let listener = UnixListener::bind_addr(&address).expect("Failed to bind to socket");
+        info!("control socket: {}", socket);
 
         Self {
             listener,
             client_id: com.0,
             client_rx: com.1,
             client_tx: com.2,
+            database_file,
+            admin_gid,
+            read_gid,
+            update_status: Arc::new(Mutex::new(DatabaseUpdateStatus::Idle)),
         }
     }
 
+    /// Root, and members of the admin group if one is configured
+    fn is_admin(&self, peer: &peer_auth::PeerCredentials) -> bool {
+        peer.uid == 0
+            || self
+                .admin_gid
+                .is_some_and(|gid| peer_auth::is_member(peer, gid))
+    }
+
+    /// Members of the read group, allowed to use the commands that are not privileged
+    fn is_reader(&self, peer: &peer_auth::PeerCredentials) -> bool {
+        self.read_gid
+            .is_some_and(|gid| peer_auth::is_member(peer, gid))
+    }
+
+    /// Another handle to the listening socket, used to stop accepting connections on shutdown
+    pub fn try_clone_listener(&self) -> std::io::Result<UnixListener> {
+        self.listener.try_clone()
+    }
+
     pub fn listen(&self) -> ! {
         info!("control server listening");
         for stream in self.listener.incoming() {
@@ -61,6 +130,81 @@ impl ControlServer {
         panic!("Control server stopped listening");
     }
 
+    /// Send an action to the detector and wait for its result
+    ///
+    /// Returns `None` if the detector is gone, e.g. while the daemon shuts down, so the
+    /// request fails instead of taking the control server down.
+    fn detector_request(&self, command: Action) -> Option<CommandResult> {
+        let request = DetectorCommand {
+            id: self.client_id,
+            command,
+        };
+        if let Err(e) = self.client_tx.send(request) {
+            error!("failed to send request to the detector: {}", e);
+            return None;
+        }
+        match self.client_rx.recv() {
+            Ok(result) => Some(result),
+            Err(e) => {
+                error!("failed to receive response from the detector: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Start a database update on a worker thread unless one is running already
+    ///
+    /// The update takes as long as the download, running it here would block every other
+    /// control request. The database watcher reloads the database once it is installed.
+    fn start_update(&self) -> DatabaseUpdateStatus {
+        let mut status = self.update_status.lock().unwrap();
+        if *status == DatabaseUpdateStatus::Running {
+            return DatabaseUpdateStatus::Running;
+        }
+        *status = DatabaseUpdateStatus::Running;
+        let update_status = self.update_status.clone();
+        let database_file = self.database_file.clone();
+        let spawned = thread::Builder::new()
+            .name("DatabaseUpdate".to_string())
+            .spawn(move || {
+                let result = match updater::UpdateSettings::load()
+                    .and_then(|settings| updater::update_database(&database_file, &settings))
+                {
+                    Ok(outcome) => DatabaseUpdateStatus::Finished(outcome.version),
+                    Err(e) => {
+                        if let updater::UpdateError::BadSignature = e {
+                            error!("downloaded database has an invalid signature: {}", e);
+                        }
+                        DatabaseUpdateStatus::Failed(e.to_string())
+                    }
+                };
+                *update_status.lock().unwrap() = result;
+            });
+        if let Err(e) = spawned {
+            error!("failed to start database update: {}", e);
+            *status = DatabaseUpdateStatus::Failed(format!("failed to start the update: {}", e));
+        }
+        status.clone()
+    }
+
+    fn monitor_action(&self, action: Action) -> CommandResponse {
+        let status = match self.detector_request(action) {
+            Some(CommandResult::MonitorAction(Ok(()))) => CommandStatus::Success,
+            Some(CommandResult::MonitorAction(Err(e))) => {
+                CommandStatus::Failure(format!("{:?}", e))
+            }
+            Some(_) => {
+                error!("invalid response from detector");
+                CommandStatus::Failure("invalid response from detector".to_string())
+            }
+            None => CommandStatus::Failure("detector is not running".to_string()),
+        };
+        CommandResponse {
+            status,
+            response: Response::None,
+        }
+    }
+
     fn serve(&self, mut stream: std::os::unix::net::UnixStream) {
         stream
             .set_read_timeout(Some(Duration::from_secs(60)))
@@ -70,16 +214,6 @@ impl ControlServer {
             .unwrap();
         let mut reader = std::io::BufReader::new(&stream);
         let mut writer = std::io::BufWriter::new(&stream);
-        let mut command_line = String::new();
-        reader.read_line(&mut command_line).unwrap();
-        if command_line.is_empty() {
-            return;
-        }
-        let Ok(command) = serde_json::from_str::<CommandRequest>(&command_line) else {
-            error!("failed to parse command: {:?}", command_line);
-            return;
-        };
-        debug!("control request: {:?}", command);
 
         fn failure(msg: &str) -> CommandResponse {
             CommandResponse {
@@ -88,78 +222,286 @@ impl ControlServer {
             }
         }
 
+        /// Response to a detector result that does not belong to the request
+        fn unexpected_response(result: Option<CommandResult>) -> CommandResponse {
+            match result {
+                Some(_) => {
+                    error!("invalid response from detector");
+                    failure("invalid response from detector")
+                }
+                None => failure("detector is not running"),
+            }
+        }
+
+        // checked before the request is read, unauthorized peers cannot reach the parser
+        let peer = match peer_auth::peer_credentials(&stream) {
+            Ok(peer) if self.is_admin(&peer) || self.is_reader(&peer) => peer,
+            Ok(peer) => {
+                warn!(
+                    "rejected control connection from uid {} (pid {})",
+                    peer.uid, peer.pid
+                );
+                Self::respond(
+                    &mut writer,
+                    &failure("permission denied: root or a control socket group is required"),
+                );
+                return;
+            }
+            Err(e) => {
+                warn!("failed to get the credentials of a control peer: {}", e);
+                return;
+            }
+        };
+        let admin = self.is_admin(&peer);
+        debug!("control peer: {:?}, admin: {}", peer, admin);
+
+        let command = match framing::read_message::<_, CommandRequest>(&mut reader) {
+            Ok(Some(command)) => command,
+            Ok(None) => {
+                debug!("control connection closed without a request");
+                return;
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                error!("failed to parse command: {}", e);
+                Self::respond(&mut writer, &failure(&format!("invalid command: {}", e)));
+                return;
+            }
+            Err(e) => {
+                warn!("failed to read control request: {}", e);
+                return;
+            }
+        };
+        debug!("control request: {:?}", command);
+        if command.command.is_privileged() && !admin {
+            warn!(
+                "rejected privileged command from uid {} (pid {}): {:?}",
+                peer.uid, peer.pid, command.command
+            );
+            Self::respond(
+                &mut writer,
+                &failure("permission denied: root or the control.admin_group is required"),
+            );
+            return;
+        }
+
         let result: CommandResponse = match command.command {
             Command::ManualScan { .. } => CommandResponse {
                 status: CommandStatus::Failure("not implemented".to_string()),
                 response: Response::None,
             },
-            Command::ManualScanStatus => {
-                todo!("not supported");
-            }
-            Command::ManualScanCancel => {
-                todo!("not supported");
-            }
-            Command::QueryQuarantine => {
-                self.client_tx
-                    .send(DetectorCommand {
-                        id: self.client_id,
-                        command: Action::QueryQuarantine,
-                    })
-                    .unwrap();
-                let result = self.client_rx.recv().unwrap();
-                match result {
-                    CommandResult::QuarantineEntries(entries) => CommandResponse {
+            Command::ManualScanStatus | Command::ManualScanCancel => failure("not implemented"),
+            Command::QueryQuarantine => match self.detector_request(Action::QueryQuarantine) {
+                Some(CommandResult::QuarantineEntries(entries)) => CommandResponse {
+                    status: CommandStatus::Success,
+                    response: Response::QuarantineQueryResponse(
+                        entries
+                            .iter()
+                            .enumerate()
+                            .map(|(i, e)| (i, e.original_path.clone()))
+                            .collect(),
+                    ),
+                },
+                other => unexpected_response(other),
+            },
+            Command::QueryQuarantineDetails => {
+                match self.detector_request(Action::QueryQuarantine) {
+                    Some(CommandResult::QuarantineEntries(entries)) => CommandResponse {
                         status: CommandStatus::Success,
-                        response: Response::QuarantineQueryResponse(
+                        response: Response::QuarantineDetailsResponse(
                             entries
-                                .iter()
+                                .into_iter()
                                 .enumerate()
-                                .map(|(i, e)| (i, e.original_path.clone()))
+                                .map(|(id, e)| QuarantineEntryDetails {
+                                    id,
+                                    path: e.original_path,
+                                    mode: e.mode,
+                                    uid: e.uid,
+                                    gid: e.gid,
+                                    size: e.size,
+                                    quarantined_at: e.quarantined_at,
+                                })
                                 .collect(),
                         ),
                     },
-                    _ => failure("invalid response from detector"),
+                    other => unexpected_response(other),
                 }
             }
             Command::RestoreQuarantine(e) => {
-                self.client_tx
-                    .send(DetectorCommand {
-                        id: self.client_id,
-                        command: Action::RestoreQuarantineEntry(e),
-                    })
-                    .unwrap();
-                let result = self.client_rx.recv().unwrap();
-                match result {
-                    CommandResult::QuarantineAction(s) => CommandResponse {
+                match self.detector_request(Action::RestoreQuarantineEntry(e)) {
+                    Some(CommandResult::QuarantineAction(s)) => CommandResponse {
                         status: CommandStatus::Success,
                         response: Response::QuarantineActionResponse(s),
                     },
-                    _ => failure("invalid response from detector"),
+                    other => unexpected_response(other),
+                }
+            }
+            Command::RestoreQuarantineTo {
+                id_or_path,
+                dest,
+                force,
+            } => {
+                match self.detector_request(Action::RestoreQuarantineEntryTo(
+                    id_or_path,
+                    PathBuf::from(dest),
+                    force,
+                )) {
+                    Some(CommandResult::QuarantineAction(s)) => CommandResponse {
+                        status: CommandStatus::Success,
+                        response: Response::QuarantineActionResponse(s),
+                    },
+                    other => unexpected_response(other),
+                }
+            }
+            Command::ExportQuarantine { dest } => {
+                match self.detector_request(Action::ExportQuarantine(PathBuf::from(dest))) {
+                    Some(CommandResult::QuarantineExport(Ok(count))) => CommandResponse {
+                        status: CommandStatus::Success,
+                        response: Response::QuarantineExportResponse(count),
+                    },
+                    Some(CommandResult::QuarantineExport(Err(e))) => failure(&e),
+                    other => unexpected_response(other),
                 }
             }
             Command::DeleteQuarantine(e) => {
-                self.client_tx
-                    .send(DetectorCommand {
-                        id: self.client_id,
-                        command: Action::DeleteQuarantineEntry(e),
-                    })
-                    .unwrap();
-
-                let result = self.client_rx.recv().unwrap();
-                match result {
-                    CommandResult::QuarantineAction(s) => CommandResponse {
+                match self.detector_request(Action::DeleteQuarantineEntry(e)) {
+                    Some(CommandResult::QuarantineAction(s)) => CommandResponse {
                         status: CommandStatus::Success,
                         response: Response::QuarantineActionResponse(s),
                     },
-                    _ => failure("invalid response from detector"),
+                    other => unexpected_response(other),
+                }
+            }
+            batch @ (Command::RestoreAllQuarantine | Command::DeleteAllQuarantine) => {
+                let command = match batch {
+                    Command::RestoreAllQuarantine => Action::RestoreAllQuarantine,
+                    _ => Action::DeleteAllQuarantine,
+                };
+                match self.detector_request(command) {
+                    Some(CommandResult::QuarantineBatch(Ok(result))) => CommandResponse {
+                        status: CommandStatus::Success,
+                        response: Response::QuarantineBatchResponse(result),
+                    },
+                    Some(CommandResult::QuarantineBatch(Err(e))) => failure(&e),
+                    other => unexpected_response(other),
+                }
+            }
+            Command::QuarantineStats => match self.detector_request(Action::QueryQuarantineStats) {
+                Some(CommandResult::QuarantineStats(stats)) => CommandResponse {
+                    status: CommandStatus::Success,
+                    response: Response::QuarantineStatsResponse(stats),
+                },
+                other => unexpected_response(other),
+            },
+            Command::QueryStats => match self.detector_request(Action::QueryStats) {
+                Some(CommandResult::Stats(stats)) => CommandResponse {
+                    status: CommandStatus::Success,
+                    response: Response::Stats(stats),
+                },
+                other => unexpected_response(other),
+            },
+            Command::QueryEvents { limit } => {
+                match self.detector_request(Action::QueryEvents(limit)) {
+                    Some(CommandResult::Events(events)) => CommandResponse {
+                        status: CommandStatus::Success,
+                        response: Response::Events(events),
+                    },
+                    other => unexpected_response(other),
                 }
             }
-            Command::Restart => {
-                todo!("not supported");
+            Command::AddMonitorPath {
+                path,
+                mask,
+                dir,
+                event_on_children,
+                mount,
+                filesystem,
+            } => match EventMask::parse(mask.iter().map(String::as_str).collect()) {
+                Ok(mut mask) => {
+                    let mut flags = MarkFlags::empty();
+                    flags.set(MarkFlags::ONLY_DIR, dir);
+                    flags.set(MarkFlags::MOUNT, mount);
+                    flags.set(MarkFlags::FILESYSTEM, filesystem);
+                    mask.set(EventMask::EVENT_ON_CHILD, event_on_children);
+                    self.monitor_action(Action::AddMonitorPath(PathBuf::from(path), flags, mask))
+                }
+                Err(e) => failure(&e),
+            },
+            Command::RemoveMonitorPath { path } => {
+                self.monitor_action(Action::RemoveMonitorPath(PathBuf::from(path)))
             }
+            Command::UpdateDatabase => CommandResponse {
+                status: CommandStatus::Success,
+                response: Response::DatabaseUpdateStatus(self.start_update()),
+            },
+            Command::UpdateDatabaseStatus => CommandResponse {
+                status: CommandStatus::Success,
+                response: Response::DatabaseUpdateStatus(
+                    self.update_status.lock().unwrap().clone(),
+                ),
+            },
+            Command::Restart => failure("not implemented"),
         };
-        let response = serde_json::to_string(&result).unwrap();
-        writer.write_all(response.as_bytes()).unwrap();
-        writer.write_all("\n".as_bytes()).unwrap();
+        Self::respond(&mut writer, &result);
+    }
+
+    /// Send the response, a client that is already gone is only logged
+    fn respond(writer: &mut impl Write, response: &CommandResponse) {
+        if let Err(e) = framing::write_message(writer, response) {
+            warn!("failed to send control response: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serve_survives_connection_without_request() {
+        let path =
+            std::env::temp_dir().join(format!("simbiota-control-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (client_tx, _detector_rx) = crossbeam_channel::unbounded();
+        let (_detector_tx, client_rx) = crossbeam_channel::unbounded();
+        // SAFETY: Safe, syscall without args
+        let gid = unsafe { libc::getegid() };
+        let server = ControlServer {
+            listener: UnixListener::bind(&path).unwrap(),
+            client_tx,
+            client_id: 0,
+            client_rx,
+            database_file: PathBuf::from("/nonexistent.sdb"),
+            admin_gid: None,
+            // the test does not need to run as root
+            read_gid: Some(gid),
+            update_status: Arc::new(Mutex::new(DatabaseUpdateStatus::Idle)),
+        };
+        thread::spawn(move || {
+            server.listen();
+        });
+
+        // connects and closes without sending anything
+        drop(UnixStream::connect(&path).unwrap());
+
+        // the server still answers the next client
+        let stream = UnixStream::connect(&path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let request = CommandRequest {
+            command: Command::UpdateDatabaseStatus,
+        };
+        framing::write_message(&mut &stream, &request).unwrap();
+        let response: CommandResponse =
+            framing::read_message(&mut std::io::BufReader::new(&stream))
+                .unwrap()
+                .unwrap();
+        assert!(matches!(response.status, CommandStatus::Success));
+        assert!(matches!(
+            response.response,
+            Response::DatabaseUpdateStatus(DatabaseUpdateStatus::Idle)
+        ));
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -1,11 +1,17 @@
 use crate::quarantine::Quarantine;
 use linked_hash_map::LinkedHashMap;
 use log::{debug, info, warn};
-use simbiota_monitor::monitor::{EventMask, MonitorFlags};
+use simbiota_clientlib::client_config::{is_toml_path, toml_to_yaml};
+use simbiota_monitor::monitor::{
+    EventMask, MonitorFlags, DEFAULT_MSG_BUFFER_SIZE, DEFAULT_QUEUE_CAPACITY,
+};
 use std::any::Any;
 use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Duration;
+use yaml_rust::yaml::Hash;
 use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
 
 #[derive(Debug)]
@@ -21,6 +27,8 @@ pub struct MonitoredPath {
     pub(crate) event_on_children: bool,
     pub(crate) mark_filesystem: bool,
     pub(crate) mark_mount: bool,
+    /// Also mark every directory below `path` on the same filesystem
+    pub(crate) recursive: bool,
     pub(crate) mask: EventMask,
 }
 
@@ -38,6 +46,15 @@ pub(crate) struct SmtpConfig {
     pub(crate) username: String,
     pub(crate) password: Option<String>,
     pub(crate) security: SmtpConnectionSecurity,
+    pub(crate) timeout: Duration,
+    /// Accept invalid TLS certificates for servers using private CAs
+    pub(crate) accept_invalid_certs: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EmailFormat {
+    Text,
+    Html,
 }
 
 #[derive(Debug)]
@@ -45,47 +62,172 @@ pub(crate) struct EmailConfig {
     pub(crate) enabled: bool,
     pub(crate) smtp_config: Option<SmtpConfig>,
     pub(crate) recipients: Vec<String>,
+    pub(crate) format: EmailFormat,
+    /// Detections within this window are sent in a single email
+    pub(crate) batch_window: Duration,
+    pub(crate) max_per_hour: Option<usize>,
+}
+
+#[derive(Debug)]
+pub(crate) struct WebhookConfig {
+    pub(crate) enabled: bool,
+    pub(crate) url: String,
+    /// Extra request headers, e.g. for authorization
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) timeout: Duration,
 }
 
 #[derive(Debug)]
 pub struct MonitorConfig {
     pub(crate) flags: MonitorFlags,
     pub(crate) paths: Vec<MonitoredPath>,
+    /// Allow permission events if no verdict is reached in time, `None` waits forever
+    pub(crate) response_timeout: Option<Duration>,
+    /// Events waiting for a verdict, permission events beyond this are allowed unscanned
+    pub(crate) queue_size: usize,
+    /// Bytes of fanotify events read at once
+    pub(crate) event_buffer_size: usize,
+    /// Files under these paths are allowed without scanning
+    pub(crate) exclude: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CacheBackend {
+    Memory,
+    Disk,
 }
 
 #[derive(Debug)]
 pub struct CacheConfig {
     pub(crate) disable_cache: bool,
+    pub(crate) backend: CacheBackend,
+    /// Maximum number of entries in the memory cache
+    pub(crate) max_entries: Option<usize>,
+    /// File used by the disk cache backend
+    pub(crate) path: PathBuf,
 }
 
+const DEFAULT_DISK_CACHE_PATH: &str = "/var/lib/simbiota/detection_cache.json";
+const DEFAULT_CACHE_MAX_ENTRIES: i64 = 100_000;
+
 #[derive(Debug)]
 pub struct DatabaseConfig {
-    pub(crate) database_path: PathBuf,
+    pub(crate) database_paths: Vec<PathBuf>,
     pub(crate) low_memory: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum QuarantineEvictionPolicy {
+    EvictOldest,
+    Refuse,
+}
+
 #[derive(Debug)]
 pub(crate) struct QuarantineConfig {
     pub(crate) enabled: bool,
     pub(crate) path: PathBuf,
+    pub(crate) encrypt: bool,
+    pub(crate) passphrase: Option<String>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) max_entries: Option<usize>,
+    pub(crate) eviction: QuarantineEvictionPolicy,
+}
+
+#[derive(Debug)]
+pub(crate) struct EventLogConfig {
+    pub(crate) enabled: bool,
+    pub(crate) path: PathBuf,
+    /// The log is rotated once it is larger than this many bytes
+    pub(crate) max_size: u64,
 }
 
+const DEFAULT_EVENT_LOG_PATH: &str = "/var/lib/simbiota/events.log";
+const DEFAULT_EVENT_LOG_MAX_SIZE_MB: i64 = 10;
+
 #[derive(Debug)]
 pub struct DaemonConfig {
     pub(crate) monitor: MonitorConfig,
     pub(crate) email: EmailConfig,
+    pub(crate) webhook: WebhookConfig,
     pub(crate) cache: Option<CacheConfig>,
     pub(crate) raw_config: Yaml,
     pub(crate) quarantine: QuarantineConfig,
+    pub(crate) events: EventLogConfig,
+    /// File with SHA-256 hashes of files that are never reported
+    pub(crate) allowlist: Option<PathBuf>,
+    pub(crate) pid_file: PathBuf,
+    /// Drop every capability not needed after the fanotify setup
+    pub(crate) drop_capabilities: bool,
+    pub(crate) control: ControlConfig,
+    pub(crate) metrics: MetricsConfig,
+}
+
+#[derive(Debug)]
+pub(crate) struct ControlConfig {
+    /// `@name` for an abstract socket or a filesystem path
+    pub(crate) socket: String,
+    /// Members of this group may use the control socket besides root
+    pub(crate) admin_group: Option<String>,
+    /// Members of this group may only use the read-only commands
+    pub(crate) read_group: Option<String>,
+}
+
+const DEFAULT_PID_FILE: &str = "/var/run/simbiota.pid";
+
+#[derive(Debug)]
+pub(crate) struct MetricsConfig {
+    pub(crate) enabled: bool,
+    /// Address of the HTTP listener serving `/metrics`
+    pub(crate) bind: SocketAddr,
 }
 
+const DEFAULT_METRICS_BIND: &str = "127.0.0.1:9464";
+
+/// Top level sections, `logger` is read by the daemon before the rest of the config
+const KNOWN_TOP_LEVEL_KEYS: [&str; 14] = [
+    "detector",
+    "monitor",
+    "email",
+    "webhook",
+    "logger",
+    "cache",
+    "database",
+    "quarantine",
+    "events",
+    "allowlist",
+    "pid_file",
+    "drop_capabilities",
+    "control",
+    "metrics",
+];
+
 const DEFAULT_MONITOR_FLAGS: MonitorFlags = MonitorFlags::empty()
     .union(MonitorFlags::CLOEXEC)
     .union(MonitorFlags::UNLIMITED_MARKS)
     .union(MonitorFlags::UNLIMITED_QUEUE);
 
+/// Overrides `quarantine.path`
+const ENV_QUARANTINE_PATH: &str = "SIMBIOTA_QUARANTINE_PATH";
+
 impl DaemonConfig {
+    /// Load the config file, then apply the environment overrides
+    ///
+    /// Precedence: command line flags > environment > config file > defaults
     pub(crate) fn load_from(path: &Path, use_default: bool) -> Self {
+        let mut config = Self::load_file(path, use_default);
+        config.apply_env_overrides();
+        config
+    }
+
+    /// Values from set environment variables replace the ones read from the file
+    fn apply_env_overrides(&mut self) {
+        if let Some(path) = std::env::var_os(ENV_QUARANTINE_PATH) {
+            info!("quarantine path overridden by {}", ENV_QUARANTINE_PATH);
+            self.quarantine.path = PathBuf::from(path);
+        }
+    }
+
+    fn load_file(path: &Path, use_default: bool) -> Self {
         debug!("loading config from {}", path.display());
 
         if !path.exists() {
@@ -108,166 +250,512 @@ impl DaemonConfig {
             content
         };
 
+        if is_toml_path(path) {
+            return match toml::from_str::<toml::Value>(&config_content) {
+                Ok(value) => Self::from_yaml(vec![toml_to_yaml(&value)])
+                    .unwrap_or_else(|errors| Self::report_errors(path, errors)),
+                Err(e) => {
+                    warn!("failed to parse TOML config: {}", e);
+                    eprintln!("The specified config is not valid: {}", e);
+                    exit(1);
+                }
+            };
+        }
+
         let maybe_doc = YamlLoader::load_from_str(config_content.as_str());
         let Ok(doc) = maybe_doc else {
             warn!("failed to parse YAML config: {}", maybe_doc.unwrap_err().to_string());
             eprintln!("The specified config is not valid! Bailing out...");
             exit(1);
         };
-        Self::from_yaml(doc)
+        Self::from_yaml(doc).unwrap_or_else(|errors| Self::report_errors(path, errors))
+    }
+
+    fn report_errors(path: &Path, errors: ConfigErrors) -> ! {
+        warn!("invalid config {}:\n{}", path.display(), errors);
+        eprintln!(
+            "The config file {} is not valid:\n{}",
+            path.display(),
+            errors
+        );
+        exit(1);
     }
 
-    fn from_yaml(mut yaml: Vec<Yaml>) -> Self {
+    fn from_yaml(mut yaml: Vec<Yaml>) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        if yaml.is_empty() {
+            errors.push("config file is empty".to_string());
+            return Err(errors);
+        }
         let doc = yaml.remove(0);
+        let Some(root) = doc.as_hash() else {
+            errors.push("expected a mapping at the top level".to_string());
+            return Err(errors);
+        };
+        for key in root.keys() {
+            match key.as_str() {
+                Some(key) if KNOWN_TOP_LEVEL_KEYS.contains(&key) => {}
+                Some(key) => errors.push(format!("unknown key `{key}`")),
+                None => errors.push(format!("invalid key `{key:?}`, expected string")),
+            }
+        }
 
-        // TODO: Normal error handling instead of expect
         let mut mpaths = Vec::new();
-        let monitor_config = doc["monitor"]
-            .as_hash()
-            .expect("invalid monitor config, expected dict");
-
-        let flags = if monitor_config.contains_key(&Yaml::String("flags".to_string())) {
-            let flags = monitor_config[&Yaml::String("flags".to_string())]
-                .as_vec()
-                .expect("invalid flags value");
-            MonitorFlags::parse(flags.iter().map(|f| f.as_str().unwrap()).collect())
-                .expect("failed to parse flags")
-        } else {
-            DEFAULT_MONITOR_FLAGS
+        let empty_hash = Hash::new();
+        let monitor_config = match &doc["monitor"] {
+            Yaml::Hash(hash) => hash,
+            Yaml::BadValue => {
+                errors.missing("monitor");
+                &empty_hash
+            }
+            _ => {
+                errors.invalid("monitor", "a mapping");
+                &empty_hash
+            }
         };
 
-        let monitored_paths = monitor_config[&Yaml::String("paths".to_owned())]
-            .as_vec()
-            .expect("missing monitored paths array");
-
-        for monitored_path in monitored_paths {
-            let masks = monitored_path["mask"]
-                .as_vec()
-                .expect("monitored_path mask expected");
-            let mut mpath_masks = HashSet::new();
-            for mask in masks {
-                mpath_masks.insert(mask.as_str().expect("mask string expected"));
-            }
+        let flags = match monitor_config.get(&Yaml::from_str("flags")) {
+            Some(flags) => match errors.str_list("monitor.flags", flags) {
+                Some(flags) => match MonitorFlags::parse(flags) {
+                    Ok(flags) => flags,
+                    Err(e) => {
+                        errors.push(format!("`monitor.flags`: {e}"));
+                        DEFAULT_MONITOR_FLAGS
+                    }
+                },
+                None => DEFAULT_MONITOR_FLAGS,
+            },
+            None => DEFAULT_MONITOR_FLAGS,
+        };
 
-            let mpath = MonitoredPath {
-                path: PathBuf::from(
-                    monitored_path["path"]
-                        .as_str()
-                        .expect("expected path in monitored_path"),
-                ),
-                dir: monitored_path["dir"].as_bool().unwrap_or(false),
-                mark_mount: monitored_path["mount"].as_bool().unwrap_or(false),
-                mark_filesystem: monitored_path["filesystem"].as_bool().unwrap_or(false),
-                event_on_children: monitored_path["event_on_children"]
-                    .as_bool()
-                    .unwrap_or(false),
-                mask: EventMask::parse(mpath_masks.iter().copied().collect())
-                    .expect("failed to parse mark mask"),
-            };
+        let response_timeout = monitor_config
+            .get(&Yaml::from_str("response_timeout_ms"))
+            .and_then(|v| errors.int("monitor.response_timeout_ms", v))
+            .filter(|ms| *ms > 0)
+            .map(|ms| Duration::from_millis(ms as u64));
+
+        let queue_size = monitor_config
+            .get(&Yaml::from_str("queue_size"))
+            .and_then(|v| errors.int("monitor.queue_size", v))
+            .map(|size| size.max(1) as usize)
+            .unwrap_or(DEFAULT_QUEUE_CAPACITY);
 
-            mpaths.push(mpath);
+        let event_buffer_size = monitor_config
+            .get(&Yaml::from_str("event_buffer_size"))
+            .and_then(|v| errors.int("monitor.event_buffer_size", v))
+            .map(|size| size.max(0) as usize)
+            .unwrap_or(DEFAULT_MSG_BUFFER_SIZE);
+
+        let exclude = monitor_config
+            .get(&Yaml::from_str("exclude"))
+            .and_then(|v| errors.str_list("monitor.exclude", v))
+            .map(|paths| paths.into_iter().map(PathBuf::from).collect())
+            .unwrap_or_default();
+
+        match monitor_config.get(&Yaml::from_str("paths")) {
+            Some(Yaml::Array(monitored_paths)) => {
+                for (i, monitored_path) in monitored_paths.iter().enumerate() {
+                    let key = |name: &str| format!("monitor.paths[{i}].{name}");
+                    let mask = match &monitored_path["mask"] {
+                        Yaml::BadValue => {
+                            errors.missing(&key("mask"));
+                            None
+                        }
+                        masks => errors.str_list(&key("mask"), masks),
+                    };
+                    let mask = mask.and_then(|masks| {
+                        let masks: HashSet<&str> = masks.into_iter().collect();
+                        EventMask::parse(masks.into_iter().collect())
+                            .map_err(|e| errors.push(format!("`{}`: {e}", key("mask"))))
+                            .ok()
+                    });
+                    let path = errors.required_str(&key("path"), &monitored_path["path"]);
+                    let flag = |errors: &mut ConfigErrors, name: &str| {
+                        errors
+                            .optional_bool(&key(name), &monitored_path[name])
+                            .unwrap_or(false)
+                    };
+                    let dir = flag(&mut errors, "dir");
+                    let mark_mount = flag(&mut errors, "mount");
+                    let mark_filesystem = flag(&mut errors, "filesystem");
+                    let event_on_children = flag(&mut errors, "event_on_children");
+                    let recursive = flag(&mut errors, "recursive");
+                    if recursive && (mark_mount || mark_filesystem) {
+                        errors.push(format!(
+                            "`{}` cannot be combined with `mount` or `filesystem`",
+                            key("recursive")
+                        ));
+                    }
+
+                    if let (Some(path), Some(mask)) = (path, mask) {
+                        mpaths.push(MonitoredPath {
+                            path: PathBuf::from(path),
+                            dir,
+                            mark_mount,
+                            mark_filesystem,
+                            event_on_children,
+                            recursive,
+                            mask,
+                        });
+                    }
+                }
+            }
+            Some(_) => errors.invalid("monitor.paths", "a list"),
+            None => errors.missing("monitor.paths"),
         }
 
         // Load email config
         let email_cfg = doc["email"].as_hash();
+        let disabled_email = || EmailConfig {
+            enabled: false,
+            smtp_config: None,
+            recipients: Vec::new(),
+            format: EmailFormat::Text,
+            batch_window: Duration::ZERO,
+            max_per_hour: None,
+        };
         let email_config = if let Some(email_cfg_data) = email_cfg {
-            let enabled = email_cfg_data[&Yaml::from_str("enabled")]
-                .as_bool()
+            let enabled = errors
+                .optional_bool("email.enabled", &email_cfg_data[&Yaml::from_str("enabled")])
                 .unwrap_or(false);
-            let email_config = if enabled {
-                let smtp_config = email_cfg_data[&Yaml::from_str("smtp")]
-                    .as_hash()
-                    .expect("you must provide an smtp server if you want email alerts");
-                let smtp_server = smtp_config[&Yaml::from_str("server")]
-                    .as_str()
-                    .expect("smtp server missing");
-                let port = smtp_config[&Yaml::from_str("port")].as_i64().unwrap_or(587);
+            if enabled {
+                let smtp_config = match &email_cfg_data[&Yaml::from_str("smtp")] {
+                    Yaml::Hash(hash) => hash,
+                    Yaml::BadValue => {
+                        errors.missing("email.smtp");
+                        &empty_hash
+                    }
+                    _ => {
+                        errors.invalid("email.smtp", "a mapping");
+                        &empty_hash
+                    }
+                };
+                let smtp_server = errors
+                    .required_str("email.smtp.server", &smtp_config[&Yaml::from_str("server")]);
+                let port = smtp_config
+                    .get(&Yaml::from_str("port"))
+                    .and_then(|p| errors.int("email.smtp.port", p))
+                    .unwrap_or(587);
+                if !(1..=u16::MAX as i64).contains(&port) {
+                    errors.invalid("email.smtp.port", "a port number");
+                }
 
-                let username = smtp_config[&Yaml::from_str("username")]
-                    .as_str()
-                    .expect("smtp user missing");
-                let maybe_password = smtp_config[&Yaml::from_str("password")].as_str();
-                let security = smtp_config[&Yaml::from_str("security")]
+                let username = errors.required_str(
+                    "email.smtp.username",
+                    &smtp_config[&Yaml::from_str("username")],
+                );
+                let maybe_password = smtp_config
+                    .get(&Yaml::from_str("password"))
+                    .and_then(|p| errors.str("email.smtp.password", p));
+                let security = match smtp_config
+                    .get(&Yaml::from_str("security"))
+                    .and_then(|s| errors.str("email.smtp.security", s))
+                    .unwrap_or("none")
+                    .to_ascii_lowercase()
                     .as_str()
-                    .unwrap_or("none");
+                {
+                    "none" => SmtpConnectionSecurity::None,
+                    "ssl" => SmtpConnectionSecurity::Ssl,
+                    "tls" | "starttls" => SmtpConnectionSecurity::Starttls,
+                    s => {
+                        errors.push(format!("unknown smtp security `{s}`"));
+                        SmtpConnectionSecurity::None
+                    }
+                };
+                let timeout_secs = smtp_config
+                    .get(&Yaml::from_str("timeout_secs"))
+                    .and_then(|t| errors.int("email.smtp.timeout_secs", t))
+                    .unwrap_or(60);
+                let accept_invalid_certs = errors
+                    .optional_bool(
+                        "email.smtp.accept_invalid_certs",
+                        &smtp_config[&Yaml::from_str("accept_invalid_certs")],
+                    )
+                    .unwrap_or(false);
 
-                let recipients = email_cfg_data[&Yaml::from_str("recipients")]
-                    .as_vec()
-                    .expect("email recipients missing")
-                    .iter()
-                    .map(|y| y.as_str().unwrap().to_string())
-                    .collect();
+                let recipients = match &email_cfg_data[&Yaml::from_str("recipients")] {
+                    Yaml::BadValue => {
+                        errors.missing("email.recipients");
+                        Vec::new()
+                    }
+                    recipients => errors
+                        .str_list("email.recipients", recipients)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|r| r.to_string())
+                        .collect(),
+                };
+                let format = match email_cfg_data
+                    .get(&Yaml::from_str("format"))
+                    .and_then(|f| errors.str("email.format", f))
+                    .unwrap_or("text")
+                    .to_ascii_lowercase()
+                    .as_str()
+                {
+                    "text" => EmailFormat::Text,
+                    "html" => EmailFormat::Html,
+                    f => {
+                        errors.push(format!("unknown email format `{f}`"));
+                        EmailFormat::Text
+                    }
+                };
+                let batch_window_secs = email_cfg_data
+                    .get(&Yaml::from_str("batch_window_secs"))
+                    .and_then(|w| errors.int("email.batch_window_secs", w))
+                    .unwrap_or(0);
+                let max_per_hour = email_cfg_data
+                    .get(&Yaml::from_str("max_per_hour"))
+                    .and_then(|m| errors.int("email.max_per_hour", m))
+                    .filter(|m| *m > 0)
+                    .map(|m| m as usize);
 
                 EmailConfig {
                     enabled: true,
                     smtp_config: Some(SmtpConfig {
-                        server: smtp_server.to_string(),
+                        server: smtp_server.unwrap_or_default().to_string(),
                         port: port as u16,
-                        username: username.to_string(),
+                        username: username.unwrap_or_default().to_string(),
                         password: maybe_password.map(|s| s.to_string()),
-                        security: match security.to_ascii_lowercase().as_str() {
-                            "none" => SmtpConnectionSecurity::None,
-                            "ssl" => SmtpConnectionSecurity::Ssl,
-                            "tls" | "starttls" => SmtpConnectionSecurity::Starttls,
-                            _ => panic!("invalid smtp connection security"),
-                        },
+                        security,
+                        timeout: Duration::from_secs(timeout_secs.max(1) as u64),
+                        accept_invalid_certs,
                     }),
                     recipients,
+                    format,
+                    batch_window: Duration::from_secs(batch_window_secs.max(0) as u64),
+                    max_per_hour,
                 }
             } else {
-                EmailConfig {
-                    enabled: false,
-                    smtp_config: None,
-                    recipients: Vec::new(),
-                }
-            };
-            email_config
+                disabled_email()
+            }
         } else {
             info!("email config not found. alerts disabled");
-            EmailConfig {
-                enabled: false,
-                smtp_config: None,
-                recipients: Vec::new(),
+            disabled_email()
+        };
+
+        let webhook = &doc["webhook"];
+        let webhook_enabled = errors
+            .optional_bool("webhook.enabled", &webhook["enabled"])
+            .unwrap_or(false);
+        let webhook_url = if webhook_enabled {
+            errors.required_str("webhook.url", &webhook["url"])
+        } else {
+            webhook["url"].as_str()
+        };
+        let webhook_headers = match &webhook["headers"] {
+            Yaml::BadValue => Vec::new(),
+            Yaml::Hash(headers) => headers
+                .iter()
+                .filter_map(|(name, value)| match (name.as_str(), value.as_str()) {
+                    (Some(name), Some(value)) => Some((name.to_string(), value.to_string())),
+                    _ => {
+                        errors.invalid("webhook.headers", "string names and values");
+                        None
+                    }
+                })
+                .collect(),
+            _ => {
+                errors.invalid("webhook.headers", "a mapping");
+                Vec::new()
             }
         };
+        let webhook_timeout_secs = webhook
+            .as_hash()
+            .and_then(|w| w.get(&Yaml::from_str("timeout_secs")))
+            .and_then(|t| errors.int("webhook.timeout_secs", t))
+            .unwrap_or(10);
+        let webhook_config = WebhookConfig {
+            enabled: webhook_enabled,
+            url: webhook_url.unwrap_or_default().to_string(),
+            headers: webhook_headers,
+            timeout: Duration::from_secs(webhook_timeout_secs.max(1) as u64),
+        };
 
-        let detector_cfg = doc["detector"].as_hash().expect("detector config expected");
-        let class = detector_cfg[&Yaml::String("class".to_string())]
-            .as_str()
-            .expect("detector class expected");
-        let mut config = HashMap::new();
-        if let Some(detector_configs_y) = detector_cfg.get(&Yaml::String("config".to_string())) {
-            if let Some(detector_configs) = detector_configs_y.as_hash() {
-                for (key, val) in detector_configs.iter() {
-                    let key_string = key.as_str().unwrap().to_string();
-                    let value = DaemonConfig::yaml_to_any(val);
-                    config.insert(key_string, value);
+        // The detector section is read by the client config, it is only validated here
+        match &doc["detector"] {
+            Yaml::Hash(detector_cfg) => {
+                errors.required_str("detector.class", &detector_cfg[&Yaml::from_str("class")]);
+                if let Some(Yaml::Hash(detector_configs)) =
+                    detector_cfg.get(&Yaml::from_str("config"))
+                {
+                    for (key, val) in detector_configs.iter() {
+                        match key.as_str() {
+                            Some(key) if DaemonConfig::yaml_to_any(val).is_none() => {
+                                errors.invalid(&format!("detector.config.{key}"), "a value")
+                            }
+                            Some(_) => {}
+                            None => errors.invalid("detector.config", "string keys"),
+                        }
+                    }
+                }
+                for name in ["max_file_size_mb", "max_files_per_sec", "burst"] {
+                    if let Some(value) = detector_cfg.get(&Yaml::from_str(name)) {
+                        errors.int(&format!("detector.{name}"), value);
+                    }
+                }
+                let options: [(&str, &[&str]); 3] = [
+                    ("scan_only", &["all", "executable"]),
+                    ("mode", &["enforce", "observe"]),
+                    ("action", &["quarantine", "remove", "delete", "none"]),
+                ];
+                for (name, allowed) in options {
+                    let key = format!("detector.{name}");
+                    if let Some(value) = detector_cfg.get(&Yaml::from_str(name)) {
+                        match errors.str(&key, value) {
+                            Some(v) if !allowed.contains(&v) => {
+                                errors.push(format!("unknown {key} value `{v}`"))
+                            }
+                            _ => {}
+                        }
+                    }
                 }
             }
+            Yaml::BadValue => errors.missing("detector"),
+            _ => errors.invalid("detector", "a mapping"),
         }
 
-        let cache_disabled = {
-            if let Some(cache_cfg) = doc["cache"].as_hash() {
-                if let Some(disabled_v) = cache_cfg[&Yaml::String("disable".to_string())].as_bool()
-                {
-                    disabled_v
-                } else {
-                    false
-                }
-            } else {
-                false
+        let cache = &doc["cache"];
+        let cache_disabled = errors
+            .optional_bool("cache.disable", &cache["disable"])
+            .unwrap_or(false);
+
+        let cache_backend = match cache["backend"].as_str().unwrap_or("memory") {
+            "memory" => CacheBackend::Memory,
+            "disk" => CacheBackend::Disk,
+            s => {
+                errors.push(format!("unknown cache backend `{s}`"));
+                CacheBackend::Memory
             }
         };
+        // 0 means unlimited
+        let cache_max_entries = Some(
+            cache
+                .as_hash()
+                .and_then(|c| c.get(&Yaml::from_str("max_entries")))
+                .and_then(|m| errors.int("cache.max_entries", m))
+                .unwrap_or(DEFAULT_CACHE_MAX_ENTRIES),
+        )
+        .filter(|m| *m > 0)
+        .map(|m| m as usize);
+        let cache_path = PathBuf::from(cache["path"].as_str().unwrap_or(DEFAULT_DISK_CACHE_PATH));
+
+        let events = &doc["events"];
+        let events_config = EventLogConfig {
+            enabled: errors
+                .optional_bool("events.enabled", &events["enabled"])
+                .unwrap_or(true),
+            path: PathBuf::from(
+                events
+                    .as_hash()
+                    .and_then(|e| e.get(&Yaml::from_str("path")))
+                    .and_then(|p| errors.str("events.path", p))
+                    .unwrap_or(DEFAULT_EVENT_LOG_PATH),
+            ),
+            max_size: events
+                .as_hash()
+                .and_then(|e| e.get(&Yaml::from_str("max_size_mb")))
+                .and_then(|s| errors.int("events.max_size_mb", s))
+                .filter(|s| *s > 0)
+                .unwrap_or(DEFAULT_EVENT_LOG_MAX_SIZE_MB) as u64
+                * 1024
+                * 1024,
+        };
+
+        let allowlist = root
+            .get(&Yaml::from_str("allowlist"))
+            .and_then(|a| errors.str("allowlist", a))
+            .map(PathBuf::from);
+        let pid_file = PathBuf::from(
+            root.get(&Yaml::from_str("pid_file"))
+                .and_then(|p| errors.str("pid_file", p))
+                .unwrap_or(DEFAULT_PID_FILE),
+        );
+        let drop_capabilities = errors
+            .optional_bool("drop_capabilities", &doc["drop_capabilities"])
+            .unwrap_or(false);
+        let control = &doc["control"];
+        if !matches!(control, Yaml::Hash(_) | Yaml::BadValue) {
+            errors.invalid("control", "a mapping");
+        }
+        let control_config = ControlConfig {
+            socket: control
+                .as_hash()
+                .and_then(|c| c.get(&Yaml::from_str("socket")))
+                .and_then(|s| errors.str("control.socket", s))
+                .unwrap_or(simbiota_protocol::DEFAULT_SOCKET)
+                .to_string(),
+            admin_group: control
+                .as_hash()
+                .and_then(|c| c.get(&Yaml::from_str("admin_group")))
+                .and_then(|g| errors.str("control.admin_group", g))
+                .map(str::to_string),
+            read_group: control
+                .as_hash()
+                .and_then(|c| c.get(&Yaml::from_str("read_group")))
+                .and_then(|g| errors.str("control.read_group", g))
+                .map(str::to_string),
+        };
+
+        let metrics = &doc["metrics"];
+        if !matches!(metrics, Yaml::Hash(_) | Yaml::BadValue) {
+            errors.invalid("metrics", "a mapping");
+        }
+        let metrics_bind = metrics
+            .as_hash()
+            .and_then(|m| m.get(&Yaml::from_str("bind")))
+            .and_then(|b| errors.str("metrics.bind", b))
+            .unwrap_or(DEFAULT_METRICS_BIND);
+        let metrics_config = MetricsConfig {
+            enabled: errors
+                .optional_bool("metrics.enabled", &metrics["enabled"])
+                .unwrap_or(false),
+            bind: metrics_bind.parse().unwrap_or_else(|_| {
+                errors.invalid("metrics.bind", "an address like 127.0.0.1:9464");
+                DEFAULT_METRICS_BIND.parse().unwrap()
+            }),
+        };
 
         // Load database config
-        let database_cfg = doc["database"].as_hash().expect("database config expected");
-        let path = database_cfg[&Yaml::String("database_file".to_owned())]
-            .as_str()
-            .expect("database file config missing");
+        let (database_paths, low_memory) = match &doc["database"] {
+            Yaml::Hash(database_cfg) => (
+                match &database_cfg[&Yaml::from_str("database_file")] {
+                    files @ Yaml::Array(_) => {
+                        match errors.str_list("database.database_file", files) {
+                            Some(files) if files.is_empty() => {
+                                errors.invalid("database.database_file", "at least one file");
+                                None
+                            }
+                            files => files,
+                        }
+                    }
+                    file => errors
+                        .required_str("database.database_file", file)
+                        .map(|file| vec![file]),
+                },
+                errors
+                    .optional_bool(
+                        "database.low_memory",
+                        &database_cfg[&Yaml::from_str("low_memory")],
+                    )
+                    .unwrap_or(false),
+            ),
+            Yaml::BadValue => {
+                errors.missing("database.database_file");
+                (None, false)
+            }
+            _ => {
+                errors.invalid("database", "a mapping");
+                (None, false)
+            }
+        };
 
         let database_config = DatabaseConfig {
-            database_path: PathBuf::from(path),
-            low_memory: false,
+            database_paths: database_paths
+                .unwrap_or_default()
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            low_memory,
         };
 
         if cache_disabled {
@@ -276,41 +764,113 @@ impl DaemonConfig {
 
         let quarantine_cfg = doc["quarantine"].as_hash();
         let quarantine_config = if let Some(quarantine_cfg) = quarantine_cfg {
-            let enabled = quarantine_cfg[&Yaml::String("enabled".to_string())]
-                .as_bool()
+            let enabled = errors
+                .optional_bool(
+                    "quarantine.enabled",
+                    &quarantine_cfg[&Yaml::from_str("enabled")],
+                )
                 .unwrap_or(false);
             let path = if enabled {
                 PathBuf::from(
-                    quarantine_cfg[&Yaml::String("path".to_string())]
-                        .as_str()
-                        .expect("quarantine path expected"),
+                    errors
+                        .required_str("quarantine.path", &quarantine_cfg[&Yaml::from_str("path")])
+                        .unwrap_or_default(),
                 )
             } else {
                 Default::default()
             };
-            QuarantineConfig { enabled, path }
+            let encrypt = errors
+                .optional_bool(
+                    "quarantine.encrypt",
+                    &quarantine_cfg[&Yaml::from_str("encrypt")],
+                )
+                .unwrap_or(false);
+            let passphrase = quarantine_cfg
+                .get(&Yaml::from_str("passphrase"))
+                .and_then(|p| errors.str("quarantine.passphrase", p))
+                .map(|p| p.to_string());
+            if enabled && encrypt && passphrase.is_none() {
+                errors.push("quarantine encryption requires `quarantine.passphrase`".to_string());
+            }
+            // 0 or missing means unlimited
+            let max_size = quarantine_cfg
+                .get(&Yaml::from_str("max_size_mb"))
+                .and_then(|s| errors.int("quarantine.max_size_mb", s))
+                .filter(|s| *s > 0)
+                .map(|s| s as u64 * 1024 * 1024);
+            let max_entries = quarantine_cfg
+                .get(&Yaml::from_str("max_entries"))
+                .and_then(|e| errors.int("quarantine.max_entries", e))
+                .filter(|e| *e > 0)
+                .map(|e| e as usize);
+            let eviction = match quarantine_cfg
+                .get(&Yaml::from_str("eviction"))
+                .and_then(|e| errors.str("quarantine.eviction", e))
+                .unwrap_or("oldest")
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "oldest" => QuarantineEvictionPolicy::EvictOldest,
+                "refuse" => QuarantineEvictionPolicy::Refuse,
+                e => {
+                    errors.push(format!("unknown quarantine eviction policy `{e}`"));
+                    QuarantineEvictionPolicy::EvictOldest
+                }
+            };
+            QuarantineConfig {
+                enabled,
+                path,
+                encrypt,
+                passphrase,
+                max_size,
+                max_entries,
+                eviction,
+            }
         } else {
             QuarantineConfig {
                 enabled: false,
                 path: Default::default(),
+                encrypt: false,
+                passphrase: None,
+                max_size: None,
+                max_entries: None,
+                eviction: QuarantineEvictionPolicy::EvictOldest,
             }
         };
 
-        Self {
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Self {
             monitor: MonitorConfig {
                 flags,
                 paths: mpaths,
+                response_timeout,
+                queue_size,
+                event_buffer_size,
+                exclude,
             },
             email: email_config,
+            webhook: webhook_config,
             cache: Some(CacheConfig {
                 disable_cache: cache_disabled,
+                backend: cache_backend,
+                max_entries: cache_max_entries,
+                path: cache_path,
             }),
             quarantine: quarantine_config,
+            events: events_config,
+            allowlist,
+            pid_file,
+            drop_capabilities,
+            control: control_config,
+            metrics: metrics_config,
             raw_config: doc,
-        }
+        })
     }
 
-    fn yaml_to_any(yaml: &Yaml) -> Box<dyn Any> {
+    fn yaml_to_any(yaml: &Yaml) -> Option<Box<dyn Any>> {
         let value: Box<dyn Any> = match yaml {
             Yaml::Real(v) => Box::new(v.clone()),
             Yaml::Integer(v) => Box::new(*v),
@@ -319,22 +879,103 @@ impl DaemonConfig {
             Yaml::Array(v) => {
                 let mut vec = Vec::new();
                 for element in v {
-                    vec.push(DaemonConfig::yaml_to_any(element));
+                    vec.push(DaemonConfig::yaml_to_any(element)?);
                 }
                 Box::new(vec)
             }
             Yaml::Hash(v) => {
                 let mut map = HashMap::new();
                 for (key, val) in v {
-                    let key = key.as_str().unwrap().to_string();
-                    let val = DaemonConfig::yaml_to_any(val);
+                    let key = match key {
+                        Yaml::Integer(i) => i.to_string(),
+                        key => key.as_str()?.to_string(),
+                    };
+                    let val = DaemonConfig::yaml_to_any(val)?;
                     map.insert(key, val);
                 }
                 Box::new(map)
             }
-            _ => panic!("invalid config"),
+            _ => return None,
         };
-        value
+        Some(value)
+    }
+}
+
+/// Problems found while reading the config, collected so they can be reported together
+#[derive(Debug, Default)]
+pub(crate) struct ConfigErrors(Vec<String>);
+
+impl ConfigErrors {
+    fn push(&mut self, error: String) {
+        self.0.push(error);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn missing(&mut self, key: &str) {
+        self.push(format!("missing required key `{key}`"));
+    }
+
+    fn invalid(&mut self, key: &str, expected: &str) {
+        self.push(format!("invalid value for `{key}`, expected {expected}"));
+    }
+
+    fn str<'a>(&mut self, key: &str, value: &'a Yaml) -> Option<&'a str> {
+        let s = value.as_str();
+        if s.is_none() {
+            self.invalid(key, "a string");
+        }
+        s
+    }
+
+    fn required_str<'a>(&mut self, key: &str, value: &'a Yaml) -> Option<&'a str> {
+        match value {
+            Yaml::BadValue => {
+                self.missing(key);
+                None
+            }
+            value => self.str(key, value),
+        }
+    }
+
+    fn int(&mut self, key: &str, value: &Yaml) -> Option<i64> {
+        let i = value.as_i64();
+        if i.is_none() {
+            self.invalid(key, "an integer");
+        }
+        i
+    }
+
+    fn optional_bool(&mut self, key: &str, value: &Yaml) -> Option<bool> {
+        match value {
+            Yaml::BadValue => None,
+            Yaml::Boolean(b) => Some(*b),
+            _ => {
+                self.invalid(key, "true or false");
+                None
+            }
+        }
+    }
+
+    fn str_list<'a>(&mut self, key: &str, value: &'a Yaml) -> Option<Vec<&'a str>> {
+        let list = value
+            .as_vec()
+            .and_then(|items| items.iter().map(|item| item.as_str()).collect());
+        if list.is_none() {
+            self.invalid(key, "a list of strings");
+        }
+        list
+    }
+}
+
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for error in &self.0 {
+            writeln!(f, "  - {error}")?;
+        }
+        Ok(())
     }
 }
 
@@ -349,20 +990,81 @@ impl Default for DaemonConfig {
                     mark_mount: false,
                     mark_filesystem: false,
                     event_on_children: true,
+                    recursive: false,
                     mask: EventMask::OPEN_EXEC_PERM,
                 }],
+                response_timeout: None,
+                queue_size: DEFAULT_QUEUE_CAPACITY,
+                event_buffer_size: DEFAULT_MSG_BUFFER_SIZE,
+                exclude: Vec::new(),
             },
             email: EmailConfig {
                 enabled: false,
                 smtp_config: None,
                 recipients: Vec::new(),
+                format: EmailFormat::Text,
+                batch_window: Duration::ZERO,
+                max_per_hour: None,
+            },
+            webhook: WebhookConfig {
+                enabled: false,
+                url: String::new(),
+                headers: Vec::new(),
+                timeout: Duration::from_secs(10),
             },
             quarantine: QuarantineConfig {
                 enabled: true,
                 path: PathBuf::from("/var/lib/simbiota/quarantine"),
+                encrypt: false,
+                passphrase: None,
+                max_size: None,
+                max_entries: None,
+                eviction: QuarantineEvictionPolicy::EvictOldest,
             },
             cache: None,
+            events: EventLogConfig {
+                enabled: true,
+                path: PathBuf::from(DEFAULT_EVENT_LOG_PATH),
+                max_size: DEFAULT_EVENT_LOG_MAX_SIZE_MB as u64 * 1024 * 1024,
+            },
+            allowlist: None,
+            pid_file: PathBuf::from(DEFAULT_PID_FILE),
+            drop_capabilities: false,
+            control: ControlConfig {
+                socket: simbiota_protocol::DEFAULT_SOCKET.to_string(),
+                admin_group: None,
+                read_group: None,
+            },
+            metrics: MetricsConfig {
+                enabled: false,
+                bind: DEFAULT_METRICS_BIND.parse().unwrap(),
+            },
             raw_config: Yaml::Null,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the only test touching ENV_QUARANTINE_PATH, the environment is shared by every test
+    #[test]
+    fn env_overrides_quarantine_path_from_file() {
+        let config_path = Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../package/common/etc/simbiota/client.yaml"
+        ));
+
+        std::env::set_var(ENV_QUARANTINE_PATH, "/env/quarantine");
+        let overridden = DaemonConfig::load_from(config_path, false);
+        std::env::remove_var(ENV_QUARANTINE_PATH);
+        let from_file = DaemonConfig::load_from(config_path, false);
+
+        assert_eq!(overridden.quarantine.path, PathBuf::from("/env/quarantine"));
+        assert_eq!(
+            from_file.quarantine.path,
+            PathBuf::from("/var/lib/simbiota/quarantine")
+        );
+    }
+}
@@ -9,24 +9,37 @@ use crossbeam_channel::{Receiver, RecvError, Sender};
 use log::{debug, error, info, log, trace, warn};
 use once_cell::sync::Lazy;
 use simbiota_database::Database;
+use std::mem::ManuallyDrop;
 use std::ops::Deref;
-use std::os::fd::FromRawFd;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::time::Instant;
 use std::{process, thread};
 
 use simbiota_clientlib::api::cache::{DetectionCache, NoopCache};
-use simbiota_clientlib::api::detector::DetectionResult::Match;
 use simbiota_clientlib::api::detector::{DetectionResult, Detector};
-use simbiota_clientlib::client_config::ClientConfig;
+use simbiota_clientlib::client_config::{ClientConfig, DetectorMode, FileAction, ScanFilter};
 use simbiota_clientlib::detector::DetectorProvider;
+use simbiota_clientlib::rate_limit::RateLimiter;
 use simbiota_clientlib::system_database::SystemDatabase;
-use simbiota_monitor::monitor::{fanotify_event_metadata, FilesystemMonitor};
+use simbiota_monitor::monitor::{
+    fanotify_event_metadata, EventMask, FanotifyMarkError, FilesystemMonitor, MarkFlags,
+};
 use simbiota_monitor::FanotifyEventResponse;
 use simbiota_monitor::FanotifyEventResponse::{Allow, Deny};
-
-use crate::daemon_config::DaemonConfig;
+use simbiota_protocol::{
+    DaemonStats, DetectionAction, DetectionEvent, QuarantineBatchFailure, QuarantineBatchResult,
+    QuarantineStats,
+};
+
+use crate::allowlist::Allowlist;
+use crate::daemon_config::{CacheBackend, DaemonConfig};
+use crate::disk_detection_cache::DiskDetectionCache;
+use crate::event_log::EventLog;
 use crate::memory_detection_cache::MemoryDetectionCache;
 use crate::quarantine::{Quarantine, QuarantineEntryInfo};
 
@@ -41,12 +54,47 @@ pub struct DetectionSystem {
     detector_rx: Receiver<DetectorCommand>,
     client_tx: Sender<DetectorCommand>,
     next_detector_id: RefCell<usize>,
-    daemon_pid: u32,
+    counters: Arc<DetectionCounters>,
+    started: Instant,
+    exclude_paths: Vec<PathBuf>,
+    max_file_size: Option<u64>,
+    scan_only: ScanFilter,
+    allowlist: Option<RefCell<Allowlist>>,
+    /// Limits the scans, files answered from the cache are not counted
+    rate_limiter: Option<RefCell<RateLimiter>>,
+    detector_class: String,
+    events: Option<Arc<EventLog>>,
+    /// Report detections without denying access or quarantining
+    observe: bool,
+    /// What happens to detected files when not observing
+    file_action: FileAction,
+}
+
+/// Runtime statistics, also read by the metrics endpoint
+#[derive(Default)]
+pub(crate) struct DetectionCounters {
+    pub(crate) files_scanned: AtomicU64,
+    pub(crate) cache_hits: AtomicU64,
+    pub(crate) cache_misses: AtomicU64,
+    pub(crate) detections: AtomicU64,
+    /// Files checked by the detector, cache hits and skipped files are not counted
+    pub(crate) scans: AtomicU64,
+    /// Total time the detector spent on `scans`
+    pub(crate) scan_time_micros: AtomicU64,
 }
 
+#[derive(Clone)]
 pub struct DetectionDetails {
     pub path: String,
     pub time: chrono::DateTime<Utc>,
+    /// Class of the detector that reported the file
+    pub detector: String,
+    pub action: DetectionAction,
+    pub quarantine_id: Option<String>,
+    /// Identifier of the matched database entry, if the detector reports it
+    pub signature: Option<usize>,
+    /// Comparison value of the match, the TLSH distance for TLSH detectors
+    pub distance: Option<i64>,
 }
 
 static REGISTERED_PROVIDERS: Lazy<Mutex<HashMap<String, Arc<dyn DetectorProvider + Send + Sync>>>> =
@@ -61,16 +109,36 @@ pub struct DetectorCommand {
     pub command: Action,
 }
 pub enum Action {
-    FanotifyEvent(fanotify_event_metadata),
+    /// Notification event, the fd of the event is closed when the action is dropped
+    FanotifyEvent(fanotify_event_metadata, OwnedFd),
     FanotifyEventWithResponse(fanotify_event_metadata),
     QueryQuarantine,
     RestoreQuarantineEntry(String),
+    /// Entry id or path, destination directory, whether to overwrite an existing file
+    RestoreQuarantineEntryTo(String, PathBuf, bool),
     DeleteQuarantineEntry(String),
+    RestoreAllQuarantine,
+    DeleteAllQuarantine,
+    QueryQuarantineStats,
+    ExportQuarantine(PathBuf),
+    QueryStats,
+    QueryEvents(usize),
+    /// The detection database was reloaded, cached results are outdated
+    DatabaseReloaded,
+    AddMonitorPath(PathBuf, MarkFlags, EventMask),
+    RemoveMonitorPath(PathBuf),
 }
 pub enum CommandResult {
     FanotifyResponse(FanotifyEventResponse),
     QuarantineEntries(Vec<QuarantineEntryInfo>),
     QuarantineAction(bool),
+    QuarantineStats(QuarantineStats),
+    /// Number of exported entries
+    QuarantineExport(Result<usize, String>),
+    QuarantineBatch(Result<QuarantineBatchResult, String>),
+    Stats(DaemonStats),
+    Events(Vec<DetectionEvent>),
+    MonitorAction(Result<(), FanotifyMarkError>),
 }
 
 impl DetectionSystem {
@@ -92,10 +160,20 @@ impl DetectionSystem {
         daemon_config: Arc<DaemonConfig>,
         database: Arc<Mutex<SystemDatabase>>,
         verbose_log: bool,
+        observe: bool,
     ) -> Self {
         let detector_config = &client_config.detector;
         let class = &detector_config.class;
 
+        let observe = observe || detector_config.mode == DetectorMode::Observe;
+        if observe {
+            warn!(
+                "observe mode: detections are only reported, files are NOT blocked or quarantined"
+            );
+        } else if detector_config.action == FileAction::Remove {
+            warn!("detected files are deleted, this cannot be undone");
+        }
+
         let map = REGISTERED_PROVIDERS.lock().unwrap();
         let provider = map.get(class).expect("invalid detector class");
         let detector = provider.get_detector(&detector_config.config, database);
@@ -107,9 +185,51 @@ impl DetectionSystem {
             if is_cache_disabled(daemon_config.as_ref()) {
                 RefCell::from(Box::new(NoopCache {}))
             } else {
-                RefCell::from(Box::new(MemoryDetectionCache::new()))
+                match &daemon_config.cache {
+                    Some(cache_config) if cache_config.backend == CacheBackend::Disk => {
+                        RefCell::from(Box::new(DiskDetectionCache::new(
+                            &cache_config.path,
+                            cache_config.max_entries,
+                            client_config
+                                .database
+                                .paths()
+                                .map(Path::to_path_buf)
+                                .collect(),
+                        )))
+                    }
+                    Some(cache_config) => RefCell::from(Box::new(MemoryDetectionCache::new(
+                        cache_config.max_entries,
+                    ))),
+                    None => RefCell::from(Box::new(MemoryDetectionCache::new(None))),
+                }
             };
 
+        let mut exclude_paths = daemon_config.monitor.exclude.clone();
+        // Quarantined files are known to be malicious and only the daemon reads them, scanning
+        // them would report every entry again when a mark also covers the quarantine
+        if daemon_config.quarantine.enabled {
+            let quarantine_path = &daemon_config.quarantine.path;
+            // event paths are resolved by the kernel, so symlinks in the config would not match
+            let quarantine_path =
+                std::fs::canonicalize(quarantine_path).unwrap_or(quarantine_path.clone());
+            debug!(
+                "excluding quarantine directory from scanning: {}",
+                quarantine_path.display()
+            );
+            exclude_paths.push(quarantine_path);
+        }
+        let allowlist = daemon_config
+            .allowlist
+            .as_deref()
+            .map(|path| RefCell::new(Allowlist::new(path)));
+
+        let events = daemon_config.events.enabled.then(|| {
+            Arc::new(EventLog::new(
+                &daemon_config.events.path,
+                daemon_config.events.max_size,
+            ))
+        });
+
         // Quarantine setup
         let quarantine = if daemon_config.quarantine.enabled {
             let quarantine = Quarantine::new(daemon_config);
@@ -128,10 +248,35 @@ impl DetectionSystem {
             client_tx,
             detector_rx,
             next_detector_id: RefCell::new(0),
-            daemon_pid: std::process::id(),
+            counters: Arc::new(DetectionCounters::default()),
+            started: Instant::now(),
+            exclude_paths,
+            max_file_size: detector_config.max_file_size,
+            scan_only: detector_config.scan_only,
+            allowlist,
+            rate_limiter: detector_config
+                .max_files_per_sec
+                .map(|rate| RefCell::new(RateLimiter::new(rate, detector_config.burst))),
+            detector_class: class.clone(),
+            events,
+            observe,
+            file_action: detector_config.action,
         }
     }
 
+    pub fn monitor(&self) -> Arc<FilesystemMonitor> {
+        self.monitor.clone()
+    }
+
+    pub(crate) fn counters(&self) -> Arc<DetectionCounters> {
+        self.counters.clone()
+    }
+
+    /// When the detection system was created, the start of the uptime
+    pub(crate) fn started(&self) -> Instant {
+        self.started
+    }
+
     pub fn com_pair(&self) -> (usize, Receiver<CommandResult>, Sender<DetectorCommand>) {
         let (caller_tx, detector_rx) = crossbeam_channel::unbounded();
 
@@ -153,36 +298,51 @@ impl DetectionSystem {
             debug!("monitor thread id: {:?}", process::id());
             let client = client_tx.clone();
             let client2 = client_tx.clone();
+            // without a detector nothing can be scanned, the monitor is closed so the
+            // kernel allows the pending events and stops delivering new ones
+            let event_monitor = monitor.clone();
+            let response_monitor = monitor.clone();
             monitor.start(
-                Arc::new(move |e| {
-                    client2
-                        .send(DetectorCommand {
-                            id: monitor_id,
-                            command: Action::FanotifyEvent(*e),
-                        })
-                        .unwrap();
+                Arc::new(move |e: &fanotify_event_metadata, fd: OwnedFd| {
+                    // a failed send drops the action and closes the fd
+                    let sent = client2.send(DetectorCommand {
+                        id: monitor_id,
+                        command: Action::FanotifyEvent(*e, fd),
+                    });
+                    if let Err(err) = sent {
+                        error!(
+                            "failed to send event to detector, stopping monitor: {}",
+                            err
+                        );
+                        event_monitor.close();
+                    }
                 }),
                 Arc::new(move |e: &fanotify_event_metadata| {
-                    //eprintln!("sending detector command");
-                    client
-                        .send(DetectorCommand {
-                            id: monitor_id,
-                            command: Action::FanotifyEventWithResponse(*e),
-                        })
-                        .unwrap();
-                    //eprintln!("waiting for result");
+                    let sent = client.send(DetectorCommand {
+                        id: monitor_id,
+                        command: Action::FanotifyEventWithResponse(*e),
+                    });
+                    if let Err(err) = sent {
+                        error!(
+                            "failed to send event to detector, stopping monitor: {}",
+                            err
+                        );
+                        response_monitor.close();
+                        return Allow;
+                    }
                     match client_rx.recv() {
-                        Ok(response) => {
-                            if let CommandResult::FanotifyResponse(response) = response {
-                                //eprintln!("got result");
-                                response
-                            } else {
-                                panic!("invalid response from detector")
-                            }
+                        Ok(CommandResult::FanotifyResponse(response)) => response,
+                        Ok(_) => {
+                            error!("invalid response from detector, allowing access");
+                            Allow
                         }
-
                         Err(err) => {
-                            panic!("error receiving response from detector: {}", err);
+                            error!(
+                                "error receiving response from detector, stopping monitor: {}",
+                                err
+                            );
+                            response_monitor.close();
+                            Allow
                         }
                     }
                 }),
@@ -194,8 +354,10 @@ impl DetectionSystem {
             let req: Result<DetectorCommand, RecvError> = self.detector_rx.recv();
             match req {
                 Ok(cmd) => match cmd.command {
-                    Action::FanotifyEvent(e) => {
+                    Action::FanotifyEvent(e, fd) => {
                         self.detector_callback(&e);
+                        // e.fd is only valid until here
+                        drop(fd);
                     }
                     Action::FanotifyEventWithResponse(e) => {
                         let response = self.detector_callback(&e);
@@ -238,13 +400,19 @@ impl DetectionSystem {
                             };
 
                             if let Some(entry) = entry {
-                                quarantine.restore_entry(entry);
+                                let restored = match quarantine.restore_entry(entry) {
+                                    Ok(()) => true,
+                                    Err(e) => {
+                                        error!("failed to restore quarantine entry: {e}");
+                                        false
+                                    }
+                                };
                                 let _ = self
                                     .channels
                                     .borrow()
                                     .get(&cmd.id)
                                     .unwrap()
-                                    .send(CommandResult::QuarantineAction(true));
+                                    .send(CommandResult::QuarantineAction(restored));
                             } else {
                                 let _ = self
                                     .channels
@@ -263,6 +431,34 @@ impl DetectionSystem {
                                 .send(CommandResult::QuarantineAction(false));
                         }
                     },
+                    Action::RestoreQuarantineEntryTo(e, dest, force) => {
+                        let restored = match &self.quarantine {
+                            Some(quarantine) => {
+                                let mut quarantine = quarantine.lock().unwrap();
+                                let entry = match e.parse::<usize>() {
+                                    Ok(id) => quarantine.get_entry_by_id(id),
+                                    Err(_) => quarantine.get_entry_by_name(&e),
+                                };
+                                match entry
+                                    .map(|entry| quarantine.restore_entry_to(entry, &dest, force))
+                                {
+                                    Some(Ok(())) => true,
+                                    Some(Err(e)) => {
+                                        error!("failed to restore quarantine entry: {e}");
+                                        false
+                                    }
+                                    None => false,
+                                }
+                            }
+                            None => false,
+                        };
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::QuarantineAction(restored));
+                    }
                     Action::DeleteQuarantineEntry(e) => match &self.quarantine {
                         Some(quarantine) => {
                             let mut quarantine = quarantine.lock().unwrap();
@@ -299,6 +495,135 @@ impl DetectionSystem {
                                 .send(CommandResult::QuarantineAction(false));
                         }
                     },
+                    Action::RestoreAllQuarantine => {
+                        let result = self.quarantine_batch("restore", Quarantine::restore_all);
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::QuarantineBatch(result));
+                    }
+                    Action::DeleteAllQuarantine => {
+                        let result = self.quarantine_batch("delete", Quarantine::remove_all);
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::QuarantineBatch(result));
+                    }
+                    Action::ExportQuarantine(dest) => {
+                        let result = match &self.quarantine {
+                            Some(quarantine) => quarantine
+                                .lock()
+                                .unwrap()
+                                .export(&dest)
+                                .map_err(|e| e.to_string()),
+                            None => Err("quarantine is disabled".to_string()),
+                        };
+                        if let Err(e) = &result {
+                            error!("failed to export quarantine: {e}");
+                        }
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::QuarantineExport(result));
+                    }
+                    Action::QueryQuarantineStats => {
+                        let stats = match &self.quarantine {
+                            Some(quarantine) => {
+                                let quarantine = quarantine.lock().unwrap();
+                                let (entries, total_size) = quarantine.get_stats();
+                                QuarantineStats {
+                                    entries,
+                                    total_size,
+                                    max_entries: quarantine.max_entries(),
+                                    max_size: quarantine.max_size(),
+                                }
+                            }
+                            None => QuarantineStats {
+                                entries: 0,
+                                total_size: 0,
+                                max_entries: None,
+                                max_size: None,
+                            },
+                        };
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::QuarantineStats(stats));
+                    }
+                    Action::AddMonitorPath(path, flags, mask) => {
+                        let result = self.monitor.mark_path(&path, flags, mask);
+                        match &result {
+                            Ok(()) => info!("marked path for monitoring: {}", path.display()),
+                            Err(e) => warn!("failed to mark {}: {:?}", path.display(), e),
+                        }
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::MonitorAction(result));
+                    }
+                    Action::RemoveMonitorPath(path) => {
+                        let result = self.monitor.unmark_path(&path);
+                        match &result {
+                            Ok(()) => info!("removed monitored path: {}", path.display()),
+                            Err(e) => warn!("failed to unmark {}: {:?}", path.display(), e),
+                        }
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::MonitorAction(result));
+                    }
+                    Action::DatabaseReloaded => {
+                        info!("database reloaded, clearing detection cache");
+                        self.cache.borrow_mut().clear();
+                        if let Some(allowlist) = &self.allowlist {
+                            allowlist.borrow_mut().reload();
+                        }
+                    }
+                    Action::QueryStats => {
+                        let quarantine_entries = match &self.quarantine {
+                            Some(quarantine) => quarantine.lock().unwrap().get_stats().0,
+                            None => 0,
+                        };
+                        let stats = DaemonStats {
+                            files_scanned: self.counters.files_scanned.load(Ordering::Relaxed),
+                            cache_hits: self.counters.cache_hits.load(Ordering::Relaxed),
+                            cache_misses: self.counters.cache_misses.load(Ordering::Relaxed),
+                            detections: self.counters.detections.load(Ordering::Relaxed),
+                            quarantine_entries,
+                            database_hashes: self.detector.borrow_mut().hash_count(),
+                            uptime_secs: self.started.elapsed().as_secs(),
+                        };
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::Stats(stats));
+                    }
+                    Action::QueryEvents(limit) => {
+                        let events = match &self.events {
+                            Some(events) => events.recent(limit),
+                            None => Vec::new(),
+                        };
+                        let _ = self
+                            .channels
+                            .borrow()
+                            .get(&cmd.id)
+                            .unwrap()
+                            .send(CommandResult::Events(events));
+                    }
                 },
                 Err(e) => {
                     error!("error receiving command for detector: {}", e);
@@ -307,56 +632,143 @@ impl DetectionSystem {
         }
     }
 
+    /// Apply `op` to every quarantine entry, failed entries are logged
+    fn quarantine_batch(
+        &self,
+        name: &str,
+        op: impl FnOnce(&mut Quarantine) -> (usize, Vec<(String, String)>),
+    ) -> Result<QuarantineBatchResult, String> {
+        let Some(quarantine) = &self.quarantine else {
+            return Err("quarantine is disabled".to_string());
+        };
+        let (succeeded, failed) = op(&mut *quarantine.lock().unwrap());
+        for (path, e) in &failed {
+            error!("failed to {} quarantine entry {}: {}", name, path, e);
+        }
+        info!(
+            "{} quarantine entries: {} succeeded, {} failed",
+            name,
+            succeeded,
+            failed.len()
+        );
+        Ok(QuarantineBatchResult {
+            succeeded,
+            failed: failed
+                .into_iter()
+                .map(|(path, error)| QuarantineBatchFailure { path, error })
+                .collect(),
+        })
+    }
+
     pub(crate) fn add_positive_action(&mut self, callback: Box<dyn Fn(&DetectionDetails) + Send>) {
         self.positive_detection_action
             .push(Arc::new(Mutex::new(callback)));
     }
 
     // This cannot make file accesses otherwise it will block itself
-    fn detector_callback(&self, event_meta: &fanotify_event_metadata) -> FanotifyEventResponse {
-        if event_meta.pid as u32 == self.daemon_pid {
-            // ignore accesses from myself
-            debug!("ignoring access from myself");
-            return FanotifyEventResponse::Allow;
-        }
+    /// Whether the path is inside one of the excluded directories
+    fn is_excluded(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        self.exclude_paths
+            .iter()
+            .any(|excluded| path.starts_with(excluded))
+    }
 
+    // Accesses of the daemon itself are allowed by the monitor and never reach this callback
+    fn detector_callback(&self, event_meta: &fanotify_event_metadata) -> FanotifyEventResponse {
         let detect_start_ts = Instant::now();
         /// SAFETY: If fanotify does not return a valid filedescriptor, we have bigger
-        /// problems than invalid handles in rust
-        let mut file = unsafe { File::from_raw_fd(event_meta.fd) };
-        let maybe_filename = simbiota_monitor::get_filename_from_fd(event_meta.fd);
-        let has_filename = maybe_filename.is_some();
-        let filename = maybe_filename.unwrap_or_else(|| "<n/a>".to_string());
+        /// problems than invalid handles in rust. The monitor owns the fd and closes it after
+        /// the response, so it must not be closed when `file` goes out of scope.
+        let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(event_meta.fd) });
+        let maybe_path = simbiota_monitor::get_path_from_fd(event_meta.fd);
+        let has_filename = maybe_path.is_some();
+        let filename = maybe_path
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "<n/a>".to_string());
+        // the exact path to act on, the filename is lossy for non UTF-8 paths
+        let path = maybe_path.filter(|path| is_actionable_path(path));
         let orig_fname = filename.clone();
 
+        if has_filename && self.is_excluded(&filename) {
+            debug!("ignoring excluded file: {}", filename);
+            return Allow;
+        }
+
+        self.counters.files_scanned.fetch_add(1, Ordering::Relaxed);
         info!("checking file: {}", filename);
         // check cache first
-        if has_filename {
-            if let Some(result) = self.cache.borrow().get_result_for(&filename, event_meta) {
-                let detection_duration = detect_start_ts.elapsed();
+        if let Some(result) = self.cache.borrow().get_result_for(event_meta) {
+            self.counters.cache_hits.fetch_add(1, Ordering::Relaxed);
+            let detection_duration = detect_start_ts.elapsed();
 
-                debug!(
-                    "scanning took: {:?} (cached)",
-                    detection_duration.clone()
-                );
-                return if result == DetectionResult::NoMatch {
-                    info!(
-                        "detection negative: {} (cached)",
-                        filename
-                    );
+            debug!(
+                "scanning took: {:?} (cached)",
+                detection_duration.clone()
+            );
+            return match result {
+                DetectionResult::NoMatch => {
+                    info!("detection negative: {} (cached)", filename);
                     Allow
-                } else {
-                    error!("detection positive: {} (cached)", filename);
-                    self.file_detected_action(filename.clone());
-                    Deny
-                };
+                }
+                DetectionResult::Match { signature, value } => {
+                    self.counters.detections.fetch_add(1, Ordering::Relaxed);
+                    error!(
+                        path = filename.as_str();
+                        "detection positive: {} (cached){}",
+                        filename,
+                        match_note(signature, value)
+                    );
+                    self.file_detected_action(filename.clone(), path, &file, signature, value);
+                    self.match_response()
+                }
+            };
+        }
+        self.counters.cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(max_file_size) = self.max_file_size {
+            let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+            if size > max_file_size {
+                debug!("not scanning {}: size {} exceeds limit", filename, size);
+                // remember the file, so it is not checked again until it changes
+                self.cache
+                    .borrow_mut()
+                    .set_result_for(event_meta, DetectionResult::NoMatch);
+                return Allow;
+            }
+        }
+
+        if self.scan_only == ScanFilter::Executable && !is_executable(&file) {
+            debug!("not scanning {}: not an executable", filename);
+            self.cache
+                .borrow_mut()
+                .set_result_for(event_meta, DetectionResult::NoMatch);
+            return Allow;
+        }
+
+        if let Some(allowlist) = &self.allowlist {
+            match allowlist.borrow().contains(&file) {
+                Ok(true) => {
+                    info!("file is allowlisted: {}", filename);
+                    return Allow;
+                }
+                Ok(false) => {}
+                Err(e) => warn!("failed to hash file for allowlist: {} ({})", filename, e),
             }
         }
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.borrow_mut().acquire();
+        }
+
         let mut no_cache = false;
+        // not mapped: any user who can write the file could truncate it during the scan
+        // and kill the daemon with SIGBUS
         let mut res = self
             .detector
             .borrow_mut()
-            .check_reader(&mut file)
+            .check_reader(&mut *file)
             .unwrap_or_else(|e| {
                 warn!("error checking file: {} ({})", filename, e);
                 no_cache = true; // skip caching this result
@@ -368,49 +780,147 @@ impl DetectionSystem {
             "scanning took: {:?}",
             detection_duration.clone()
         );
+        self.counters.scans.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .scan_time_micros
+            .fetch_add(detection_duration.as_micros() as u64, Ordering::Relaxed);
 
         if !no_cache {
-            self.cache
-                .borrow_mut()
-                .set_result_for(orig_fname.clone(), event_meta, res);
+            self.cache.borrow_mut().set_result_for(event_meta, res);
         }
 
-        if res == DetectionResult::Match {
-            error!("detection positive: {}", filename);
-            self.file_detected_action(orig_fname);
+        if let DetectionResult::Match { signature, value } = res {
+            self.counters.detections.fetch_add(1, Ordering::Relaxed);
+            error!(
+                path = filename.as_str();
+                "detection positive: {}{}",
+                filename,
+                match_note(signature, value)
+            );
+            self.file_detected_action(orig_fname, path, &file, signature, value);
             debug!("detected actions done");
         } else {
             info!("detection negative: {}", filename);
+            if let Some(distance) = self.detector.borrow().closest_distance() {
+                info!("closest database entry to {}: distance {}", filename, distance);
+            }
         }
 
         debug!(
             "blocking took: {:?}",
             detect_start_ts.elapsed()
         );
-        if res == DetectionResult::Match {
-            Deny
+        if res.is_match() {
+            self.match_response()
         } else {
             Allow
         }
     }
 
-    fn file_detected_action(&self, filename: String) {
+    fn match_response(&self) -> FanotifyEventResponse {
+        if self.observe {
+            Allow
+        } else {
+            Deny
+        }
+    }
+
+    fn file_detected_action(
+        &self,
+        filename: String,
+        path: Option<PathBuf>,
+        file: &File,
+        signature: Option<usize>,
+        distance: Option<i64>,
+    ) {
+        // the event fd is closed after the response, files without a usable path are
+        // quarantined from a duplicate
+        let handle = if path.is_none() {
+            file.try_clone().ok()
+        } else {
+            None
+        };
         let actions = self.positive_detection_action.clone();
         let quarantine = self.quarantine.clone();
+        let observe = self.observe;
+        let file_action = self.file_action;
+        let detector = self.detector_class.clone();
+        let events = self.events.clone();
         thread::spawn(move || {
             let callbacks = actions;
+            let time = chrono::Utc::now();
+
+            let (action, quarantine_id) = if observe {
+                info!("observe mode, not moving file to quarantine: {}", filename);
+                (DetectionAction::Observed, None)
+            } else if let Some(path) = &path {
+                match file_action {
+                    FileAction::Quarantine => match &quarantine {
+                        Some(quarantine) => {
+                            error!("moving file to quarantine: {}", filename);
+                            match quarantine.lock().unwrap().add_file(path) {
+                                Some(id) => (DetectionAction::Quarantined, Some(id)),
+                                None => (DetectionAction::Blocked, None),
+                            }
+                        }
+                        None => {
+                            info!("not moving file to quarantine: quarantine disabled");
+                            (DetectionAction::Blocked, None)
+                        }
+                    },
+                    FileAction::Remove => match std::fs::remove_file(path) {
+                        Ok(()) => {
+                            error!("deleted detected file: {}", filename);
+                            (DetectionAction::Removed, None)
+                        }
+                        Err(e) => {
+                            error!("failed to delete detected file {}: {}", filename, e);
+                            (DetectionAction::Blocked, None)
+                        }
+                    },
+                    FileAction::Keep => {
+                        info!("leaving detected file in place: {}", filename);
+                        (DetectionAction::Blocked, None)
+                    }
+                }
+            } else if let (FileAction::Quarantine, Some(quarantine), Some(handle)) =
+                (file_action, &quarantine, &handle)
+            {
+                error!(
+                    "copying file without a usable path to quarantine: {}",
+                    filename
+                );
+                match quarantine.lock().unwrap().add_from_fd(handle, &filename) {
+                    Some(id) => (DetectionAction::Quarantined, Some(id)),
+                    None => (DetectionAction::Blocked, None),
+                }
+            } else {
+                warn!(
+                    "the path of the detected file cannot be resolved, leaving it in place: {}",
+                    filename
+                );
+                (DetectionAction::Blocked, None)
+            };
             let detection_details = DetectionDetails {
-                path: filename.clone(),
-                time: chrono::Utc::now(),
+                path: filename,
+                time,
+                detector,
+                action,
+                quarantine_id,
+                signature,
+                distance,
             };
 
-            if let Some(quarantine) = &quarantine {
-                error!("moving file to quarantine: {}", filename);
-                quarantine.lock().unwrap().add_file(&filename);
-            } else {
-                info!(
-                    "not moving file to quarantine: quarantine disabled"
-                );
+            if let Some(events) = events {
+                events.append(&DetectionEvent {
+                    path: detection_details.path.clone(),
+                    time: detection_details.time.to_rfc3339(),
+                    detector: detection_details.detector.clone(),
+                    action: detection_details.action,
+                    quarantine_id: detection_details.quarantine_id.clone(),
+                    signature: detection_details.signature,
+                    distance: detection_details.distance,
+                });
             }
 
             for positive_callback in callbacks {
@@ -421,9 +931,46 @@ impl DetectionSystem {
     }
 }
 
+/// ` (signature <id>, distance <value>)` for the detection messages, empty if both are unknown
+pub(crate) fn match_note(signature: Option<usize>, distance: Option<i64>) -> String {
+    let details: Vec<String> = signature
+        .map(|id| format!("signature {}", id))
+        .into_iter()
+        .chain(distance.map(|distance| format!("distance {}", distance)))
+        .collect();
+    if details.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", details.join(", "))
+    }
+}
+
+/// Whether the file can be moved or deleted by its path
+///
+/// Files that are not on a filesystem (memfd, anonymous files) have no absolute path, and
+/// `/proc` shows deleted files with a ` (deleted)` suffix.
+fn is_actionable_path(path: &Path) -> bool {
+    if !path.is_absolute() {
+        return false;
+    }
+    !(path.to_string_lossy().ends_with(" (deleted)") && !path.exists())
+}
+
 fn is_cache_disabled(config: &DaemonConfig) -> bool {
     let Some(cache_cfg) = &config.cache else {
         return false;
     };
     cache_cfg.disable_cache
 }
+
+/// Whether the file starts with an ELF or a `#!` script header
+///
+/// Reads with `pread`, so the file offset is left at the start for the detector.
+fn is_executable(file: &File) -> bool {
+    let mut magic = [0u8; 4];
+    let Ok(len) = file.read_at(&mut magic, 0) else {
+        return false;
+    };
+    let magic = &magic[..len];
+    magic.starts_with(b"\x7fELF") || magic.starts_with(b"#!")
+}
@@ -0,0 +1,202 @@
+use crate::memory_detection_cache::{stat_event, StatBasedCacheData};
+use libc::fanotify_event_metadata;
+use linked_hash_map::LinkedHashMap;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use simbiota_clientlib::api::cache::DetectionCache;
+use simbiota_clientlib::api::detector::DetectionResult;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two writes of the cache file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize)]
+struct DiskCacheEntry {
+    data: StatBasedCacheData,
+    matched: bool,
+    /// Matched database entry, missing from caches written by older versions
+    #[serde(default)]
+    signature: Option<usize>,
+    /// Comparison value of the match, missing from caches written by older versions
+    #[serde(default)]
+    value: Option<i64>,
+}
+
+/// Content of the cache file
+#[derive(Serialize, Deserialize)]
+struct DiskCacheFile {
+    /// Fingerprint of the database files the results were computed with
+    database: String,
+    /// Entries in least recently used first order
+    entries: Vec<(String, DiskCacheEntry)>,
+}
+
+/// Detection cache that survives restarts
+///
+/// Entries are kept in memory and written to `path` at most once every [`FLUSH_INTERVAL`],
+/// so a fresh boot does not have to re-scan every file. The entries are keyed by
+/// `<dev>:<ino>` of the file and evicted least recently used first, like in the
+/// memory cache.
+///
+/// The file records a fingerprint of the database files. A cache written with a different
+/// database, e.g. one updated while the daemon was stopped, is discarded at startup, so new
+/// signatures are checked against unchanged files too.
+pub struct DiskDetectionCache {
+    cache_map: RefCell<LinkedHashMap<String, DiskCacheEntry>>,
+    max_entries: Option<usize>,
+    path: PathBuf,
+    database_files: Vec<PathBuf>,
+    database_fingerprint: String,
+    last_flush: Instant,
+}
+
+impl DiskDetectionCache {
+    pub fn new(path: &Path, max_entries: Option<usize>, database_files: Vec<PathBuf>) -> Self {
+        let database_fingerprint = database_fingerprint(&database_files);
+        let mut cache_map = LinkedHashMap::new();
+        match std::fs::read(path) {
+            Ok(content) => match serde_json::from_slice::<DiskCacheFile>(&content) {
+                Ok(file) if file.database == database_fingerprint => {
+                    cache_map.extend(file.entries);
+                }
+                Ok(_) => {
+                    info!("database changed since the detection cache was written, discarding it")
+                }
+                Err(e) => {
+                    if serde_json::from_slice::<HashMap<String, serde_json::Value>>(&content)
+                        .is_ok()
+                    {
+                        info!("discarding detection cache of an older version");
+                    } else {
+                        warn!("invalid detection cache file {}: {}", path.display(), e);
+                    }
+                }
+            },
+            Err(e) => debug!("detection cache file {} not loaded: {}", path.display(), e),
+        }
+        let mut cache = Self {
+            cache_map: RefCell::new(cache_map),
+            max_entries,
+            path: path.to_path_buf(),
+            database_files,
+            database_fingerprint,
+            last_flush: Instant::now(),
+        };
+        cache.evict();
+        info!(
+            "loaded {} detection cache entries",
+            cache.cache_map.borrow().len()
+        );
+        cache
+    }
+
+    /// Drop the least recently used entries over `max_entries`
+    fn evict(&mut self) {
+        let Some(max_entries) = self.max_entries else {
+            return;
+        };
+        let cache_map = self.cache_map.get_mut();
+        while cache_map.len() > max_entries {
+            if let Some((evicted, _)) = cache_map.pop_front() {
+                debug!("evicted cache entry: {}", evicted);
+            }
+        }
+    }
+
+    /// Write the cache file, replacing the previous one atomically
+    pub fn flush(&mut self) {
+        let tmp_path = self.path.with_extension("tmp");
+        let cache_map = self.cache_map.get_mut();
+        let file = DiskCacheFile {
+            database: self.database_fingerprint.clone(),
+            entries: std::mem::take(cache_map).into_iter().collect(),
+        };
+        let result = serde_json::to_vec(&file)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(std::fs::write(&tmp_path, content)?))
+            .and_then(|_| Ok(std::fs::rename(&tmp_path, &self.path)?));
+        cache_map.extend(file.entries);
+        match result {
+            Ok(()) => debug!("detection cache written to {}", self.path.display()),
+            Err(e) => warn!(
+                "failed to write detection cache {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+        self.last_flush = Instant::now();
+    }
+}
+
+/// SHA-256 of the contents of every database file, in order
+fn database_fingerprint(database_files: &[PathBuf]) -> String {
+    let mut hasher = Sha256::new();
+    for path in database_files {
+        let hashed = File::open(path).and_then(|mut file| std::io::copy(&mut file, &mut hasher));
+        if let Err(e) = hashed {
+            warn!(
+                "cannot read database {} for the cache: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl DetectionCache<fanotify_event_metadata> for DiskDetectionCache {
+    fn get_result_for(&self, event_meta: &fanotify_event_metadata) -> Option<DetectionResult> {
+        let (key, current_data) = stat_event(event_meta);
+        let mut cache_map = self.cache_map.borrow_mut();
+        let entry = cache_map.get_refresh(&key.to_key_string())?;
+        if current_data == entry.data {
+            return Some(if entry.matched {
+                DetectionResult::Match {
+                    signature: entry.signature,
+                    value: entry.value,
+                }
+            } else {
+                DetectionResult::NoMatch
+            });
+        }
+        None
+    }
+
+    fn set_result_for(&mut self, data: &fanotify_event_metadata, result: DetectionResult) {
+        let (key, current_data) = stat_event(data);
+        let (signature, value) = match result {
+            DetectionResult::Match { signature, value } => (signature, value),
+            DetectionResult::NoMatch => (None, None),
+        };
+        // insert moves existing keys to the back as well
+        self.cache_map.get_mut().insert(
+            key.to_key_string(),
+            DiskCacheEntry {
+                data: current_data,
+                matched: result.is_match(),
+                signature,
+                value,
+            },
+        );
+        self.evict();
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Called when the database changed, later results belong to the new database
+    fn clear(&mut self) {
+        self.cache_map.get_mut().clear();
+        self.database_fingerprint = database_fingerprint(&self.database_files);
+        self.flush();
+    }
+}
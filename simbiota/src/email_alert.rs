@@ -1,23 +1,154 @@
-use crate::daemon_config::{DaemonConfig, SmtpConnectionSecurity};
+use crate::daemon_config::{DaemonConfig, EmailFormat, SmtpConnectionSecurity};
 use libc::fanotify_event_metadata;
 use log::{debug, info, warn};
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::{process, thread};
 
-use crate::detection_system::DetectionDetails;
+use crate::detection_system::{match_note, DetectionDetails};
+use lettre::message::MultiPart;
 use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
 use lettre::{Message, SmtpTransport, Transport};
 
+const RATE_LIMIT_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Collects detections and sends them from a background thread
+///
+/// Detections arriving within `email.batch_window_secs` of the first pending one are sent
+/// in a single email, and at most `email.max_per_hour` emails are sent per hour.
 pub struct EmailAlertSystem {
-    config: Arc<DaemonConfig>,
+    shared: Arc<AlertQueue>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct AlertQueue {
+    sender: EmailSender,
+    state: Mutex<AlertQueueState>,
+    cond: Condvar,
+}
+
+struct AlertQueueState {
+    pending: Vec<DetectionDetails>,
+    period_start: Instant,
+    sent_in_period: usize,
+    shutdown: bool,
 }
 
 impl EmailAlertSystem {
     pub fn new(config: Arc<DaemonConfig>) -> Self {
-        Self { config }
+        let shared = Arc::new(AlertQueue {
+            sender: EmailSender { config },
+            state: Mutex::new(AlertQueueState {
+                pending: Vec::new(),
+                period_start: Instant::now(),
+                sent_in_period: 0,
+                shutdown: false,
+            }),
+            cond: Condvar::new(),
+        });
+        let worker_shared = shared.clone();
+        let worker = thread::spawn(move || {
+            debug!("email alert thread id: {:?}", process::id());
+            worker_shared.run();
+        });
+        Self {
+            shared,
+            worker: Mutex::new(Some(worker)),
+        }
+    }
+
+    /// Queue a detection for the next alert email
+    pub fn queue_alert(&self, data: &DetectionDetails) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.shutdown {
+            warn!(
+                "email alerts are shut down, dropping alert for {}",
+                data.path
+            );
+            return;
+        }
+        state.pending.push(data.clone());
+        self.shared.cond.notify_all();
+    }
+
+    /// Send the pending alerts and stop the background thread
+    pub fn flush(&self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.cond.notify_all();
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            if worker.join().is_err() {
+                warn!("email alert thread panicked");
+            }
+        }
     }
+}
+
+impl AlertQueue {
+    fn run(&self) {
+        let batch_window = self.sender.config.email.batch_window;
+        let max_per_hour = self.sender.config.email.max_per_hour;
+        loop {
+            let mut state = self.state.lock().unwrap();
+            while state.pending.is_empty() && !state.shutdown {
+                state = self.cond.wait(state).unwrap();
+            }
+            if state.pending.is_empty() {
+                // shutdown with nothing left to send
+                return;
+            }
+
+            // Give the detections following the first one a chance to join the batch
+            let deadline = Instant::now() + batch_window;
+            while !state.shutdown {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+            }
+
+            let alerts = std::mem::take(&mut state.pending);
+            if state.period_start.elapsed() >= RATE_LIMIT_PERIOD {
+                state.period_start = Instant::now();
+                state.sent_in_period = 0;
+            }
+            let note = match max_per_hour {
+                Some(max) if state.sent_in_period >= max => {
+                    warn!(
+                        "email alert limit reached, dropping alert for {} file(s)",
+                        alerts.len()
+                    );
+                    continue;
+                }
+                Some(max) if state.sent_in_period + 1 == max => Some(format!(
+                    "The limit of {} alert emails per hour has been reached, further \
+                    detections will not be emailed for up to an hour. Check the daemon \
+                    log for the complete list of detections.",
+                    max
+                )),
+                _ => None,
+            };
+            state.sent_in_period += 1;
+            let shutdown = state.shutdown;
+            drop(state);
+
+            self.sender.send_email_alert(&alerts, note.as_deref());
+            if shutdown {
+                return;
+            }
+        }
+    }
+}
+
+struct EmailSender {
+    config: Arc<DaemonConfig>,
+}
 
-    pub fn send_email_alert(&self, data: &DetectionDetails) {
+impl EmailSender {
+    fn send_email_alert(&self, alerts: &[DetectionDetails], note: Option<&str>) {
         info!("sending email notification");
 
         let mut email = Message::builder().from(
@@ -32,10 +163,16 @@ impl EmailAlertSystem {
             email = email.to(recp.parse().unwrap());
         }
 
-        let email = email
-            .subject("SIMBIoTA Alert")
-            .body(self.gen_body(data))
-            .unwrap();
+        let hostname = crate::hostname();
+        let email = email.subject(format!("SIMBIoTA Alert on {}", hostname));
+        let email = match self.config.email.format {
+            EmailFormat::Text => email.body(self.gen_body(alerts, &hostname, note)),
+            EmailFormat::Html => email.multipart(MultiPart::alternative_plain_html(
+                self.gen_body(alerts, &hostname, note),
+                self.gen_html_body(alerts, &hostname, note),
+            )),
+        }
+        .unwrap();
 
         let smtp_config = self.config.email.smtp_config.as_ref().unwrap();
         let creds = Credentials::new(
@@ -43,39 +180,129 @@ impl EmailAlertSystem {
             smtp_config.password.clone().unwrap_or("".to_string()),
         );
 
-        let mailer = match smtp_config.security {
-            SmtpConnectionSecurity::None => SmtpTransport::builder_dangerous(&smtp_config.server)
-                .port(smtp_config.port)
-                .credentials(creds)
-                .build(),
-            SmtpConnectionSecurity::Ssl => SmtpTransport::relay(&smtp_config.server)
-                .unwrap()
-                .port(smtp_config.port)
-                .credentials(creds)
-                .build(),
-            SmtpConnectionSecurity::Starttls => SmtpTransport::starttls_relay(&smtp_config.server)
-                .unwrap()
+        let tls_parameters = || {
+            TlsParameters::builder(smtp_config.server.clone())
+                .dangerous_accept_invalid_certs(smtp_config.accept_invalid_certs)
+                .build_rustls()
+        };
+        let builder = match smtp_config.security {
+            SmtpConnectionSecurity::None => {
+                Ok(SmtpTransport::builder_dangerous(&smtp_config.server))
+            }
+            SmtpConnectionSecurity::Ssl => tls_parameters().map(|params| {
+                SmtpTransport::builder_dangerous(&smtp_config.server).tls(Tls::Wrapper(params))
+            }),
+            SmtpConnectionSecurity::Starttls => tls_parameters().map(|params| {
+                SmtpTransport::builder_dangerous(&smtp_config.server).tls(Tls::Required(params))
+            }),
+        };
+        let mailer = match builder {
+            Ok(builder) => builder
                 .port(smtp_config.port)
                 .credentials(creds)
+                .timeout(Some(smtp_config.timeout))
                 .build(),
+            Err(err) => {
+                warn!(
+                    "failed to set up TLS for smtp server {}: {}",
+                    smtp_config.server, err
+                );
+                return;
+            }
         };
 
         info!("sending email using {:?}", smtp_config.server);
         let result = mailer.send(&email);
-        if result.is_err() {
-            warn!("failed to send email: {}", result.unwrap_err());
+        if let Err(err) = result {
+            warn!("failed to send email using {}: {}", smtp_config.server, err);
         } else {
             warn!("alert email sent");
         }
     }
 
-    fn gen_body(&self, data: &DetectionDetails) -> String {
+    fn detector_class(&self) -> &str {
+        self.config.raw_config["detector"]["class"]
+            .as_str()
+            .unwrap_or("unknown")
+    }
+
+    fn gen_body(&self, alerts: &[DetectionDetails], hostname: &str, note: Option<&str>) -> String {
+        let mut body = format!(
+            "SIMBIoTA Alert message:\n\n\
+            The system detected {} malicious file(s).\n\
+            Hostname: {}\n\
+            Detector: {}\n\n",
+            alerts.len(),
+            hostname,
+            self.detector_class()
+        );
+        for alert in alerts {
+            body.push_str(&format!(
+                "{}  {}{}\n",
+                alert.time,
+                alert.path,
+                match_note(alert.signature, alert.distance)
+            ));
+        }
+        if let Some(note) = note {
+            body.push_str(&format!("\n{}\n", note));
+        }
+        body
+    }
+
+    fn gen_html_body(
+        &self,
+        alerts: &[DetectionDetails],
+        hostname: &str,
+        note: Option<&str>,
+    ) -> String {
+        let rows = alerts
+            .iter()
+            .map(|alert| {
+                format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    escape_html(&alert.path),
+                    alert.time,
+                    escape_html(hostname),
+                    escape_html(self.detector_class()),
+                    alert.signature.map(|id| id.to_string()).unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let note = note
+            .map(|note| format!("<p>{}</p>\n", escape_html(note)))
+            .unwrap_or_default();
         format!(
-            r#"SIMBIoTA Alert message: 
-        
-        The system detected a malicious file: {}
-        Detection time: {}"#,
-            data.path, data.time
+            "<html><body>\n\
+            <h2>SIMBIoTA Alert</h2>\n\
+            <p>The system detected {} malicious file(s).</p>\n\
+            <table>\n\
+            <tr><th align=\"left\">File</th><th align=\"left\">Detection time</th>\
+            <th align=\"left\">Hostname</th><th align=\"left\">Detector</th>\
+            <th align=\"left\">Signature</th></tr>\n\
+            {}\n\
+            </table>\n\
+            {}\
+            </body></html>\n",
+            alerts.len(),
+            rows,
+            note
         )
     }
 }
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,89 @@
+use log::{debug, warn};
+use simbiota_protocol::DetectionEvent;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Record of positive detections, one JSON object per line
+pub struct EventLog {
+    path: PathBuf,
+    /// The file is moved to `<path>.1` once it grows larger than this
+    max_size: u64,
+    lock: Mutex<()>,
+}
+
+impl EventLog {
+    pub fn new(path: &Path, max_size: u64) -> Self {
+        if let Some(parent) = path.parent() {
+            if let Err(e) = std::fs::create_dir_all(parent) {
+                warn!("failed to create event log directory: {}", e);
+            }
+        }
+        Self {
+            path: path.to_path_buf(),
+            max_size,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn append(&self, event: &DetectionEvent) {
+        let _guard = self.lock.lock().unwrap();
+        if let Err(e) = self.write_event(event) {
+            warn!("failed to write detection event: {}", e);
+        }
+    }
+
+    fn write_event(&self, event: &DetectionEvent) -> std::io::Result<()> {
+        let full = self
+            .path
+            .metadata()
+            .is_ok_and(|meta| meta.len() >= self.max_size);
+        if full {
+            std::fs::rename(&self.path, self.rotated_path())?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)?;
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+    }
+
+    /// The last `limit` events, oldest first
+    pub fn recent(&self, limit: usize) -> Vec<DetectionEvent> {
+        let _guard = self.lock.lock().unwrap();
+        if limit == 0 {
+            return Vec::new();
+        }
+        let mut events = VecDeque::new();
+        for path in [self.rotated_path(), self.path.clone()] {
+            let Ok(file) = File::open(&path) else {
+                continue;
+            };
+            // a failing read stops at the events read so far instead of retrying forever
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                match serde_json::from_str(&line) {
+                    Ok(event) => {
+                        if events.len() == limit {
+                            events.pop_front();
+                        }
+                        events.push_back(event);
+                    }
+                    Err(e) => debug!("skipping invalid detection event: {}", e),
+                }
+            }
+        }
+        events.into()
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".1");
+        PathBuf::from(path)
+    }
+}
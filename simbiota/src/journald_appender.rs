@@ -0,0 +1,97 @@
+use log::kv::Key;
+use log::{Level, Record};
+use log4rs::append::Append;
+use std::fmt::Debug;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends log records to the systemd journal using its native protocol
+///
+/// Falls back to stderr when the journal socket is not available, e.g. when not running
+/// under systemd.
+#[derive(Debug)]
+pub struct JournaldAppender {
+    socket: Option<UnixDatagram>,
+}
+
+impl JournaldAppender {
+    pub fn new() -> Self {
+        let socket = UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(JOURNAL_SOCKET).map(|_| socket))
+            .ok();
+        if socket.is_none() {
+            eprintln!("journald is not available, logging to stderr");
+        }
+        Self { socket }
+    }
+
+    /// Map log levels to syslog priorities
+    fn priority(level: Level) -> u8 {
+        match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+            Level::Trace => 7,
+        }
+    }
+
+    fn add_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+        buf.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // values with newlines need the binary format: name, newline, length, value
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+}
+
+impl Default for JournaldAppender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Append for JournaldAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let Some(socket) = &self.socket else {
+            writeln!(
+                std::io::stderr(),
+                "{} {} - {}",
+                record.level(),
+                record.target(),
+                record.args()
+            )?;
+            return Ok(());
+        };
+
+        let mut buf = Vec::new();
+        Self::add_field(&mut buf, "MESSAGE", &record.args().to_string());
+        Self::add_field(
+            &mut buf,
+            "PRIORITY",
+            &Self::priority(record.level()).to_string(),
+        );
+        Self::add_field(&mut buf, "SYSLOG_IDENTIFIER", "simbiota");
+        Self::add_field(&mut buf, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            Self::add_field(&mut buf, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            Self::add_field(&mut buf, "CODE_LINE", &line.to_string());
+        }
+        if let Some(path) = record.key_values().get(Key::from_str("path")) {
+            Self::add_field(&mut buf, "SIMBIOTA_PATH", &path.to_string());
+        }
+        socket.send(&buf)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
@@ -3,24 +3,36 @@
 use crate::args::ClientArgs;
 use crate::control_server::ControlServer;
 use crate::daemon_config::DaemonConfig;
-use crate::detection_system::{CommandResult, DetectionDetails, DetectionSystem, DetectorCommand};
+use crate::detection_system::{
+    Action, CommandResult, DetectionDetails, DetectionSystem, DetectorCommand,
+};
 use crate::email_alert::EmailAlertSystem;
+use crate::journald_appender::JournaldAppender;
 use crate::logging::SimbiotaLoggerHolder;
+use crate::metrics::MetricsServer;
+use crate::pid_file::PidFile;
 use crate::syslog_appender::{SyslogAppender, SyslogFormat};
+use crate::webhook_alert::WebhookAlertSystem;
 use clap::Parser;
 use crossbeam_channel::{Receiver, Sender};
-use inotify::{Inotify, WatchMask};
 use libc::{getegid, geteuid, setsid};
 use log::{debug, error, info, logger, warn, LevelFilter};
 use log4rs::append::console::{ConsoleAppender, ConsoleAppenderBuilder, Target};
 use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
 use log4rs::config::{Appender, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use log4rs::filter::threshold::ThresholdFilter;
 use log4rs::Config;
 use simbiota_clientlib::client_config::ClientConfig;
+use simbiota_clientlib::database_watcher::watch_database;
+use simbiota_clientlib::detector::dummy_detector::DummyDetectorProvider;
 use simbiota_clientlib::detector::tlsh_detector::SimpleTLSHDetectorProvider;
 use simbiota_clientlib::system_database::SystemDatabase;
+use simbiota_clientlib::updater;
 use simbiota_monitor::monitor::{
     EventFlags, EventMask, FANClass, FanotifyInitError, FanotifyMarkError, FilesystemMonitor,
     MarkFlags,
@@ -31,9 +43,12 @@ use std::collections::HashMap;
 use std::ffi::CString;
 use std::fs::File;
 use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixListener;
 use std::os::unix::process::CommandExt;
-use std::path::PathBuf;
-use std::process::{exit, Command};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command, Stdio};
 use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
@@ -42,30 +57,130 @@ use std::{process, thread};
 use syslog::Facility;
 use yaml_rust::Yaml;
 
+mod allowlist;
 mod args;
+mod capabilities;
 mod daemon_config;
+mod disk_detection_cache;
+mod event_log;
+mod journald_appender;
 mod logging;
 mod memory_detection_cache;
+mod metrics;
+mod peer_auth;
+mod pid_file;
 mod quarantine;
+mod sd_notify;
 mod syslog_appender;
 
 pub mod detection_system;
 #[cfg(feature = "email_alert")]
 mod email_alert;
+#[cfg(feature = "webhook_alert")]
+mod webhook_alert;
 
 mod control_server;
 
 const DEFAULT_CONFIG_PATH: &str = "/etc/simbiota/client.yaml";
+const DEFAULT_LOG_MAX_SIZE_MB: u64 = 10;
+const DEFAULT_LOG_KEEP: u32 = 5;
 
 struct SimbiotaClientDaemon {
     logger: &'static mut SimbiotaLoggerHolder,
     args: ClientArgs,
+    daemon_config: Arc<DaemonConfig>,
     database: Arc<Mutex<SystemDatabase>>,
+    /// Database file kept up to date by the updater
     database_file: PathBuf,
+    /// Every configured database file, reloaded when one of them changes
+    database_files: Vec<PathBuf>,
     detection_system: DetectionSystem,
+    email_system: Option<Arc<EmailAlertSystem>>,
+    pid_file: PidFile,
 }
 
 impl SimbiotaClientDaemon {
+    /// Load the config through the same parsers as a normal start, then exit
+    fn check_config(args: &ClientArgs) -> ! {
+        let config_path = args
+            .config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+        // both exit with a report if the config is invalid
+        DaemonConfig::load_from(&config_path, false);
+        ClientConfig::load_from(&config_path, false);
+        println!("{}: config is valid", config_path.display());
+        exit(0);
+    }
+
+    /// Load a database file the same way as a normal start, then exit
+    fn verify_database(path: &Path) -> ! {
+        let mut config = ClientConfig::default();
+        config.database.database_path = path.to_path_buf();
+        match SystemDatabase::try_load(&config) {
+            Ok(_) => {
+                println!("{}: database is valid", path.display());
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+    }
+
+    /// Download and install the latest database release, or with `--check-update` only
+    /// report whether one is available, then exit
+    ///
+    /// The exit codes are listed in simbiota(8), `simbiota-update.sh` passes them on.
+    fn update_database(args: &ClientArgs) -> ! {
+        let config_path = args
+            .config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+        // like the daemon, the default database path is used without a config file
+        let client_config = ClientConfig::load_from(&config_path, args.config.is_none());
+        let database_path = &client_config.database.database_path;
+        let settings = updater::UpdateSettings::load().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(e.exit_code());
+        });
+        if args.check_update {
+            match updater::check_update(database_path, &settings) {
+                Ok(check) if check.update_available() => {
+                    println!(
+                        "update available: {} -> {}",
+                        check.current_version.as_deref().unwrap_or("unknown"),
+                        check.latest_version
+                    );
+                    exit(2);
+                }
+                Ok(check) => {
+                    println!("database is up to date ({})", check.latest_version);
+                    exit(0);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(e.exit_code());
+                }
+            }
+        }
+        match updater::update_database(database_path, &settings) {
+            Ok(outcome) => {
+                println!(
+                    "{}: database version {}",
+                    database_path.display(),
+                    outcome.version.as_deref().unwrap_or("unknown")
+                );
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(e.exit_code());
+            }
+        }
+    }
+
     fn new() -> Self {
         let mut logger_holder = Box::leak(Box::new(SimbiotaLoggerHolder::new()));
 
@@ -77,6 +192,29 @@ impl SimbiotaClientDaemon {
             }
         });*/
 
+        let args = ClientArgs::parse();
+
+        // checking the config or a database does not need root, neither touches the system
+        if args.check_config {
+            Self::check_config(&args);
+        }
+        if let Some(path) = &args.verify_database {
+            Self::verify_database(path);
+        }
+        // updates need write access to the database only, not root
+        if args.update_database || args.check_update {
+            let level = if args.verbose {
+                LevelFilter::Debug
+            } else {
+                LevelFilter::Info
+            };
+            let update_log = SimpleLogger::new()
+                .with_level(level)
+                .with_module_level("rustls", LevelFilter::Info);
+            logger_holder.set_logger(Box::new(update_log));
+            Self::update_database(&args);
+        }
+
         unsafe {
             if geteuid() != 0 {
                 eprintln!("error: not running as root user, exiting...");
@@ -93,7 +231,6 @@ impl SimbiotaClientDaemon {
             debug!("Running in debug mode")
         }
 
-        let args = ClientArgs::parse();
         if args.bg {
             restart_in_bg();
         }
@@ -108,7 +245,7 @@ impl SimbiotaClientDaemon {
         let daemon_config = Arc::from(DaemonConfig::load_from(config_path, has_config_override));
         let client_config = Rc::from(ClientConfig::load_from(config_path, false));
         if !args.verbose {
-            Self::load_logging_config(daemon_config.as_ref(), logger_holder);
+            Self::load_logging_config(daemon_config.as_ref(), logger_holder, args.foreground);
         } else {
             let startup_log = SimpleLogger::new()
                 .env()
@@ -116,6 +253,17 @@ impl SimbiotaClientDaemon {
             logger_holder.set_logger(Box::new(startup_log));
         }
 
+        // after the --bg re-exec, so the file contains the PID of the daemon
+        let pid_file = PidFile::create(&daemon_config.pid_file).unwrap_or_else(|e| {
+            error!(
+                "failed to write PID file {}: {}",
+                daemon_config.pid_file.display(),
+                e
+            );
+            eprintln!("Cannot write PID file: {}", e);
+            exit(1);
+        });
+
         // print pid and EUID, EGID
         /// SAFETY: Safe, only calls to syscalls without args
         unsafe {
@@ -131,7 +279,17 @@ impl SimbiotaClientDaemon {
         Self::register_providers();
 
         // Load the database from the filesystem
-        let database = Arc::new(Mutex::new(SystemDatabase::load(&client_config)));
+        if args.wait_for_database {
+            for path in client_config.database.paths() {
+                wait_for_database(path);
+            }
+        }
+        let database = SystemDatabase::try_load(&client_config).unwrap_or_else(|e| {
+            error!("failed to load database: {}", e);
+            eprintln!("Cannot load database: {}", e);
+            exit(1);
+        });
+        let database = Arc::new(Mutex::new(database));
 
         // Create monitor flags.
         let monitor_flags = daemon_config.monitor.flags;
@@ -140,20 +298,18 @@ impl SimbiotaClientDaemon {
         let mut monitor =
             FilesystemMonitor::new(FANClass::ClassContent, monitor_flags, event_flags)
                 .unwrap_or_else(|e| {
-                    if let FanotifyInitError::NotImplemented = e {
-                        error!(
-                        "fanotify_init return NotImplemented. The kernel does not support fanotify"
-                    );
-                        eprintln!(
-                            "The kernel does not support fanotify. Please recompile the kernel"
-                        );
-                        exit(1);
-                    }
-                    error!("failed to create monitor: {e:?}");
-                    eprintln!("cannot create fanotify monitor. Exiting...");
+                    let hint = monitor_init_hint(&e);
+                    error!("failed to create monitor: {e:?}: {hint}");
+                    eprintln!("Cannot create fanotify monitor: {hint}");
                     exit(1);
                 });
 
+        monitor.set_response_timeout(daemon_config.monitor.response_timeout);
+        monitor.set_queue_capacity(daemon_config.monitor.queue_size);
+        monitor.set_msg_buffer_size(daemon_config.monitor.event_buffer_size);
+        // the control server is already listening when the monitor starts
+        monitor.set_ready_callback(Box::new(|| sd_notify::notify("READY=1")));
+
         // Load paths from config
         for mp in &daemon_config.monitor.paths {
             let mut mark_flags = MarkFlags::empty();
@@ -171,12 +327,24 @@ impl SimbiotaClientDaemon {
 
             let mut mask = mp.mask;
 
-            if mp.event_on_children {
+            if mp.event_on_children || mp.recursive {
                 mask.insert(EventMask::EVENT_ON_CHILD);
             }
 
-            monitor.add_path(&mp.path, mark_flags, mask);
-            info!("marked path for monitoring: {}", mp.path.display());
+            if mp.recursive {
+                let directories = directory_tree(&mp.path);
+                info!(
+                    "adding {} directories for monitoring: {}",
+                    directories.len(),
+                    mp.path.display()
+                );
+                for directory in directories {
+                    monitor.add_path(&directory, mark_flags | MarkFlags::ONLY_DIR, mask);
+                }
+            } else {
+                monitor.add_path(&mp.path, mark_flags, mask);
+                info!("adding path for monitoring: {}", mp.path.display());
+            }
             debug!("mark flags: {:?}, mask: {:?}", mark_flags, mask);
         }
 
@@ -186,15 +354,30 @@ impl SimbiotaClientDaemon {
             daemon_config.clone(),
             database.clone(),
             args.verbose,
+            args.observe,
         );
 
+        if daemon_config.drop_capabilities {
+            // marking needs CAP_SYS_ADMIN, and no worker thread may be running yet as
+            // capabilities are per thread
+            detection_system.monitor().apply_marks();
+            if let Err(e) = capabilities::drop_capabilities() {
+                error!("failed to drop capabilities: {}", e);
+                eprintln!("Cannot drop capabilities: {}", e);
+                exit(1);
+            }
+            capabilities::log_capabilities();
+        }
+
         // Check email support
+        let mut email_system = None;
         if cfg!(feature = "email_alert") {
             debug!("email support enabled");
             if daemon_config.email.enabled {
-                let email_system = EmailAlertSystem::new(daemon_config.clone());
+                let sender = Arc::new(EmailAlertSystem::new(daemon_config.clone()));
                 detection_system
-                    .add_positive_action(Box::new(Self::create_sender_action(email_system)));
+                    .add_positive_action(Box::new(Self::create_sender_action(sender.clone())));
+                email_system = Some(sender);
                 info!("email alerts enabled");
             } else {
                 info!("email alerts disabled");
@@ -203,85 +386,199 @@ impl SimbiotaClientDaemon {
             debug!("email support disabled");
         }
 
+        if cfg!(feature = "webhook_alert") {
+            debug!("webhook support enabled");
+            if daemon_config.webhook.enabled {
+                let sender = WebhookAlertSystem::new(daemon_config.clone());
+                detection_system
+                    .add_positive_action(Box::new(move |event| sender.queue_alert(event)));
+                info!("webhook alerts enabled");
+            } else {
+                info!("webhook alerts disabled");
+            }
+        } else {
+            debug!("webhook support disabled");
+        }
+
         // Start database updater
         let database_file = client_config.database.database_path.clone();
+        let database_files = client_config
+            .database
+            .paths()
+            .map(Path::to_path_buf)
+            .collect();
 
         Self {
             logger: logger_holder,
             args,
+            daemon_config,
             database,
             database_file,
+            database_files,
             detection_system,
+            email_system,
+            pid_file,
         }
     }
 
-    fn create_sender_action(sender: EmailAlertSystem) -> impl Fn(&DetectionDetails) {
+    fn create_sender_action(sender: Arc<EmailAlertSystem>) -> impl Fn(&DetectionDetails) {
         move |event| {
-            sender.send_email_alert(event);
+            sender.queue_alert(event);
         }
     }
 
     fn start(&mut self) {
-        let dbfile_clone = self.database_file.clone();
-        let database_clone = Arc::clone(&self.database);
-        thread::spawn(move || {
-            debug!("database file watcher thread id: {}", process::id());
-            let mut inotify = Inotify::init()
-                .expect("failed to init inotify. Cannot watch database file for changes");
-            inotify
-                .watches()
-                .add(dbfile_clone, WatchMask::CLOSE_WRITE)
+        let (detector_id, _, detector_tx) = self.detection_system.com_pair();
+        watch_database(self.database.clone(), &self.database_files, move || {
+            // results cached with the old database may be wrong now
+            detector_tx
+                .send(DetectorCommand {
+                    id: detector_id,
+                    command: Action::DatabaseReloaded,
+                })
                 .unwrap();
-            let mut buffer = [0; 1024];
-            info!("watching database file for changes");
-            loop {
-                let events = inotify
-                    .read_events_blocking(&mut buffer)
-                    .expect("inotify wait failed");
-
-                for event in events {
-                    info!("database file changed, reloading...");
-                    let mut database_lock = database_clone.lock().unwrap();
-                    database_lock.pre_update();
-                    database_lock.mark_update();
-                }
-            }
-        });
+        })
+        .expect("failed to watch database file for changes");
 
         info!("starting control server");
-        self.start_control_server(self.detection_system.com_pair());
+        let control_listener = self.start_control_server(self.detection_system.com_pair());
+
+        if self.daemon_config.metrics.enabled {
+            self.start_metrics_server();
+        }
+
+        // the shutdown signals stay blocked and pending until the handler runs
+        self.start_signal_handler(control_listener);
 
         info!("starting detector");
         self.detection_system.start();
     }
 
-    fn start_control_server(&self, com: (usize, Receiver<CommandResult>, Sender<DetectorCommand>)) {
-        thread::spawn(|| {
+    /// Flush pending work and exit on SIGTERM and SIGINT
+    ///
+    /// The signals must already be blocked by [`block_shutdown_signals`]
+    fn start_signal_handler(&self, control_listener: Option<UnixListener>) {
+        let email_system = self.email_system.clone();
+        let monitor = self.detection_system.monitor();
+        let pid_file = self.pid_file.clone();
+        thread::spawn(move || {
+            debug!("signal handler thread id: {:?}", process::id());
+            let mut signal = 0;
+            /// SAFETY: Standard LibC calls
+            unsafe {
+                let set = shutdown_signals();
+                libc::sigwait(&set, &mut signal);
+            }
+            info!("received signal {}, shutting down", signal);
+            sd_notify::notify("STOPPING=1");
+            // releases every process still waiting for a permission response
+            monitor.close();
+            if let Some(email_system) = email_system {
+                email_system.flush();
+            }
+            if let Some(listener) = control_listener {
+                /// SAFETY: Standard LibC calls
+                unsafe {
+                    libc::shutdown(listener.as_raw_fd(), libc::SHUT_RDWR);
+                }
+            }
+            pid_file.remove();
+            logger().flush();
+            exit(0);
+        });
+    }
+
+    /// Returns a handle to the listening socket, so it can be shut down on exit
+    fn start_control_server(
+        &self,
+        com: (usize, Receiver<CommandResult>, Sender<DetectorCommand>),
+    ) -> Option<UnixListener> {
+        let server =
+            ControlServer::new(com, self.database_file.clone(), &self.daemon_config.control);
+        let listener = server
+            .try_clone_listener()
+            .map_err(|e| warn!("failed to clone control socket: {}", e))
+            .ok();
+        thread::spawn(move || {
             debug!("control server thread id: {:?}", process::id());
-            let mut server = ControlServer::new(com);
             server.listen();
         });
+        listener
+    }
+
+    fn start_metrics_server(&self) {
+        let bind = self.daemon_config.metrics.bind;
+        let server = MetricsServer::bind(
+            bind,
+            self.detection_system.counters(),
+            self.detection_system.started(),
+        )
+        .unwrap_or_else(|e| {
+            error!("cannot start metrics server on {}: {}", bind, e);
+            eprintln!("Cannot listen on metrics address {}: {}", bind, e);
+            exit(1);
+        });
+        thread::spawn(move || {
+            debug!("metrics server thread id: {:?}", process::id());
+            server.listen();
+        });
+    }
+
+    /// Log file appender that rolls the file over to `<path>.0` .. `<path>.<keep - 1>`
+    /// once it grows beyond `max_size_mb`
+    fn rolling_file_appender(
+        path: &str,
+        append: bool,
+        max_size_mb: u64,
+        keep: u32,
+    ) -> RollingFileAppender {
+        let trigger = SizeTrigger::new(max_size_mb * 1024 * 1024);
+        let roller = FixedWindowRoller::builder()
+            .build(&format!("{}.{{}}", path), keep.max(1))
+            .expect("invalid log file path");
+        RollingFileAppender::builder()
+            .encoder(Box::<PatternEncoder>::default())
+            .append(append)
+            .build(
+                path,
+                Box::new(CompoundPolicy::new(Box::new(trigger), Box::new(roller))),
+            )
+            .unwrap()
     }
 
     /// Parse logging config and setup loggers
     ///
     /// This is not part of the config file as it is too complex to be parsed into simple structs
-    fn load_logging_config(config: &DaemonConfig, holder: &mut SimbiotaLoggerHolder) {
+    /// With `foreground`, everything from info level is also logged to stderr
+    fn load_logging_config(
+        config: &DaemonConfig,
+        holder: &mut SimbiotaLoggerHolder,
+        foreground: bool,
+    ) {
         warn!("switching logging systems...");
         let doc = &config.raw_config;
 
         let Some(logging_config) = &doc.as_hash().unwrap().get(&Yaml::String("logger".to_owned())) else {
             warn!("logging config not found, using default settings");
 
-            let warn_output = FileAppender::builder()
-                .encoder(Box::<PatternEncoder>::default())
-                .build("/var/log/simbiota.log")
-                .unwrap();
+            let warn_output = Self::rolling_file_appender(
+                "/var/log/simbiota.log",
+                true,
+                DEFAULT_LOG_MAX_SIZE_MB,
+                DEFAULT_LOG_KEEP,
+            );
 
-            let config = log4rs::Config::builder()
-                .appender(Appender::builder().build("output_file", Box::new(warn_output)))
-                .build(Root::builder().appender("output_file").build(LevelFilter::Warn))
-                .unwrap();
+            let mut config = log4rs::Config::builder().appender(
+                Appender::builder()
+                    .filter(Box::new(ThresholdFilter::new(LevelFilter::Warn)))
+                    .build("output_file", Box::new(warn_output)),
+            );
+            let mut root = Root::builder().appender("output_file");
+            if foreground {
+                config = config.appender(Self::foreground_appender("foreground"));
+                root = root.appender("foreground");
+            }
+            let config = config.build(root.build(LevelFilter::Info)).unwrap();
             let logger = log4rs::Logger::new(config);
             holder.set_logger(Box::new(logger));
             warn!("-------------------------");
@@ -345,10 +642,16 @@ impl SimbiotaClientDaemon {
                         } else {
                             true
                         };
-                    let file_appender = FileAppender::builder()
-                        .encoder(Box::<PatternEncoder>::default())
-                        .build(path)
-                        .unwrap();
+                    let max_size_mb = logger_config
+                        .get(&Yaml::String("max_size_mb".to_string()))
+                        .map(|m| m.as_i64().expect("invalid max_size_mb for logger") as u64)
+                        .unwrap_or(DEFAULT_LOG_MAX_SIZE_MB);
+                    let keep = logger_config
+                        .get(&Yaml::String("keep".to_string()))
+                        .map(|k| k.as_i64().expect("invalid keep for logger") as u32)
+                        .unwrap_or(DEFAULT_LOG_KEEP);
+                    let file_appender =
+                        Self::rolling_file_appender(path, append, max_size_mb, keep);
                     appenders.push(
                         Appender::builder()
                             .filter(Box::new(ThresholdFilter::new(level)))
@@ -378,10 +681,25 @@ impl SimbiotaClientDaemon {
                                 Box::new(SyslogAppender::new(facility, format)),
                             ),
                     );
+                } else if output == "journald" {
+                    appenders.push(
+                        Appender::builder()
+                            .filter(Box::new(ThresholdFilter::new(level)))
+                            .build(
+                                format!("appender_{}", appenders.len()),
+                                Box::new(JournaldAppender::new()),
+                            ),
+                    );
                 } else {
                     panic!("invalid logger output: {output}");
                 }
             }
+            if foreground {
+                appenders.push(Self::foreground_appender(&format!(
+                    "appender_{}",
+                    appenders.len()
+                )));
+            }
             let mut config = Config::builder();
             let mut root = Root::builder();
             for appender in appenders {
@@ -400,12 +718,24 @@ impl SimbiotaClientDaemon {
         }
     }
 
+    /// Console output of `--foreground`
+    fn foreground_appender(name: &str) -> Appender {
+        let console_appender = ConsoleAppender::builder()
+            .encoder(Box::<PatternEncoder>::default())
+            .target(Target::Stderr)
+            .build();
+        Appender::builder()
+            .filter(Box::new(ThresholdFilter::new(LevelFilter::Info)))
+            .build(name, Box::new(console_appender))
+    }
+
     fn register_providers() {
         info!("registering builtin providers");
         DetectionSystem::register_provider(
             "simple_tlsh",
             Arc::new(SimpleTLSHDetectorProvider::new()),
         );
+        DetectionSystem::register_provider("dummy", Arc::new(DummyDetectorProvider::new()));
         info!(
             "registered {} detector providers",
             DetectionSystem::registered_providers().len()
@@ -414,21 +744,46 @@ impl SimbiotaClientDaemon {
 }
 
 fn main() {
+    block_shutdown_signals();
     let mut daemon = SimbiotaClientDaemon::new();
     daemon.start();
 }
 
+fn shutdown_signals() -> libc::sigset_t {
+    /// SAFETY: Standard LibC calls
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        set
+    }
+}
+
+/// Block the shutdown signals before any thread is started, so every thread inherits
+/// the mask and the signals are only received by the signal handler thread
+fn block_shutdown_signals() {
+    let set = shutdown_signals();
+    /// SAFETY: Standard LibC calls
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+}
+
 /// Restarts the program in the background using `setsid`
+///
+/// The new process has no controlling terminal and its stdin, stdout and stderr are
+/// `/dev/null`, so it only reports through the configured loggers.
 fn restart_in_bg() {
-    let new_args: Vec<String> = std::env::args()
-        .skip(1)
-        .filter(|a| !a.contains("--bg"))
-        .collect();
+    let new_args: Vec<String> = std::env::args().skip(1).filter(|a| a != "--bg").collect();
     println!("Starting client in daemon mode");
     /// SAFETY: Standard LibC calls
     unsafe {
         Command::new(std::env::current_exe().unwrap().display().to_string())
             .args(new_args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
             .pre_exec(|| {
                 let res = setsid();
                 if res < 0 {
@@ -441,3 +796,79 @@ fn restart_in_bg() {
     }
     exit(0);
 }
+
+/// `root` and every directory below it on the same filesystem, symlinks are not followed
+fn directory_tree(root: &Path) -> Vec<PathBuf> {
+    let Ok(root_meta) = std::fs::symlink_metadata(root) else {
+        // reported when the path is marked
+        return vec![root.to_path_buf()];
+    };
+    let mut directories = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        match std::fs::read_dir(&directory) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let Ok(meta) = std::fs::symlink_metadata(entry.path()) else {
+                        continue;
+                    };
+                    // other mounts need their own path entry, like with a mount mark
+                    if meta.is_dir() && meta.dev() == root_meta.dev() {
+                        pending.push(entry.path());
+                    }
+                }
+            }
+            Err(e) => warn!("cannot list {}: {}", directory.display(), e),
+        }
+        directories.push(directory);
+    }
+    directories
+}
+
+/// What to do about a failed `fanotify_init`
+fn monitor_init_hint(error: &FanotifyInitError) -> String {
+    match error {
+        FanotifyInitError::NotImplemented => {
+            "the kernel does not support fanotify, recompile it with CONFIG_FANOTIFY and CONFIG_FANOTIFY_ACCESS_PERMISSIONS".to_string()
+        }
+        FanotifyInitError::InsufficientPermission => {
+            "CAP_SYS_ADMIN is required, run the daemon as root or grant it the capability".to_string()
+        }
+        FanotifyInitError::LimitReached => {
+            "too many fanotify groups or open files, raise /proc/sys/fs/fanotify/max_user_groups or the open file limit (ulimit -n, LimitNOFILE= in the systemd unit)".to_string()
+        }
+        FanotifyInitError::AllocationError => {
+            "the kernel could not allocate the fanotify group, free some memory and try again".to_string()
+        }
+        FanotifyInitError::InvalidArguments => {
+            "the kernel rejected the monitor flags, remove unsupported flags from monitor.flags".to_string()
+        }
+        FanotifyInitError::UnknownError { errno } => format!(
+            "unexpected error: {}",
+            std::io::Error::from_raw_os_error(*errno)
+        ),
+    }
+}
+
+/// Poll until the database file exists, e.g. while the updater unit downloads it
+fn wait_for_database(path: &Path) {
+    if path.exists() {
+        return;
+    }
+    info!("waiting for the database file at {}", path.display());
+    sd_notify::notify("STATUS=Waiting for the database file");
+    while !path.exists() {
+        thread::sleep(Duration::from_secs(1));
+    }
+    info!("database file found");
+}
+
+pub(crate) fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
@@ -1,16 +1,35 @@
-use libc::{c_uint, fanotify_event_metadata, fstat, gid_t, mode_t, off_t, stat, time_t, uid_t};
+use libc::{
+    c_uint, dev_t, fanotify_event_metadata, fstat, gid_t, ino_t, mode_t, off_t, stat, time_t, uid_t,
+};
+use linked_hash_map::LinkedHashMap;
 use log::debug;
+use serde::{Deserialize, Serialize};
 use simbiota_clientlib::api::cache::DetectionCache;
 use simbiota_clientlib::api::detector::DetectionResult;
-use std::collections::HashMap;
+use std::cell::RefCell;
 
 struct MemoryCacheEntry {
     pub data: StatBasedCacheData,
     pub result: DetectionResult,
 }
 
-#[derive(Eq, PartialEq)]
-struct StatBasedCacheData {
+/// Identifies a file independently of the path it was opened with, so hard links and renamed
+/// files share one cache entry
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub(crate) struct InodeKey {
+    dev: dev_t,
+    ino: ino_t,
+}
+
+impl InodeKey {
+    /// `<dev>:<ino>`, the key in the disk cache file
+    pub(crate) fn to_key_string(self) -> String {
+        format!("{}:{}", self.dev, self.ino)
+    }
+}
+
+#[derive(Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StatBasedCacheData {
     size: off_t,
     uid: uid_t,
     gid: gid_t,
@@ -19,72 +38,185 @@ struct StatBasedCacheData {
     mode: mode_t,
 }
 
-impl From<fanotify_event_metadata> for StatBasedCacheData {
-    fn from(value: fanotify_event_metadata) -> Self {
-        let mut stat = std::mem::MaybeUninit::<stat>::zeroed();
-        /// SAFETY: Normal LibC call, stat is a zeroed struct
-        let stat_res = unsafe { fstat(value.fd, stat.as_mut_ptr()) };
-        if stat_res < 0 {
-            panic!("stat failed");
-        }
-        /// SAFETY: If `fstat` failed, we paniced before, if it has not, we are OK
-        let stat = unsafe { stat.assume_init() };
-        Self {
-            size: stat.st_size,
-            uid: stat.st_uid,
-            gid: stat.st_gid,
-            mtime: stat.st_mtime,
-            ctime: stat.st_ctime,
-            mode: stat.st_mode,
-        }
+/// Stat the file of the event, returns its cache key and the data validating the entry
+pub(crate) fn stat_event(value: &fanotify_event_metadata) -> (InodeKey, StatBasedCacheData) {
+    let mut stat = std::mem::MaybeUninit::<stat>::zeroed();
+    /// SAFETY: Normal LibC call, stat is a zeroed struct
+    let stat_res = unsafe { fstat(value.fd, stat.as_mut_ptr()) };
+    if stat_res < 0 {
+        panic!("stat failed");
     }
+    /// SAFETY: If `fstat` failed, we paniced before, if it has not, we are OK
+    let stat = unsafe { stat.assume_init() };
+    let key = InodeKey {
+        dev: stat.st_dev,
+        ino: stat.st_ino,
+    };
+    let data = StatBasedCacheData {
+        size: stat.st_size,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        mtime: stat.st_mtime,
+        ctime: stat.st_ctime,
+        mode: stat.st_mode,
+    };
+    (key, data)
 }
 
+/// In-memory detection cache with least recently used eviction
+///
+/// The map is kept in access order, lookups move the entry to the back and
+/// the front entry is evicted when the cache grows over `max_entries`.
 pub struct MemoryDetectionCache {
-    cache_map: HashMap<String, MemoryCacheEntry>,
+    cache_map: RefCell<LinkedHashMap<InodeKey, MemoryCacheEntry>>,
+    max_entries: Option<usize>,
 }
 
 impl MemoryDetectionCache {
-    pub fn new() -> Self {
+    pub fn new(max_entries: Option<usize>) -> Self {
         Self {
-            cache_map: HashMap::new(),
+            cache_map: RefCell::new(LinkedHashMap::new()),
+            max_entries,
         }
     }
 }
 
 impl DetectionCache<fanotify_event_metadata> for MemoryDetectionCache {
-    fn get_result_for(
-        &self,
-        key: &str,
-        event_meta: &fanotify_event_metadata,
-    ) -> Option<DetectionResult> {
-        let Some(entry) = self.cache_map.get(key) else {
-            return None
-        };
-        let current_data = StatBasedCacheData::from(*event_meta);
+    fn get_result_for(&self, event_meta: &fanotify_event_metadata) -> Option<DetectionResult> {
+        let (key, current_data) = stat_event(event_meta);
+        let mut cache_map = self.cache_map.borrow_mut();
+        let entry = cache_map.get_refresh(&key)?;
         if current_data == entry.data {
             return Some(entry.result);
         }
         None
     }
 
-    fn set_result_for(
-        &mut self,
-        key: String,
-        data: &fanotify_event_metadata,
-        result: DetectionResult,
-    ) {
-        let current_data = StatBasedCacheData::from(*data);
-        self.cache_map.insert(
+    fn set_result_for(&mut self, data: &fanotify_event_metadata, result: DetectionResult) {
+        let (key, current_data) = stat_event(data);
+        let mut cache_map = self.cache_map.borrow_mut();
+        // insert moves existing keys to the back as well
+        cache_map.insert(
             key,
             MemoryCacheEntry {
                 data: current_data,
                 result,
             },
         );
+        if let Some(max_entries) = self.max_entries {
+            while cache_map.len() > max_entries {
+                if let Some((evicted, _)) = cache_map.pop_front() {
+                    debug!("evicted cache entry: {:?}", evicted);
+                }
+            }
+        }
         if cfg!(debug_log) {
-            let cache_size = self.cache_map.keys().len() * std::mem::size_of::<MemoryCacheEntry>();
+            let cache_size = cache_map.keys().len() * std::mem::size_of::<MemoryCacheEntry>();
             debug!("cache size is {} bytes + keys", cache_size);
         }
     }
+
+    fn clear(&mut self) {
+        self.cache_map.borrow_mut().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+    use std::path::PathBuf;
+
+    /// Files kept open for the test, with events pointing at their descriptors
+    struct TestFiles {
+        dir: PathBuf,
+        files: Vec<File>,
+    }
+
+    impl TestFiles {
+        fn new(name: &str, count: usize) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "simbiota-cache-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let files = (0..count)
+                .map(|i| File::create(dir.join(i.to_string())).unwrap())
+                .collect();
+            Self { dir, files }
+        }
+
+        fn event(&self, index: usize) -> fanotify_event_metadata {
+            // SAFETY: fanotify_event_metadata is a plain C struct, all zeroes is valid
+            let mut event: fanotify_event_metadata = unsafe { std::mem::zeroed() };
+            event.fd = self.files[index].as_raw_fd();
+            event
+        }
+    }
+
+    impl Drop for TestFiles {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    const MATCH: DetectionResult = DetectionResult::Match {
+        signature: Some(1),
+        value: Some(10),
+    };
+
+    #[test]
+    fn evicts_least_recently_used_over_max_entries() {
+        let files = TestFiles::new("lru", 4);
+        let mut cache = MemoryDetectionCache::new(Some(3));
+        cache.set_result_for(&files.event(0), DetectionResult::NoMatch);
+        cache.set_result_for(&files.event(1), MATCH);
+        cache.set_result_for(&files.event(2), DetectionResult::NoMatch);
+
+        // refresh 0, so 1 becomes the least recently used entry
+        assert_eq!(
+            cache.get_result_for(&files.event(0)),
+            Some(DetectionResult::NoMatch)
+        );
+        cache.set_result_for(&files.event(3), DetectionResult::NoMatch);
+
+        assert_eq!(cache.cache_map.borrow().len(), 3);
+        assert_eq!(cache.get_result_for(&files.event(1)), None);
+        assert_eq!(
+            cache.get_result_for(&files.event(0)),
+            Some(DetectionResult::NoMatch)
+        );
+        assert_eq!(
+            cache.get_result_for(&files.event(2)),
+            Some(DetectionResult::NoMatch)
+        );
+        assert_eq!(
+            cache.get_result_for(&files.event(3)),
+            Some(DetectionResult::NoMatch)
+        );
+    }
+
+    #[test]
+    fn unbounded_without_max_entries() {
+        let files = TestFiles::new("unbounded", 4);
+        let mut cache = MemoryDetectionCache::new(None);
+        for i in 0..4 {
+            cache.set_result_for(&files.event(i), MATCH);
+        }
+        assert_eq!(cache.cache_map.borrow().len(), 4);
+        for i in 0..4 {
+            assert_eq!(cache.get_result_for(&files.event(i)), Some(MATCH));
+        }
+    }
+
+    #[test]
+    fn changed_file_is_not_served_from_cache() {
+        let files = TestFiles::new("changed", 1);
+        let mut cache = MemoryDetectionCache::new(Some(1));
+        cache.set_result_for(&files.event(0), DetectionResult::NoMatch);
+        files.files[0].set_len(1).unwrap();
+        assert_eq!(cache.get_result_for(&files.event(0)), None);
+    }
 }
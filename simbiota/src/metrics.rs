@@ -0,0 +1,137 @@
+use crate::detection_system::DetectionCounters;
+use log::{debug, info, warn};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Serves the runtime statistics on `/metrics` in the Prometheus text format
+pub struct MetricsServer {
+    listener: TcpListener,
+    counters: Arc<DetectionCounters>,
+    started: Instant,
+}
+
+impl MetricsServer {
+    pub fn bind(
+        address: SocketAddr,
+        counters: Arc<DetectionCounters>,
+        started: Instant,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        info!("metrics endpoint: http://{}/metrics", address);
+        Ok(Self {
+            listener,
+            counters,
+            started,
+        })
+    }
+
+    pub fn listen(&self) -> ! {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = self.serve(stream) {
+                        debug!("metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => warn!("metrics connection failed: {}", e),
+            }
+        }
+        panic!("Metrics server stopped listening");
+    }
+
+    /// Answer a single request, the connection is closed afterwards
+    fn serve(&self, stream: TcpStream) -> std::io::Result<()> {
+        // requests are served one at a time, a stuck client must not block the scraper
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // the headers are not needed, but must be read before answering
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("");
+        let target = parts.next().unwrap_or("");
+        let path = target.split('?').next().unwrap_or("");
+        let (status, body) = match (method, path) {
+            ("GET", "/metrics") => ("200 OK", self.render()),
+            ("GET", _) => ("404 Not Found", "not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+        };
+        let mut writer = &stream;
+        write!(
+            writer,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        writer.flush()
+    }
+
+    fn render(&self) -> String {
+        let counters = &self.counters;
+        let load = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "simbiota_files_scanned_total",
+            "counter",
+            "Files checked, including cache hits.",
+            load(&counters.files_scanned).to_string(),
+        );
+        metric(
+            "simbiota_cache_hits_total",
+            "counter",
+            "Files answered from the detection cache.",
+            load(&counters.cache_hits).to_string(),
+        );
+        metric(
+            "simbiota_cache_misses_total",
+            "counter",
+            "Files not found in the detection cache.",
+            load(&counters.cache_misses).to_string(),
+        );
+        metric(
+            "simbiota_detections_total",
+            "counter",
+            "Positive detections.",
+            load(&counters.detections).to_string(),
+        );
+        metric(
+            "simbiota_uptime_seconds",
+            "gauge",
+            "Time since the daemon started.",
+            self.started.elapsed().as_secs().to_string(),
+        );
+        // a summary without quantiles, the average is sum / count
+        let _ = writeln!(
+            out,
+            "# HELP simbiota_scan_duration_seconds Time the detector spent on a file."
+        );
+        let _ = writeln!(out, "# TYPE simbiota_scan_duration_seconds summary");
+        let _ = writeln!(
+            out,
+            "simbiota_scan_duration_seconds_sum {}",
+            load(&counters.scan_time_micros) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            out,
+            "simbiota_scan_duration_seconds_count {}",
+            load(&counters.scans)
+        );
+        out
+    }
+}
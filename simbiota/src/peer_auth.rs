@@ -0,0 +1,116 @@
+//! Identifying the processes connecting to the control socket
+
+use std::ffi::{CStr, CString};
+use std::io::{Error, ErrorKind};
+use std::mem::MaybeUninit;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+/// Credentials of the peer process at the time it connected, from `SO_PEERCRED`
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+pub fn peer_credentials(stream: &UnixStream) -> std::io::Result<PeerCredentials> {
+    let mut cred = MaybeUninit::<libc::ucred>::zeroed();
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    /// SAFETY: the buffer is large enough for `ucred` and `len` holds its size
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            cred.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    /// SAFETY: filled in by the kernel
+    let cred = unsafe { cred.assume_init() };
+    Ok(PeerCredentials {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+/// The id of the group called `name`
+pub fn group_id(name: &str) -> std::io::Result<u32> {
+    let name = CString::new(name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut group = MaybeUninit::<libc::group>::zeroed();
+    let mut result = std::ptr::null_mut();
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    /// SAFETY: every pointer is valid for the duration of the call
+    let error = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            group.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if error != 0 {
+        return Err(Error::from_raw_os_error(error));
+    }
+    if result.is_null() {
+        return Err(Error::new(ErrorKind::NotFound, "no such group"));
+    }
+    /// SAFETY: `result` points to `group`, which was filled in
+    let group = unsafe { group.assume_init() };
+    Ok(group.gr_gid)
+}
+
+/// Whether the peer is a member of `gid`, as its primary group or as a supplementary group of
+/// its user
+pub fn is_member(peer: &PeerCredentials, gid: u32) -> bool {
+    if peer.gid == gid {
+        return true;
+    }
+    let Some(user) = user_name(peer.uid) else {
+        return false;
+    };
+    let mut groups = vec![0 as libc::gid_t; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        /// SAFETY: `groups` has room for `count` entries
+        let result =
+            unsafe { libc::getgrouplist(user.as_ptr(), peer.gid, groups.as_mut_ptr(), &mut count) };
+        if result >= 0 {
+            groups.truncate(count as usize);
+            return groups.contains(&gid);
+        }
+        // too many groups, `count` is the number needed
+        if count as usize <= groups.len() {
+            return false;
+        }
+        groups.resize(count as usize, 0);
+    }
+}
+
+fn user_name(uid: u32) -> Option<CString> {
+    let mut passwd = MaybeUninit::<libc::passwd>::zeroed();
+    let mut result = std::ptr::null_mut();
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    /// SAFETY: every pointer is valid for the duration of the call
+    let error = unsafe {
+        libc::getpwuid_r(
+            uid,
+            passwd.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if error != 0 || result.is_null() {
+        return None;
+    }
+    /// SAFETY: `pw_name` points into `buffer`, which is still alive
+    let name = unsafe { CStr::from_ptr(passwd.assume_init_ref().pw_name) };
+    Some(name.to_owned())
+}
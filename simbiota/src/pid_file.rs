@@ -0,0 +1,41 @@
+use anyhow::bail;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+
+/// File containing the PID of the running daemon, for init scripts and monitoring
+#[derive(Clone)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the PID of this process to `path`
+    ///
+    /// An existing file is only replaced if the process it refers to is not running anymore.
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        if let Ok(content) = std::fs::read_to_string(path) {
+            if let Ok(pid) = content.trim().parse::<libc::pid_t>() {
+                if pid != std::process::id() as libc::pid_t && process_alive(pid) {
+                    bail!("already running with PID {}", pid);
+                }
+            }
+            info!("replacing stale PID file {}", path.display());
+        }
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn remove(&self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("failed to remove PID file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn process_alive(pid: libc::pid_t) -> bool {
+    /// SAFETY: Standard LibC call, signal 0 only checks for the process
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
@@ -1,22 +1,51 @@
-use crate::daemon_config::DaemonConfig;
-use std::ffi::{OsStr, OsString};
+use crate::daemon_config::{DaemonConfig, QuarantineEvictionPolicy};
+use anyhow::{anyhow, bail};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use std::ffi::{CString, OsStr, OsString};
 use std::fs::{File, OpenOptions, Permissions};
-use std::io::{Read, Write};
+use std::io::{Read, Seek, Write};
 
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
 pub struct QuarantineEntryInfo {
+    /// Original path for display, lossy if the path is not valid UTF-8
     pub original_path: String,
+    /// Exact original path, only stored if it is not valid UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_path_raw: Option<OsString>,
     pub uid: u32,
     pub gid: u32,
     pub mode: u32,
+    #[serde(default)]
+    pub encryption: Option<QuarantineEncryptionInfo>,
+    /// Unix timestamp of the time the file was moved to quarantine
+    #[serde(default)]
+    pub quarantined_at: u64,
+    /// Size of the original file, 0 for entries quarantined by older versions
+    #[serde(default)]
+    pub size: u64,
+}
+
+/// Parameters needed to decrypt an encrypted quarantine entry.
+///
+/// The key is derived from the configured passphrase and `salt`, the entry
+/// contents are sealed with ChaCha20-Poly1305 using `nonce`.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct QuarantineEncryptionInfo {
+    pub salt: Vec<u8>,
+    pub nonce: Vec<u8>,
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
@@ -28,6 +57,14 @@ pub struct LegacyQuarantineEntryInfo {
 }
 
 impl QuarantineEntryInfo {
+    /// The path the file is restored to
+    pub fn path(&self) -> PathBuf {
+        match &self.original_path_raw {
+            Some(raw) => PathBuf::from(raw),
+            None => PathBuf::from(&self.original_path),
+        }
+    }
+
     pub fn serialize(&self) -> String {
         serde_json::to_string(self).expect("failed to serialize quarantine entry")
     }
@@ -44,6 +81,67 @@ struct QuaratineEntry {
 
 pub(crate) struct Quarantine {
     quarantine_dir: PathBuf,
+    passphrase: Option<String>,
+    max_size: Option<u64>,
+    max_entries: Option<usize>,
+    eviction: QuarantineEvictionPolicy,
+}
+
+/// PBKDF2 iteration count used to derive the entry encryption keys
+const KEY_DERIVATION_ROUNDS: u32 = 100_000;
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, KEY_DERIVATION_ROUNDS, &mut key);
+    key
+}
+
+fn encrypt_contents(
+    passphrase: &str,
+    contents: &[u8],
+) -> anyhow::Result<(QuarantineEncryptionInfo, Vec<u8>)> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let encrypted = cipher
+        .encrypt(&nonce, contents)
+        .map_err(|_| anyhow!("failed to encrypt quarantine entry"))?;
+    Ok((
+        QuarantineEncryptionInfo {
+            salt: salt.to_vec(),
+            nonce: nonce.to_vec(),
+        },
+        encrypted,
+    ))
+}
+
+fn decrypt_contents(
+    passphrase: &str,
+    encryption: &QuarantineEncryptionInfo,
+    contents: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    if encryption.nonce.len() != 12 {
+        bail!("invalid nonce in quarantine entry info");
+    }
+    let key = derive_key(passphrase, &encryption.salt);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    cipher
+        .decrypt(Nonce::from_slice(&encryption.nonce), contents)
+        .map_err(|_| {
+            anyhow!("failed to decrypt quarantine entry: wrong passphrase or corrupted entry")
+        })
+}
+
+/// Change the owner of a restored file back to the original one
+fn restore_ownership(path: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: LibC call with a valid, NUL terminated path
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
 }
 
 impl Quarantine {
@@ -54,8 +152,18 @@ impl Quarantine {
         }
         std::fs::set_permissions(dir_path, Permissions::from_mode(0o0700))
             .expect("failed to set quarantine directory permissions");
+        let passphrase = if daemon_config.quarantine.encrypt {
+            info!("quarantine encryption enabled");
+            daemon_config.quarantine.passphrase.clone()
+        } else {
+            None
+        };
         Self {
             quarantine_dir: daemon_config.quarantine.path.clone(),
+            passphrase,
+            max_size: daemon_config.quarantine.max_size,
+            max_entries: daemon_config.quarantine.max_entries,
+            eviction: daemon_config.quarantine.eviction,
         }
     }
 
@@ -112,9 +220,18 @@ impl Quarantine {
                     warn!("converting legacy quarantine entry info to new format: {filename}");
                     let info = QuarantineEntryInfo {
                         original_path: legacy_info.original_path.to_string_lossy().to_string(),
+                        original_path_raw: legacy_info
+                            .original_path
+                            .to_str()
+                            .is_none()
+                            .then(|| legacy_info.original_path.clone()),
                         uid: legacy_info.uid,
                         gid: legacy_info.gid,
                         mode: legacy_info.mode,
+                        encryption: None,
+                        quarantined_at: 0,
+                        // legacy entries are never encrypted
+                        size: entry.metadata().map(|m| m.len()).unwrap_or(0),
                     };
                     std::fs::write(&info_path, info.serialize())
                         .expect("failed to write quarantine entry info");
@@ -158,58 +275,251 @@ impl Quarantine {
             .map(|e| e.info.clone())
     }
 
+    /// Write every entry with its `.info` metadata into a new tar archive at `dest`
+    ///
+    /// Entries are archived as stored, encrypted entries stay encrypted and can be decrypted
+    /// later with the salt and nonce from their metadata. Returns the number of exported entries.
+    pub fn export(&self, dest: &Path) -> anyhow::Result<usize> {
+        if !dest.is_absolute() {
+            bail!("export path must be absolute");
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(dest)?;
+        let mut archive = tar::Builder::new(file);
+        let entries = self.get_stored_entries();
+        for entry in &entries {
+            let info_name = format!(".{}.info", entry.id);
+            archive.append_path_with_name(self.quarantine_dir.join(&entry.id), &entry.id)?;
+            archive.append_path_with_name(self.quarantine_dir.join(&info_name), &info_name)?;
+        }
+        archive.into_inner()?.sync_all()?;
+        info!(
+            "exported {} quarantine entries to {}",
+            entries.len(),
+            dest.display()
+        );
+        Ok(entries.len())
+    }
+
+    /// Returns the number of entries and their total size in bytes
+    pub fn get_stats(&self) -> (usize, u64) {
+        let entries = self.get_stored_entries();
+        let total_size = entries.iter().map(|e| self.stored_entry_size(e)).sum();
+        (entries.len(), total_size)
+    }
+
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    pub fn max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    fn stored_entry_size(&self, entry: &QuaratineEntry) -> u64 {
+        std::fs::metadata(self.quarantine_dir.join(&entry.id))
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    /// Delete an entry and its info file.
+    ///
+    /// The info file is removed first: an entry without info is never listed and gets
+    /// cleaned up by [`Quarantine::get_stored_entries`], so a failure in between cannot
+    /// leave a half-removed entry behind.
+    fn remove_stored_entry(&self, entry: &QuaratineEntry) -> std::io::Result<()> {
+        std::fs::remove_file(self.quarantine_dir.join(format!(".{}.info", &entry.id)))?;
+        std::fs::remove_file(self.quarantine_dir.join(&entry.id))
+    }
+
+    /// Make sure a new entry with the given size fits into the configured limits.
+    ///
+    /// Depending on the eviction policy, either the oldest entries are removed until the
+    /// new one fits, or `false` is returned and the new entry should not be stored.
+    fn make_room(&self, incoming_size: u64) -> bool {
+        if self.max_size.is_some_and(|max| incoming_size > max) {
+            error!("file is larger than the quarantine size limit");
+            return false;
+        }
+
+        let mut entries = self.get_stored_entries();
+        entries.sort_by_key(|e| e.info.quarantined_at);
+        let mut count = entries.len();
+        let mut total_size: u64 = entries.iter().map(|e| self.stored_entry_size(e)).sum();
+        let exceeds = |count: usize, total_size: u64| {
+            self.max_entries.is_some_and(|max| count + 1 > max)
+                || self
+                    .max_size
+                    .is_some_and(|max| total_size + incoming_size > max)
+        };
+
+        let mut oldest = entries.iter();
+        while exceeds(count, total_size) {
+            if self.eviction == QuarantineEvictionPolicy::Refuse {
+                error!("quarantine is full, refusing to store new entry");
+                return false;
+            }
+            let Some(entry) = oldest.next() else {
+                return false;
+            };
+            let size = self.stored_entry_size(entry);
+            warn!(
+                "quarantine is full, evicting oldest entry: {}",
+                entry.info.original_path
+            );
+            if let Err(e) = self.remove_stored_entry(entry) {
+                error!("failed to evict quarantine entry: {e}");
+                return false;
+            }
+            count -= 1;
+            total_size -= size;
+        }
+        true
+    }
+
     pub fn remove_entry(&mut self, entry: QuarantineEntryInfo) {
         let entries = self.get_stored_entries();
         let e = entries.iter().find(|e| e.info == entry);
         if let Some(entry) = e {
-            std::fs::remove_file(self.quarantine_dir.join(&entry.id))
+            self.remove_stored_entry(entry)
                 .expect("failed to remove quarantine entry");
-            std::fs::remove_file(self.quarantine_dir.join(format!(".{}.info", &entry.id)))
-                .expect("failed to remove quarantine entry info");
             self.get_stored_entries();
         }
     }
 
-    pub fn restore_entry(&mut self, entry: QuarantineEntryInfo) {
+    pub fn restore_entry(&mut self, entry: QuarantineEntryInfo) -> anyhow::Result<()> {
         let entries = self.get_stored_entries();
-        let e = entries.iter().find(|e| e.info == entry);
-        if let Some(entry) = e {
-            std::fs::rename(
-                self.quarantine_dir.join(&entry.id),
-                &entry.info.original_path,
-            )
-            .expect("failed to remove quarantine entry");
-            std::fs::set_permissions(
-                &entry.info.original_path,
-                Permissions::from_mode(entry.info.mode),
-            )
-            .expect("failed to set file permissions");
-            std::fs::remove_file(self.quarantine_dir.join(format!(".{}.info", &entry.id)))
-                .expect("failed to remove quarantine entry info");
-            self.get_stored_entries();
+        let Some(entry) = entries.iter().find(|e| e.info == entry) else {
+            bail!("quarantine entry not found");
+        };
+        self.restore_stored_entry(entry, &entry.info.path())
+    }
+
+    /// Restore an entry into `dest_dir` under its original file name, instead of its original path
+    ///
+    /// An existing file at the destination is only replaced if `force` is set.
+    pub fn restore_entry_to(
+        &mut self,
+        entry: QuarantineEntryInfo,
+        dest_dir: &Path,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let entries = self.get_stored_entries();
+        let Some(entry) = entries.iter().find(|e| e.info == entry) else {
+            bail!("quarantine entry not found");
+        };
+        if !dest_dir.is_absolute() || dest_dir.components().any(|c| c == Component::ParentDir) {
+            bail!("destination must be an absolute path without '..' components");
+        }
+        if !dest_dir.is_dir() {
+            bail!("destination is not a directory: {}", dest_dir.display());
+        }
+        let original_path = entry.info.path();
+        let Some(file_name) = original_path.file_name() else {
+            bail!("quarantine entry has no file name");
+        };
+        let dest = dest_dir.join(file_name);
+        // symlink_metadata, so a dangling symlink is not followed and replaced silently
+        if dest.symlink_metadata().is_ok() && !force {
+            bail!("destination already exists: {}", dest.display());
+        }
+        self.restore_stored_entry(entry, &dest)
+    }
+
+    /// Restore every entry to its original location
+    ///
+    /// Entries whose original path exists again are not overwritten. A failed entry stays in
+    /// quarantine and does not stop the others. Returns the number of restored entries and the
+    /// original path and error of every failed one.
+    pub fn restore_all(&mut self) -> (usize, Vec<(String, String)>) {
+        self.apply_to_all(|quarantine, entry| {
+            let dest = entry.info.path();
+            if dest.symlink_metadata().is_ok() {
+                bail!("original path already exists");
+            }
+            quarantine.restore_stored_entry(entry, &dest)
+        })
+    }
+
+    /// Delete every entry, same return value as [`Quarantine::restore_all`]
+    pub fn remove_all(&mut self) -> (usize, Vec<(String, String)>) {
+        self.apply_to_all(|quarantine, entry| Ok(quarantine.remove_stored_entry(entry)?))
+    }
+
+    fn apply_to_all(
+        &self,
+        op: impl Fn(&Self, &QuaratineEntry) -> anyhow::Result<()>,
+    ) -> (usize, Vec<(String, String)>) {
+        let mut succeeded = 0;
+        let mut failed = Vec::new();
+        for entry in self.get_stored_entries() {
+            match op(self, &entry) {
+                Ok(()) => succeeded += 1,
+                Err(e) => failed.push((entry.info.original_path.clone(), e.to_string())),
+            }
         }
+        (succeeded, failed)
     }
 
-    pub fn add_file(&mut self, filename: &str) {
-        warn!("moving file to quarantine: {filename}");
-        let original_path = Path::new(filename);
+    fn restore_stored_entry(&self, entry: &QuaratineEntry, dest: &Path) -> anyhow::Result<()> {
+        let entry_path = self.quarantine_dir.join(&entry.id);
+        if let Some(encryption) = &entry.info.encryption {
+            // decrypt before touching the original path, so a wrong key never writes garbage back
+            let Some(passphrase) = &self.passphrase else {
+                bail!("quarantine entry is encrypted but no passphrase is configured");
+            };
+            let contents = std::fs::read(&entry_path)?;
+            let decrypted = decrypt_contents(passphrase, encryption, &contents)?;
+            std::fs::write(dest, decrypted)?;
+            std::fs::remove_file(&entry_path)?;
+        } else if let Err(e) = std::fs::rename(&entry_path, dest) {
+            // the destination may be on another filesystem than the quarantine
+            if e.raw_os_error() != Some(libc::EXDEV) {
+                return Err(e.into());
+            }
+            std::fs::copy(&entry_path, dest)?;
+            std::fs::remove_file(&entry_path)?;
+        }
+        std::fs::set_permissions(dest, Permissions::from_mode(entry.info.mode))?;
+        restore_ownership(dest, entry.info.uid, entry.info.gid)?;
+        std::fs::remove_file(self.quarantine_dir.join(format!(".{}.info", &entry.id)))?;
+        self.get_stored_entries();
+        Ok(())
+    }
+
+    /// Move a file to quarantine, returns the id of the new entry
+    pub fn add_file(&mut self, original_path: &Path) -> Option<String> {
+        warn!("moving file to quarantine: {}", original_path.display());
         if !original_path.exists() {
             warn!("file added to quarantine but it does not exists");
-            return;
+            return None;
         }
         let meta = original_path
             .metadata()
             .expect("failed to get file metadata");
+        if !self.make_room(meta.len()) {
+            error!("not moving file to quarantine: quarantine size limit reached");
+            return None;
+        }
 
-        let quarantine_entry = QuarantineEntryInfo {
-            original_path: original_path
-                .to_path_buf()
-                .into_os_string()
-                .into_string()
-                .expect("somehow ended up with a non-utf8 filename"),
+        let mut quarantine_entry = QuarantineEntryInfo {
+            original_path: original_path.display().to_string(),
+            original_path_raw: original_path
+                .to_str()
+                .is_none()
+                .then(|| original_path.as_os_str().to_os_string()),
             mode: meta.st_mode(),
             uid: meta.st_uid(),
             gid: meta.st_gid(),
+            encryption: None,
+            quarantined_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            size: meta.len(),
         };
 
         let entry_id = uuid::Uuid::new_v4();
@@ -217,14 +527,145 @@ impl Quarantine {
         entry_path.push(entry_id.to_string());
 
         // move file to quarantine
-        std::fs::rename(original_path, &entry_path).expect("failed to move file to quarantine");
+        if let Some(passphrase) = &self.passphrase {
+            let contents = std::fs::read(original_path).expect("failed to read file to quarantine");
+            let (encryption, encrypted) =
+                encrypt_contents(passphrase, &contents).expect("failed to encrypt file");
+            // never readable by others, not even before the permissions are set below
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o0000)
+                .open(&entry_path)
+                .and_then(|mut entry| entry.write_all(&encrypted))
+                .expect("failed to write quarantine entry");
+            std::fs::remove_file(original_path).expect("failed to remove quarantined file");
+            quarantine_entry.encryption = Some(encryption);
+        } else {
+            std::fs::rename(original_path, &entry_path).expect("failed to move file to quarantine");
+        }
         std::fs::set_permissions(&entry_path, Permissions::from_mode(0o0000))
             .expect("failed to set quarantine file permissions");
-        // store entry info alongside the file
-        let info_entry_path = self.quarantine_dir.join(format!(".{}.info", entry_id));
-        std::fs::write(&info_entry_path, quarantine_entry.serialize())
+        self.write_entry_info(&entry_id.to_string(), &quarantine_entry)
             .expect("failed to write quarantine entry info");
+        Some(entry_id.to_string())
+    }
+
+    /// Copy the contents of an open file to quarantine, returns the id of the new entry
+    ///
+    /// Used when the file cannot be moved by its path, e.g. it was deleted but is still open.
+    /// The original file is not removed. `original_path` is only stored for display.
+    pub fn add_from_fd(&mut self, file: &File, original_path: &str) -> Option<String> {
+        warn!("copying open file to quarantine: {}", original_path);
+        match self.try_add_from_fd(file, original_path) {
+            Ok(id) => id,
+            Err(e) => {
+                error!("failed to copy file to quarantine: {e}");
+                None
+            }
+        }
+    }
+
+    fn try_add_from_fd(
+        &mut self,
+        mut file: &File,
+        original_path: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let meta = file.metadata()?;
+        if !self.make_room(meta.len()) {
+            error!("not copying file to quarantine: quarantine size limit reached");
+            return Ok(None);
+        }
+        let mut contents = Vec::new();
+        file.rewind()?;
+        file.read_to_end(&mut contents)?;
+
+        let mut quarantine_entry = QuarantineEntryInfo {
+            original_path: original_path.to_string(),
+            original_path_raw: None,
+            mode: meta.st_mode(),
+            uid: meta.st_uid(),
+            gid: meta.st_gid(),
+            encryption: None,
+            quarantined_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            size: meta.len(),
+        };
+
+        let entry_id = uuid::Uuid::new_v4().to_string();
+        let entry_path = self.quarantine_dir.join(&entry_id);
+        let contents = match &self.passphrase {
+            Some(passphrase) => {
+                let (encryption, encrypted) = encrypt_contents(passphrase, &contents)?;
+                quarantine_entry.encryption = Some(encryption);
+                encrypted
+            }
+            None => contents,
+        };
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o0000)
+            .open(&entry_path)?
+            .write_all(&contents)?;
+        self.write_entry_info(&entry_id, &quarantine_entry)?;
+        Ok(Some(entry_id))
+    }
+
+    /// Store the entry info alongside the file
+    fn write_entry_info(&self, entry_id: &str, info: &QuarantineEntryInfo) -> std::io::Result<()> {
+        let info_entry_path = self.quarantine_dir.join(format!(".{}.info", entry_id));
+        std::fs::write(&info_entry_path, info.serialize())?;
         std::fs::set_permissions(&info_entry_path, Permissions::from_mode(0o0600))
-            .expect("failed to set quarantine file permissions");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry_for(path: &Path) -> QuarantineEntryInfo {
+        QuarantineEntryInfo {
+            original_path: path.display().to_string(),
+            original_path_raw: path
+                .to_str()
+                .is_none()
+                .then(|| path.as_os_str().to_os_string()),
+            uid: 1000,
+            gid: 1000,
+            mode: 0o100644,
+            encryption: None,
+            quarantined_at: 1_700_000_000,
+            size: 42,
+        }
+    }
+
+    #[test]
+    fn non_utf8_path_round_trip() {
+        let path = Path::new(OsStr::from_bytes(b"/tmp/invalid-\xff\xfe-name"));
+        let entry = entry_for(path);
+        assert!(entry.original_path_raw.is_some());
+
+        let json = entry.serialize();
+        // the entry info is a valid UTF-8 JSON document even for such paths
+        assert!(serde_json::from_str::<serde_json::Value>(&json).is_ok());
+        let restored = QuarantineEntryInfo::deserialize(&json);
+        assert_eq!(restored, entry);
+        assert_eq!(restored.path(), path);
+    }
+
+    #[test]
+    fn utf8_path_round_trip() {
+        let path = Path::new("/tmp/árvíztűrő tükörfúrógép");
+        let entry = entry_for(path);
+        assert!(entry.original_path_raw.is_none());
+
+        let json = entry.serialize();
+        assert!(!json.contains("original_path_raw"));
+        let restored = QuarantineEntryInfo::deserialize(&json);
+        assert_eq!(restored, entry);
+        assert_eq!(restored.path(), path);
     }
 }
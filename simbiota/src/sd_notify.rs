@@ -0,0 +1,25 @@
+use log::{debug, warn};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Send a state notification to systemd, see `sd_notify(3)`
+///
+/// Does nothing if the daemon was not started by systemd with `NOTIFY_SOCKET` set.
+pub fn notify(state: &str) {
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let socket_path = socket_path.to_string_lossy();
+    // '@' marks a socket in the abstract namespace
+    let address = match socket_path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(&*socket_path),
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        address.and_then(|address| socket.send_to_addr(state.as_bytes(), &address))
+    });
+    match result {
+        Ok(_) => debug!("sent notification to systemd: {}", state),
+        Err(e) => warn!("failed to notify systemd: {}", e),
+    }
+}
@@ -4,10 +4,11 @@ use log::{Level, Log, Record};
 use log4rs::append::Append;
 use log4rs::config::Appender;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
 use std::io::Write;
 use std::sync::Mutex;
-use syslog::{Facility, Formatter3164, Formatter5424, Logger, LoggerBackend};
+use syslog::{Facility, Formatter3164, Formatter5424, Logger, LoggerBackend, StructuredData};
 
 #[derive(Debug)]
 pub enum SyslogFormat {
@@ -79,6 +80,81 @@ impl Syslog for Syslog3164 {
     }
 }
 
+/// SD-ID of the structured data element, 32473 is the example enterprise number from RFC 5612
+const STRUCTURED_DATA_ID: &str = "simbiota@32473";
+
+struct Syslog5424 {
+    log: Logger<LoggerBackend, Formatter5424>,
+    message_id: u32,
+}
+
+impl Syslog5424 {
+    pub fn new(facility: Facility) -> Self {
+        let formatter = Formatter5424 {
+            facility,
+            hostname: None,
+            process: "simbiota".to_string(),
+            pid: std::process::id(),
+        };
+        Self {
+            log: syslog::unix(formatter).unwrap(),
+            message_id: 0,
+        }
+    }
+
+    fn message(&mut self, record: &Record) -> (u32, StructuredData, String) {
+        self.message_id = self.message_id.wrapping_add(1);
+        let mut params = BTreeMap::new();
+        params.insert("level".to_string(), record.level().to_string());
+        params.insert("target".to_string(), record.target().to_string());
+        let mut data = StructuredData::new();
+        data.insert(STRUCTURED_DATA_ID.to_string(), params);
+        (self.message_id, data, format!("{}", record.args()))
+    }
+}
+impl Debug for Syslog5424 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Syslog5424")
+    }
+}
+
+macro_rules! logme5424 {
+    ($slf:expr, $fun:tt, $record:expr) => {
+        let message = $slf.message($record);
+        $slf.log.$fun(message).unwrap();
+    };
+}
+impl Syslog for Syslog5424 {
+    fn log(&mut self, record: &Record) -> anyhow::Result<()> {
+        match record.level() {
+            Level::Error => {
+                logme5424!(self, err, record);
+                Ok(())
+            }
+            Level::Warn => {
+                logme5424!(self, err, record);
+                Ok(())
+            }
+            Level::Info => {
+                logme5424!(self, info, record);
+                Ok(())
+            }
+            Level::Debug => {
+                logme5424!(self, debug, record);
+                Ok(())
+            }
+            Level::Trace => {
+                logme5424!(self, notice, record);
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.log.backend.flush();
+    }
+}
+
 #[derive(Debug)]
 pub struct SyslogAppender {
     logger: Mutex<Box<dyn Syslog>>,
@@ -88,10 +164,7 @@ impl SyslogAppender {
     pub fn new(facility: Facility, format: SyslogFormat) -> Self {
         let logger: Box<dyn Syslog> = match format {
             syslog_appender::SyslogFormat::Format3164 => Box::new(Syslog3164::new(facility)),
-            //syslog_appender::SyslogFormat::Format5424 => Box::new(Syslog5424::new(facility)),
-            syslog_appender::SyslogFormat::Format5424 => {
-                panic!("currently not supported syslog format")
-            }
+            syslog_appender::SyslogFormat::Format5424 => Box::new(Syslog5424::new(facility)),
         };
 
         Self {
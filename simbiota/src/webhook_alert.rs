@@ -0,0 +1,57 @@
+use crate::daemon_config::DaemonConfig;
+use crate::detection_system::DetectionDetails;
+use crossbeam_channel::Sender;
+use log::{debug, info, warn};
+use std::sync::Arc;
+use std::{process, thread};
+
+/// Posts detections to an HTTP endpoint from a background thread
+///
+/// The body is a JSON object with the `path`, `time`, `host` and `detector` of the detection,
+/// which is accepted by the incoming webhooks of most chat and paging services.
+pub struct WebhookAlertSystem {
+    queue: Sender<DetectionDetails>,
+}
+
+impl WebhookAlertSystem {
+    pub fn new(config: Arc<DaemonConfig>) -> Self {
+        let (queue, alerts) = crossbeam_channel::unbounded::<DetectionDetails>();
+        thread::spawn(move || {
+            debug!("webhook alert thread id: {:?}", process::id());
+            let webhook = &config.webhook;
+            let agent = ureq::AgentBuilder::new().timeout(webhook.timeout).build();
+            let host = crate::hostname();
+            for alert in alerts {
+                let body = serde_json::json!({
+                    "path": alert.path,
+                    "time": alert.time.to_rfc3339(),
+                    "host": host,
+                    "detector": alert.detector,
+                    "signature": alert.signature,
+                    "distance": alert.distance,
+                });
+                let mut request = agent
+                    .post(&webhook.url)
+                    .set("Content-Type", "application/json");
+                for (name, value) in &webhook.headers {
+                    request = request.set(name, value);
+                }
+                match request.send_string(&body.to_string()) {
+                    Ok(_) => info!("webhook alert sent for {}", alert.path),
+                    Err(e) => warn!("failed to send webhook alert for {}: {}", alert.path, e),
+                }
+            }
+        });
+        Self { queue }
+    }
+
+    /// Queue a detection, it is posted without blocking the caller
+    pub fn queue_alert(&self, data: &DetectionDetails) {
+        if self.queue.send(data.clone()).is_err() {
+            warn!(
+                "webhook alert thread stopped, dropping alert for {}",
+                data.path
+            );
+        }
+    }
+}
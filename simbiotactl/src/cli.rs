@@ -3,6 +3,17 @@ use std::path::PathBuf;
 
 #[derive(Parser)]
 pub struct Cli {
+    /// Print daemon responses as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Control socket of the daemon, `@name` for an abstract socket or a path
+    #[arg(
+        long,
+        global = true,
+        env = simbiota_protocol::ENV_SOCKET,
+        default_value = simbiota_protocol::DEFAULT_SOCKET
+    )]
+    pub socket: String,
     #[command(subcommand)]
     pub subsys: Subsys,
 }
@@ -19,6 +30,63 @@ pub enum Subsys {
         #[command(subcommand)]
         command: QuarantineCommand,
     },
+    /// Show runtime statistics of the daemon
+    Stats,
+    /// Show the most recent detections
+    Events {
+        /// Number of detections to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Download the latest database now, the daemon reloads it when it is installed
+    Update {
+        /// Return once the update has started instead of waiting for it to finish
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Monitored path operations
+    Monitor {
+        #[command(subcommand)]
+        command: MonitorCommand,
+    },
+    /// Config file operations, these do not need a running daemon
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print a commented default config
+    Generate,
+    /// Check whether a config file is valid
+    Check { path: PathBuf },
+}
+
+#[derive(Subcommand)]
+pub enum MonitorCommand {
+    /// Start monitoring a path
+    Add {
+        path: PathBuf,
+        /// fanotify event mask, can be given multiple times
+        #[arg(short, long, default_value = "OPEN_EXEC_PERM")]
+        mask: Vec<String>,
+        /// Only mark the path if it is a directory
+        #[arg(long)]
+        dir: bool,
+        /// Create events for the immediate children of the directory
+        #[arg(long)]
+        children: bool,
+        /// Mark the mount containing the path
+        #[arg(long)]
+        mount: bool,
+        /// Mark the filesystem containing the path
+        #[arg(long)]
+        filesystem: bool,
+    },
+    /// Stop monitoring a path
+    Remove { path: PathBuf },
 }
 
 #[derive(Subcommand)]
@@ -42,7 +110,33 @@ pub enum QuarantineCommand {
     /// List quarantined files
     List,
     /// Restore a file from quarantine
-    Restore { id_or_path: String },
+    Restore {
+        id_or_path: String,
+        /// Restore into this directory instead of the original location
+        #[arg(long, value_name = "DIR")]
+        to: Option<PathBuf>,
+        /// Overwrite an existing file in the directory given with --to
+        #[arg(long, requires = "to")]
+        force: bool,
+    },
     /// Permanently delete a file from quarantine
     Delete { id_or_path: String },
+    /// Restore every file to its original location, existing files are not overwritten
+    RestoreAll {
+        /// Do not ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Permanently delete every file from quarantine
+    DeleteAll {
+        /// Do not ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Show the number and total size of quarantined files
+    Stats,
+    /// Archive every quarantined file with its metadata into a new tar file
+    ///
+    /// Encrypted entries are exported encrypted.
+    Export { file: PathBuf },
 }
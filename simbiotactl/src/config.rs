@@ -0,0 +1,66 @@
+use std::path::Path;
+use std::process::{exit, Command};
+
+/// The daemon binary, it owns the config parsers
+const DAEMON_PATH: &str = "/usr/sbin/simbiota";
+
+/// Same values the daemon uses when started without a config file
+pub const DEFAULT_CONFIG: &str = r#"# SIMBIoTA client configuration, see simbiota-config(5) for every option
+detector:
+  # class - the detector to be used, currently the only option is 'simple_tlsh'
+  class: simple_tlsh
+  # mode - 'enforce' blocks and quarantines detected files, 'observe' only reports them
+  mode: enforce
+  # scan_only - 'all' or 'executable' (ELF binaries and scripts starting with '#!')
+  scan_only: all
+  config:
+    # threshold - TLSH distance below which a file is considered a match
+    threshold: 40
+
+monitor:
+  flags:
+    - CLOEXEC
+    - UNLIMITED_MARKS
+    - UNLIMITED_QUEUE
+  paths:
+    - path: /usr/bin
+      dir: true
+      event_on_children: true
+      mask:
+        - OPEN_EXEC_PERM
+
+email:
+  # alerts need an smtp server and recipients, see simbiota-config(5)
+  enabled: false
+
+cache:
+  disable: false
+  # backend - 'memory' or 'disk'
+  backend: memory
+  max_entries: 100000
+
+database:
+  database_file: /var/lib/simbiota/database.sdb
+
+quarantine:
+  enabled: true
+  path: /var/lib/simbiota/quarantine
+
+pid_file: /var/run/simbiota.pid
+"#;
+
+/// Validate a config file by loading it with the daemon's parsers, exits with the daemon's status
+pub fn check(path: &Path) -> ! {
+    let status = Command::new(DAEMON_PATH)
+        .arg("--check-config")
+        .arg("--config")
+        .arg(path)
+        .status();
+    match status {
+        Ok(status) => exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            eprintln!("failed to run {}: {}", DAEMON_PATH, e);
+            exit(1);
+        }
+    }
+}
@@ -1,97 +1,389 @@
-use crate::cli::{Cli, QuarantineCommand, Subsys};
+use crate::cli::{Cli, ConfigCommand, MonitorCommand, QuarantineCommand, Subsys};
+use chrono::NaiveDateTime;
 use clap::Parser;
-use simbiota_protocol::{Command, CommandRequest, CommandResponse, Response};
-use std::io::{Read, Write};
-use std::os::unix::net::UnixStream;
+use simbiota_protocol::framing;
+use simbiota_protocol::{
+    Command, CommandRequest, CommandResponse, CommandStatus, DatabaseUpdateStatus, DetectionAction,
+    Response,
+};
+use std::io::{BufReader, IsTerminal, Write};
+use std::os::unix::net::{SocketAddr, UnixStream};
 use std::process::exit;
+use std::thread;
 use std::time::Duration;
 
 mod cli;
+mod config;
+
+/// How often `update` asks the daemon whether the update has finished
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 fn main() {
     let cli = Cli::parse();
 
-    let connection = UnixStream::connect_addr(&simbiota_protocol::socket_address());
-    if let Err(e) = connection {
-        eprintln!("failed to connect to control socket: {:?}", e.to_string());
-        exit(1);
+    // local operations, handled before connecting to the daemon
+    if let Subsys::Config { command } = &cli.subsys {
+        match command {
+            ConfigCommand::Generate => print!("{}", config::DEFAULT_CONFIG),
+            ConfigCommand::Check { path } => config::check(path),
+        }
+        return;
     }
-    let mut connection = connection.unwrap();
-    connection
-        .set_read_timeout(Some(Duration::from_secs(60)))
-        .unwrap();
-    connection
-        .set_write_timeout(Some(Duration::from_secs(60)))
-        .unwrap();
 
-    let output = match cli.subsys {
-        /*Subsys::Scan { command } => match command {
-            ScanCommand::Start { path, recursive } => {
-                let command = CommandRequest {
-                    command: Command::ManualScan {
-                        path: path.to_string_lossy().to_string(),
-                        recursive,
-                    },
-                };
-                serde_json::to_string(&command).unwrap()
+    // destructive operations are confirmed before connecting to the daemon
+    if let Subsys::Quarantine { command } = &cli.subsys {
+        let question = match command {
+            QuarantineCommand::RestoreAll { yes: false } => {
+                Some("Restore every quarantined file to its original location?")
             }
-            ScanCommand::List => String::new(),
-            ScanCommand::Cancel { .. } => String::new(),
+            QuarantineCommand::DeleteAll { yes: false } => {
+                Some("Permanently delete every quarantined file?")
+            }
+            _ => None,
+        };
+        if let Some(question) = question {
+            confirm(cli.json, question);
+        }
+    }
+
+    let address = match simbiota_protocol::parse_socket_address(&cli.socket) {
+        Ok(address) => address,
+        Err(e) => fail(
+            cli.json,
+            &format!("invalid control socket {}: {}", cli.socket, e),
+        ),
+    };
+    let wait_for_update = matches!(cli.subsys, Subsys::Update { no_wait: false });
+
+    let request = match cli.subsys {
+        /*Subsys::Scan { command } => match command {
+            ScanCommand::Start { path, recursive } => CommandRequest {
+                command: Command::ManualScan {
+                    path: path.to_string_lossy().to_string(),
+                    recursive,
+                },
+            },
+            ScanCommand::List => todo!(),
+            ScanCommand::Cancel { .. } => todo!(),
         },*/
         Subsys::Quarantine { command } => match command {
-            QuarantineCommand::List => {
-                let command = CommandRequest {
-                    command: Command::QueryQuarantine,
-                };
-                serde_json::to_string(&command).unwrap()
-            }
-            QuarantineCommand::Restore { id_or_path } => {
-                let command = CommandRequest {
-                    command: Command::RestoreQuarantine(id_or_path),
+            QuarantineCommand::List => CommandRequest {
+                command: Command::QueryQuarantineDetails,
+            },
+            QuarantineCommand::Restore {
+                id_or_path,
+                to,
+                force,
+            } => {
+                let command = match to {
+                    Some(dest) => Command::RestoreQuarantineTo {
+                        id_or_path,
+                        // the daemon resolves paths from its own working directory
+                        dest: std::fs::canonicalize(&dest)
+                            .unwrap_or(dest)
+                            .to_string_lossy()
+                            .to_string(),
+                        force,
+                    },
+                    None => Command::RestoreQuarantine(id_or_path),
                 };
-                serde_json::to_string(&command).unwrap()
+                CommandRequest { command }
             }
-            QuarantineCommand::Delete { id_or_path } => {
-                let command = CommandRequest {
-                    command: Command::DeleteQuarantine(id_or_path),
-                };
-                serde_json::to_string(&command).unwrap()
+            QuarantineCommand::Delete { id_or_path } => CommandRequest {
+                command: Command::DeleteQuarantine(id_or_path),
+            },
+            QuarantineCommand::RestoreAll { .. } => CommandRequest {
+                command: Command::RestoreAllQuarantine,
+            },
+            QuarantineCommand::DeleteAll { .. } => CommandRequest {
+                command: Command::DeleteAllQuarantine,
+            },
+            QuarantineCommand::Export { file } => {
+                // the daemon resolves paths from its own working directory
+                let dest = std::env::current_dir()
+                    .map(|cwd| cwd.join(&file))
+                    .unwrap_or(file);
+                CommandRequest {
+                    command: Command::ExportQuarantine {
+                        dest: dest.to_string_lossy().to_string(),
+                    },
+                }
             }
+            QuarantineCommand::Stats => CommandRequest {
+                command: Command::QuarantineStats,
+            },
+        },
+        Subsys::Monitor { command } => {
+            let command = match command {
+                MonitorCommand::Add {
+                    path,
+                    mask,
+                    dir,
+                    children,
+                    mount,
+                    filesystem,
+                } => Command::AddMonitorPath {
+                    path: path.to_string_lossy().to_string(),
+                    mask,
+                    dir,
+                    event_on_children: children,
+                    mount,
+                    filesystem,
+                },
+                MonitorCommand::Remove { path } => Command::RemoveMonitorPath {
+                    path: path.to_string_lossy().to_string(),
+                },
+            };
+            CommandRequest { command }
+        }
+        Subsys::Stats => CommandRequest {
+            command: Command::QueryStats,
+        },
+        Subsys::Events { limit } => CommandRequest {
+            command: Command::QueryEvents { limit },
+        },
+        Subsys::Update { .. } => CommandRequest {
+            command: Command::UpdateDatabase,
         },
+        Subsys::Config { .. } => unreachable!(),
     };
-    connection.write_all(output.as_ref()).unwrap();
-    connection.write_all("\n".as_ref()).unwrap();
-    connection.flush().unwrap();
-    let mut response_bytes = vec![];
+    let mut response = send_request(cli.json, &address, &request);
+    // the daemon updates on a worker thread, poll it until the update has finished
+    if wait_for_update {
+        while let (
+            CommandStatus::Success,
+            Response::DatabaseUpdateStatus(DatabaseUpdateStatus::Running),
+        ) = (&response.status, &response.response)
+        {
+            thread::sleep(UPDATE_POLL_INTERVAL);
+            let request = CommandRequest {
+                command: Command::UpdateDatabaseStatus,
+            };
+            response = send_request(cli.json, &address, &request);
+        }
+    }
+    if let Response::DatabaseUpdateStatus(DatabaseUpdateStatus::Failed(reason)) = &response.response
+    {
+        fail(cli.json, &format!("database update failed: {}", reason));
+    }
+    match response.status {
+        simbiota_protocol::CommandStatus::Failure(reason) if cli.json => print_json_error(&reason),
+        simbiota_protocol::CommandStatus::Failure(reason) => {
+            eprintln!("command failed: {}", reason)
+        }
+        simbiota_protocol::CommandStatus::Success if cli.json => print_json(response.response),
+        simbiota_protocol::CommandStatus::Success => print_response(response.response),
+    }
+}
+
+/// Send one request on a new connection and wait for the response
+fn send_request(json: bool, address: &SocketAddr, request: &CommandRequest) -> CommandResponse {
+    let mut connection = match UnixStream::connect_addr(address) {
+        Ok(connection) => connection,
+        Err(e) => fail(
+            json,
+            &format!("failed to connect to control socket: {:?}", e.to_string()),
+        ),
+    };
+    connection
+        .set_read_timeout(Some(Duration::from_secs(60)))
+        .unwrap();
     connection
-        .read_to_end(&mut response_bytes)
-        .expect("failed to read response");
+        .set_write_timeout(Some(Duration::from_secs(60)))
+        .unwrap();
+    if let Err(e) = framing::write_message(&mut connection, request) {
+        fail(json, &format!("failed to send command: {}", e));
+    }
+    match framing::read_message(&mut BufReader::new(&connection)) {
+        Ok(Some(response)) => response,
+        Ok(None) => fail(json, "the daemon closed the connection without a response"),
+        Err(e) => fail(json, &format!("invalid response from the daemon: {}", e)),
+    }
+}
 
-    let response: CommandResponse =
-        serde_json::from_slice(&response_bytes).expect("invalid response");
-    if let simbiota_protocol::CommandStatus::Failure(reason) = response.status {
-        eprintln!("command failed: {}", reason);
+/// Print the error and exit, as JSON with `--json`
+fn fail(json: bool, error: &str) -> ! {
+    if json {
+        print_json_error(error);
     } else {
-        match response.response {
-            Response::None => {}
-            Response::QuarantineQueryResponse(e) => {
-                if e.is_empty() {
-                    println!("Quarantine is empty");
-                    return;
-                }
+        eprintln!("{}", error);
+    }
+    exit(1);
+}
 
-                println!("Quarantine entries:");
-                for entry in e {
-                    println!("\t{}:\t{}", entry.0, entry.1);
-                }
+/// Ask on the terminal, exits unless the answer is yes
+fn confirm(json: bool, question: &str) {
+    if json || !std::io::stdin().is_terminal() {
+        fail(json, "confirmation required, pass --yes");
+    }
+    print!("{} [y/N] ", question);
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    let _ = std::io::stdin().read_line(&mut answer);
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        fail(json, "aborted");
+    }
+}
+
+fn print_json_error(error: &str) {
+    println!("{}", serde_json::json!({ "error": error }));
+}
+
+/// Print the payload of the response, without the protocol's variant tags
+fn print_json(response: Response) {
+    let value = match response {
+        Response::None => serde_json::json!({}),
+        Response::QuarantineQueryResponse(entries) => entries
+            .into_iter()
+            .map(|(id, path)| serde_json::json!({ "id": id, "path": path }))
+            .collect(),
+        Response::QuarantineDetailsResponse(entries) => serde_json::to_value(entries).unwrap(),
+        Response::QuarantineActionResponse(success) => serde_json::json!({ "success": success }),
+        Response::QuarantineStatsResponse(stats) => serde_json::to_value(stats).unwrap(),
+        Response::QuarantineExportResponse(count) => serde_json::json!({ "exported": count }),
+        Response::QuarantineBatchResponse(result) => serde_json::to_value(result).unwrap(),
+        Response::Stats(stats) => serde_json::to_value(stats).unwrap(),
+        Response::Events(events) => serde_json::to_value(events).unwrap(),
+        Response::DatabaseUpdateStatus(status) => match status {
+            DatabaseUpdateStatus::Idle => serde_json::json!({ "status": "idle" }),
+            DatabaseUpdateStatus::Running => serde_json::json!({ "status": "running" }),
+            DatabaseUpdateStatus::Finished(version) => {
+                serde_json::json!({ "status": "finished", "version": version })
+            }
+            DatabaseUpdateStatus::Failed(reason) => {
+                serde_json::json!({ "status": "failed", "error": reason })
+            }
+        },
+    };
+    println!("{}", value);
+}
+
+fn print_response(response: Response) {
+    match response {
+        Response::None => {}
+        Response::QuarantineQueryResponse(e) => {
+            if e.is_empty() {
+                println!("Quarantine is empty");
+                return;
+            }
+
+            println!("Quarantine entries:");
+            for entry in e {
+                println!("\t{}:\t{}", entry.0, entry.1);
+            }
+        }
+        Response::QuarantineDetailsResponse(entries) => {
+            if entries.is_empty() {
+                println!("Quarantine is empty");
+                return;
             }
-            Response::QuarantineActionResponse(s) => {
-                if s {
-                    println!("Quarantine action succeeded");
-                } else {
-                    println!("Quarantine action failed");
+
+            println!("ID\tMODE\tUID\tGID\tSIZE\t\tQUARANTINED (UTC)\tPATH");
+            for entry in entries {
+                println!(
+                    "{}\t{:04o}\t{}\t{}\t{}\t\t{}\t{}",
+                    entry.id,
+                    entry.mode & 0o7777,
+                    entry.uid,
+                    entry.gid,
+                    entry.size,
+                    format_timestamp(entry.quarantined_at),
+                    entry.path
+                );
+            }
+        }
+        Response::QuarantineActionResponse(s) => {
+            if s {
+                println!("Quarantine action succeeded");
+            } else {
+                println!("Quarantine action failed");
+            }
+        }
+        Response::QuarantineExportResponse(count) => {
+            println!("Exported {} quarantine entries", count)
+        }
+        Response::QuarantineBatchResponse(result) => {
+            println!(
+                "{} entries succeeded, {} failed",
+                result.succeeded,
+                result.failed.len()
+            );
+            for failure in result.failed {
+                println!("\t{}:\t{}", failure.path, failure.error);
+            }
+        }
+        Response::QuarantineStatsResponse(stats) => {
+            match stats.max_entries {
+                Some(max) => {
+                    println!("Quarantine entries:\t{} (limit: {})", stats.entries, max)
                 }
+                None => println!("Quarantine entries:\t{}", stats.entries),
+            }
+            match stats.max_size {
+                Some(max) => println!(
+                    "Quarantine size:\t{} bytes (limit: {} bytes)",
+                    stats.total_size, max
+                ),
+                None => println!("Quarantine size:\t{} bytes", stats.total_size),
+            }
+        }
+        Response::Stats(stats) => {
+            println!("Uptime:\t\t\t{} s", stats.uptime_secs);
+            println!("Files scanned:\t\t{}", stats.files_scanned);
+            println!("Cache hits:\t\t{}", stats.cache_hits);
+            println!("Cache misses:\t\t{}", stats.cache_misses);
+            println!("Detections:\t\t{}", stats.detections);
+            println!("Quarantine entries:\t{}", stats.quarantine_entries);
+            match stats.database_hashes {
+                Some(hashes) => println!("Database hashes:\t{}", hashes),
+                None => println!("Database hashes:\tn/a"),
             }
         }
+        Response::Events(events) => {
+            if events.is_empty() {
+                println!("No detections");
+                return;
+            }
+
+            for event in events {
+                let action = match event.action {
+                    DetectionAction::Blocked => "blocked".to_string(),
+                    DetectionAction::Observed => "observed".to_string(),
+                    DetectionAction::Removed => "removed".to_string(),
+                    DetectionAction::Quarantined => match &event.quarantine_id {
+                        Some(id) => format!("quarantined ({})", id),
+                        None => "quarantined".to_string(),
+                    },
+                };
+                let signature = event
+                    .signature
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                let distance = event
+                    .distance
+                    .map(|distance| distance.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    event.time, event.detector, action, signature, distance, event.path
+                );
+            }
+        }
+        Response::DatabaseUpdateStatus(status) => match status {
+            DatabaseUpdateStatus::Idle => println!("No database update was started"),
+            DatabaseUpdateStatus::Running => println!("Database update running"),
+            DatabaseUpdateStatus::Finished(Some(version)) => {
+                println!("Database updated, version: {}", version)
+            }
+            DatabaseUpdateStatus::Finished(None) => println!("Database updated"),
+            DatabaseUpdateStatus::Failed(reason) => println!("Database update failed: {}", reason),
+        },
     }
 }
+
+fn format_timestamp(timestamp: u64) -> String {
+    NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+        .filter(|_| timestamp > 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "unknown\t\t".to_string())
+}